@@ -0,0 +1,62 @@
+use std::num::Wrapping;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use yokoyboi::{
+    conditions::Condition,
+    instructions::{assembler::Assembler, type_def::Instruction},
+    registers::{R16, R8},
+    test_utils::{idle, machine_running},
+};
+
+fn read_dispatch(c: &mut Criterion) {
+    let machine = machine_running(idle());
+    c.bench_function("read every address", |b| {
+        b.iter(|| {
+            for address in 0x0000..=0xFFFF {
+                black_box(machine.read_u8_unrestricted(Wrapping(black_box(address))));
+            }
+        })
+    });
+}
+
+// Reads WRAM, writes its echo, and reads ROM and I/O registers, over and over
+fn memory_heavy_frame(c: &mut Criterion) {
+    let code = Assembler::new(0x150)
+        .label("copy")
+        .ld_r16_u16(R16::HL, 0xC000)
+        .label("byte")
+        .instruction(Instruction::LD_A_mHLinc)
+        .ld_mu16_a(0xFD00)
+        .ld_a_mu16(0x4000)
+        .ldh_a_u8(0x44)
+        .ld_r8_r8(R8::A, R8::H)
+        .cp_a_u8(0xE0)
+        .jr_cc_label(Condition::NZ, "byte")
+        .jr_label("copy");
+    let mut machine = machine_running(code);
+    c.bench_function("memory-heavy frame", |b| {
+        b.iter(|| black_box(machine.run_frames(1)))
+    });
+}
+
+// Scrolled background, with the window over part of it
+fn full_frame(c: &mut Criterion) {
+    let mut machine = machine_running(idle());
+    let mut write = |address: u16, value: u8| machine.write_u8(Wrapping(address), Wrapping(value));
+    write(0xFF40, 0x00);
+    for address in 0x8000..0xA000u16 {
+        write(address, (address ^ (address >> 5) ^ (address >> 11)) as u8);
+    }
+    write(0xFF47, 0xE4);
+    write(0xFF42, 0xC5);
+    write(0xFF43, 0x9D);
+    write(0xFF4A, 0x60);
+    write(0xFF4B, 0x57);
+    write(0xFF40, 0xF1);
+    c.bench_function("full frame", |b| {
+        b.iter(|| black_box(machine.run_frames(1)))
+    });
+}
+
+criterion_group!(benches, read_dispatch, memory_heavy_frame, full_frame);
+criterion_main!(benches);
@@ -2,7 +2,7 @@ use std::{
     fs::{self, File, OpenOptions},
     io::Write,
     num::{Saturating, Wrapping},
-    path::Path,
+    path::{Path, PathBuf},
     thread::sleep,
     time::{self, Duration},
 };
@@ -12,56 +12,24 @@ use iced::{exit, keyboard, Task};
 
 use crate::{
     command_line_arguments::CommandLineArguments,
-    cpu::{interrupts::Interrupts, CPU},
     instructions::decode::DecodedInstruction,
-    machine::Machine,
+    machine::{Machine, FRAME_DURATION},
     memory::{load_boot_rom, load_game_rom},
     message::Message,
+    rewind::{Rewind, DEFAULT_FRAMES_PER_SNAPSHOT, DEFAULT_REWIND_CAPACITY},
+    watchpoints::WatchpointHit,
 };
 
 const CPU_SNAPS_CAPACITY: usize = 5;
-const FRAME_TIME_NANOSECONDS: u32 = 16742;
 const LOG_PATH: &str = "log";
 
-#[derive(Clone, Debug)]
-pub enum MapperType {
-    ROMOnly,
-    MBC1,
-    Other, // TODO
-}
-
-#[derive(Clone, Debug)]
-pub enum RAMSize {
-    NoRAM,
-    Ram2kb,
-    Ram8kb,
-    Ram4banks8kb,
-    Ram16banks8kb,
-    Ram8banks8kb,
-}
-
-#[derive(Clone, Debug)]
-pub struct ROMInformation {
-    pub mapper_type: MapperType,
-    pub ram_size: RAMSize,
-    pub rom_banks: u8,
-}
-
-impl ROMInformation {
-    pub fn new() -> Self {
-        ROMInformation {
-            mapper_type: MapperType::ROMOnly,
-            ram_size: RAMSize::NoRAM,
-            rom_banks: 0,
-        }
-    }
-}
-
 #[derive(Debug)]
 pub struct ApplicationState {
-    pub breakpoints: Vec<u16>,
     pub output_file: Option<File>,
     pub paused: bool,
+    pub rewind: Rewind,
+    /// Where battery-backed external RAM gets persisted, next to the game ROM.
+    pub save_path: PathBuf,
     pub snaps: CircularQueue<Machine>,
     target_frame_time: Duration,
 }
@@ -71,27 +39,36 @@ enum PreserveHistory {
     PreserveHistory,
 }
 
-pub struct MachineStep {
-    t_cycles: u128,
-    instruction_executed: Option<DecodedInstruction>,
-}
-
 pub struct InstructionStep {
-    t_cycles: u128,
-    _instruction_executed: DecodedInstruction,
+    dots: u128,
+    // None when stopped by a breakpoint
+    _instruction_executed: Option<DecodedInstruction>,
+    breakpoint_hit: Option<u16>,
+    watchpoint_hit: Option<WatchpointHit>,
+    illegal_opcode: Option<(u8, u16)>,
 }
 
 impl ApplicationState {
     pub fn new(args: &CommandLineArguments, breakpoints: &[u16]) -> Self {
         let mut queue = CircularQueue::with_capacity(CPU_SNAPS_CAPACITY);
-        let boot_rom = load_boot_rom(&args.boot_rom).unwrap();
-        let (game_rom, rom_information) = load_game_rom(&args.game_rom).unwrap();
-        println!("{:?}", rom_information);
-        let machine = Machine::new(boot_rom, game_rom, rom_information, args.log_for_doctor);
+        let (game_rom, cartridge) = load_game_rom(Path::new(&args.game_rom)).unwrap();
+        println!("{:?}", cartridge);
+        let mut machine = match &args.boot_rom {
+            Some(boot_rom) => {
+                let boot_rom = load_boot_rom(Path::new(boot_rom)).unwrap();
+                Machine::new(boot_rom, game_rom, cartridge, args.log_for_doctor)
+            }
+            None => Machine::new_post_boot(game_rom, cartridge, args.log_for_doctor),
+        };
+        let save_path = Path::new(&args.game_rom).with_extension("sav");
+        if let Err(e) = machine.load_external_ram(&save_path) {
+            println!("[WARNING] Could not load save file: {}", e);
+        }
+        for breakpoint in breakpoints {
+            machine.cpu_mut().add_breakpoint(*breakpoint);
+        }
         queue.push(machine);
-        let target_frame_time = Duration::new(0, FRAME_TIME_NANOSECONDS);
         Self {
-            breakpoints: breakpoints.into(),
             output_file: if args.log_for_doctor {
                 Some(
                     OpenOptions::new()
@@ -109,8 +86,10 @@ impl ApplicationState {
                 None
             },
             paused: false,
+            rewind: Rewind::new(DEFAULT_REWIND_CAPACITY, DEFAULT_FRAMES_PER_SNAPSHOT),
+            save_path,
             snaps: queue,
-            target_frame_time,
+            target_frame_time: FRAME_DURATION,
         }
     }
 
@@ -130,41 +109,13 @@ impl ApplicationState {
 
     // TODO: move this elsewhere
     pub fn display_breakpoint(self: &Self, address: Wrapping<u16>) -> String {
-        String::from(if self.breakpoints.contains(&address.0) {
-            "@"
-        } else {
-            ""
-        })
-    }
-
-    // TODO: move in machine.rs
-    fn step_machine(machine: &mut Machine) -> MachineStep {
-        let mut instruction_executed = None;
-        let (mut t_cycles, mut _m_cycles) = Interrupts::handle_interrupts(machine);
-        if t_cycles == 0 {
-            (instruction_executed, (t_cycles, _m_cycles)) = CPU::execute_one_instruction(machine);
-        }
-        machine.timers.ticks(&mut machine.interrupts, t_cycles);
-        machine.ppu.ticks(
-            &mut machine.background_window_fetcher,
-            &mut machine.interrupts,
-            &mut machine.object_fetcher,
-            &mut machine.pixel_fetcher,
-            t_cycles,
-        );
-        machine.t_cycle_count += t_cycles as u64;
-
-        // // Print characters written to the Link cable on the terminal (useful for blargg w/o LCD)
-        // if machine.read_u8(Wrapping(0xFF02)).0 == 0x81 {
-        //     let char = machine.read_u8(Wrapping(0xFF01));
-        //     print!("{}", char.0 as char);
-        //     machine.write_u8(Wrapping(0xFF02), Wrapping(0x01));
-        // }
-
-        MachineStep {
-            t_cycles: t_cycles as u128,
-            instruction_executed,
-        }
+        String::from(
+            if self.current_machine_immut().cpu().has_breakpoint(address.0) {
+                "@"
+            } else {
+                ""
+            },
+        )
     }
 
     // Steps cycles forward until an instruction is executed.  May take many tries when the console
@@ -173,7 +124,7 @@ impl ApplicationState {
         if !self.current_machine().is_dmg_boot_rom_on()
             && !self.current_machine().cpu().low_power_mode
         {
-            let string = CPU::gbdoctor_string(self.current_machine());
+            let string = self.current_machine().gb_doctor_line();
             if let Some(output_file) = self.output_file.as_mut() {
                 write!(output_file, "{}\n", string).expect("write to log failed");
             }
@@ -183,20 +134,36 @@ impl ApplicationState {
             PreserveHistory::DontPreserveHistory => {
                 let machine = current_machine;
                 let mut executed_instruction = None;
-                let mut total_t_cycles: u128 = 0;
+                let mut watchpoint_hit = None;
+                let mut total_dots: u128 = 0;
 
                 loop {
                     match executed_instruction {
                         Some(decoded_instruction) => {
                             return InstructionStep {
-                                t_cycles: total_t_cycles,
-                                _instruction_executed: decoded_instruction,
+                                dots: total_dots,
+                                _instruction_executed: Some(decoded_instruction),
+                                breakpoint_hit: None,
+                                watchpoint_hit,
+                                illegal_opcode: None,
                             }
                         }
                         None => {
-                            let step = ApplicationState::step_machine(machine);
+                            let step = machine.step();
+                            total_dots += step.dots;
+                            // A locked up CPU never executes another instruction
+                            if step.breakpoint_hit.is_some() || step.illegal_opcode.is_some() {
+                                return InstructionStep {
+                                    dots: total_dots,
+                                    _instruction_executed: None,
+                                    breakpoint_hit: step.breakpoint_hit,
+                                    watchpoint_hit,
+                                    illegal_opcode: step.illegal_opcode,
+                                };
+                            }
+                            // Interrupt dispatch can also access memory, so keep the first hit
+                            watchpoint_hit = watchpoint_hit.or(step.watchpoint_hit);
                             executed_instruction = step.instruction_executed;
-                            total_t_cycles += step.t_cycles;
                         }
                     }
                 }
@@ -204,21 +171,38 @@ impl ApplicationState {
             PreserveHistory::PreserveHistory => {
                 let mut next_machine = current_machine.clone();
                 let mut executed_instruction = None;
-                let mut total_t_cycles = 0;
+                let mut watchpoint_hit = None;
+                let mut total_dots = 0;
 
                 loop {
                     match executed_instruction {
                         Some(decoded_instruction) => {
                             self.snaps.push(next_machine);
                             return InstructionStep {
-                                t_cycles: total_t_cycles,
-                                _instruction_executed: decoded_instruction,
+                                dots: total_dots,
+                                _instruction_executed: Some(decoded_instruction),
+                                breakpoint_hit: None,
+                                watchpoint_hit,
+                                illegal_opcode: None,
                             };
                         }
                         None => {
-                            let step = ApplicationState::step_machine(&mut next_machine);
+                            let step = next_machine.step();
+                            total_dots += step.dots;
+                            // A locked up CPU never executes another instruction
+                            if step.breakpoint_hit.is_some() || step.illegal_opcode.is_some() {
+                                self.snaps.push(next_machine);
+                                return InstructionStep {
+                                    dots: total_dots,
+                                    _instruction_executed: None,
+                                    breakpoint_hit: step.breakpoint_hit,
+                                    watchpoint_hit,
+                                    illegal_opcode: step.illegal_opcode,
+                                };
+                            }
+                            // Interrupt dispatch can also access memory, so keep the first hit
+                            watchpoint_hit = watchpoint_hit.or(step.watchpoint_hit);
                             executed_instruction = step.instruction_executed;
-                            total_t_cycles += step.t_cycles;
                         }
                     }
                 }
@@ -235,6 +219,7 @@ impl ApplicationState {
                 Some(Message::RunNextInstruction)
             }
             keyboard::Key::Named(keyboard::key::Named::Space) => Some(Message::Pause),
+            keyboard::Key::Named(keyboard::key::Named::Backspace) => Some(Message::Rewind),
             keyboard::Key::Named(keyboard::key::Named::Escape) => Some(Message::Quit),
             _ => None,
         })
@@ -251,9 +236,21 @@ impl ApplicationState {
                 if let Some(output_file) = self.output_file.as_mut() {
                     output_file.flush().expect("flush failed");
                 }
+                let save_path = self.save_path.clone();
+                if let Err(e) = self.current_machine().save_external_ram(&save_path) {
+                    println!("[WARNING] Could not write save file: {}", e);
+                }
                 exit()
             }
 
+            Message::Rewind => {
+                self.paused = true;
+                let machine = self.snaps.iter_mut().next().expect("rewind: no machine");
+                machine.rewind_one(&mut self.rewind);
+                machine.ppu_mut().render();
+                Task::none()
+            }
+
             Message::RunNextInstruction => {
                 let _step = self.execute_one_instruction(PreserveHistory::PreserveHistory);
                 self.current_machine().ppu_mut().render();
@@ -268,14 +265,23 @@ impl ApplicationState {
             }
 
             Message::ContinueRunUntilBreakpoint => {
-                let mut pc = self.current_machine().registers().pc;
-
                 let initial_time = time::Instant::now();
 
                 let mut remaining_steps = Saturating(69_905);
-                while remaining_steps.0 > 0 && !self.paused && !self.breakpoints.contains(&pc.0) {
+                let mut breakpoint_hit = None;
+                let mut watchpoint_hit = None;
+                let mut illegal_opcode = None;
+                while remaining_steps.0 > 0
+                    && !self.paused
+                    && breakpoint_hit.is_none()
+                    && watchpoint_hit.is_none()
+                    && illegal_opcode.is_none()
+                {
                     let step = self.execute_one_instruction(PreserveHistory::DontPreserveHistory);
-                    remaining_steps -= step.t_cycles as u32;
+                    remaining_steps -= step.dots as u32;
+                    breakpoint_hit = step.breakpoint_hit;
+                    watchpoint_hit = step.watchpoint_hit;
+                    illegal_opcode = step.illegal_opcode;
                     // self.current_machine().ppu_mut().render();
                     // let final_frame_time = time::Instant::now() - initial_time;
                     // if final_frame_time > target_frame_time {
@@ -283,15 +289,34 @@ impl ApplicationState {
                     // } else {
                     //     println!("Did not oversleep");
                     // }
-                    pc = self.current_machine().registers().pc;
                 }
 
-                if remaining_steps.0 == 0 {
+                if let Some(hit) = watchpoint_hit {
+                    println!(
+                        "Watchpoint hit: {:?} of 0x{:02X} at 0x{:04X}",
+                        hit.access, hit.value, hit.address
+                    );
+                }
+
+                if let Some((opcode, address)) = illegal_opcode {
+                    println!(
+                        "CPU locked up by illegal opcode 0x{:02X} at 0x{:04X}",
+                        opcode, address
+                    );
+                }
+
+                if remaining_steps.0 == 0
+                    && breakpoint_hit.is_none()
+                    && watchpoint_hit.is_none()
+                    && illegal_opcode.is_none()
+                {
                     // If we're stopping for a frame, try to get accurate frame time
                     self.current_machine().ppu_mut().render();
+                    let machine = self.snaps.iter().next().expect("rewind: no machine");
+                    self.rewind.record_frame(machine);
                     let final_time = time::Instant::now();
                     let frame_time = final_time - initial_time;
-                    if frame_time.as_nanos() < FRAME_TIME_NANOSECONDS as u128 {
+                    if frame_time < self.target_frame_time {
                         sleep(self.target_frame_time - frame_time);
                     }
                     // Note: I think technically we should save this time, so that we can account
@@ -0,0 +1,642 @@
+pub mod envelope;
+pub mod length_counter;
+pub mod noise;
+pub mod square;
+pub mod wave;
+
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use noise::NoiseChannel;
+use square::SquareChannel;
+use wave::WaveChannel;
+
+use crate::{machine::Machine, utils};
+
+const CPU_FREQUENCY: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+// The frame sequencer runs at 512 Hz
+const DOTS_PER_FRAME_SEQUENCER_STEP: u16 = 8192;
+// Charge factor of the DMG high-pass filter capacitors, per dot
+const HIGH_PASS_CHARGE_FACTOR: f32 = 0.999958;
+
+const SOUND_ON_BIT: u8 = 7;
+// Bits 4-6 of NR52 are unused and read as 1
+const SOUND_ON_UNUSED_BITS: u8 = 0x70;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct APU {
+    pub channel_1: SquareChannel,
+    pub channel_2: SquareChannel,
+    pub channel_3: WaveChannel,
+    pub channel_4: NoiseChannel,
+
+    /// NR50 (0xFF24): left (bits 4-6) and right (bits 0-2) volumes.
+    pub master_volume: Wrapping<u8>,
+    /// NR51 (0xFF25): channels sent to the left (bits 4-7) and right (bits 0-3) outputs.
+    pub panning: Wrapping<u8>,
+    /// NR52 (0xFF26): audio master enable (bit 7), the only writable bit.
+    pub sound_on: Wrapping<u8>,
+
+    /// Frame sequencer step, from 0 to 7.
+    frame_sequencer_step: u8,
+    frame_sequencer_dots: u16,
+    /// Host sample rate, at which samples get produced.
+    pub sample_rate: u32,
+    // Accumulates `sample_rate` every dot, a sample is produced every `CPU_FREQUENCY`
+    sample_accumulator: u32,
+    sample_sum: (f32, f32),
+    sample_sum_dots: u32,
+    high_pass_capacitors: (f32, f32),
+    /// (left, right) samples produced since last drained, from -1.0 to 1.0.  Up to one second gets
+    /// buffered, later samples are dropped.  Not part of save states.
+    #[serde(skip)]
+    samples: Vec<(f32, f32)>,
+    /// Set to skip mixing altogether, so that no samples get produced.  The channels still run.
+    /// Not part of save states.
+    #[serde(skip)]
+    pub muted: bool,
+}
+
+// Bits that read as 1 whatever was written: the unused ones, and the write-only frequencies, length
+// timers, and triggers
+fn register_read_mask(address: u16) -> u8 {
+    match address {
+        0xFF10 => 0x80,
+        0xFF11 | 0xFF16 => 0x3F,
+        0xFF13 | 0xFF18 | 0xFF1D => 0xFF,
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0xBF,
+        0xFF1A => 0x7F,
+        0xFF1B | 0xFF20 => 0xFF,
+        0xFF1C => 0x9F,
+        0xFF15 | 0xFF1F => 0xFF,
+        _ => 0x00,
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            channel_1: SquareChannel::new(true),
+            channel_2: SquareChannel::new(false),
+            channel_3: WaveChannel::new(),
+            channel_4: NoiseChannel::new(),
+            master_volume: Wrapping(0),
+            panning: Wrapping(0),
+            sound_on: Wrapping(0),
+            frame_sequencer_step: 0,
+            frame_sequencer_dots: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_accumulator: 0,
+            sample_sum: (0.0, 0.0),
+            sample_sum_dots: 0,
+            high_pass_capacitors: (0.0, 0.0),
+            samples: Vec::new(),
+            muted: false,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        utils::is_bit_set(&self.sound_on, SOUND_ON_BIT)
+    }
+
+    // Bits 0-3 report which channels are currently on
+    fn read_sound_on(&self) -> Wrapping<u8> {
+        let channels_on = [
+            self.channel_1.enabled,
+            self.channel_2.enabled,
+            self.channel_3.enabled,
+            self.channel_4.enabled,
+        ];
+        let mut value = SOUND_ON_UNUSED_BITS | (self.sound_on.0 & (1 << SOUND_ON_BIT));
+        for (channel, enabled) in channels_on.iter().enumerate() {
+            value |= (*enabled as u8) << channel;
+        }
+        Wrapping(value)
+    }
+
+    fn write_sound_on(&mut self, value: Wrapping<u8>) {
+        let was_on = self.is_on();
+        self.sound_on = Wrapping(value.0 & (1 << SOUND_ON_BIT));
+        if was_on && !self.is_on() {
+            // Turning the APU off clears all its registers, but not wave RAM
+            let wave_ram = self.channel_3.wave_ram;
+            self.channel_1 = SquareChannel::new(true);
+            self.channel_2 = SquareChannel::new(false);
+            self.channel_3 = WaveChannel::new();
+            self.channel_3.wave_ram = wave_ram;
+            self.channel_4 = NoiseChannel::new();
+            self.master_volume = Wrapping(0);
+            self.panning = Wrapping(0);
+        } else if !was_on && self.is_on() {
+            self.frame_sequencer_step = 0;
+        }
+    }
+
+    pub fn read(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        Wrapping(register_read_mask(address.0)) | self.read_register(address)
+    }
+
+    fn read_register(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match address.0 {
+            0xFF10 => self.channel_1.sweep,
+            0xFF11 => self.channel_1.duty_and_length,
+            0xFF12 => self.channel_1.volume_envelope,
+            0xFF13 => self.channel_1.frequency_low,
+            0xFF14 => self.channel_1.frequency_high_and_control,
+            0xFF16 => self.channel_2.duty_and_length,
+            0xFF17 => self.channel_2.volume_envelope,
+            0xFF18 => self.channel_2.frequency_low,
+            0xFF19 => self.channel_2.frequency_high_and_control,
+            0xFF1A => self.channel_3.dac_enable,
+            0xFF1B => self.channel_3.length,
+            0xFF1C => self.channel_3.output_level,
+            0xFF1D => self.channel_3.frequency_low,
+            0xFF1E => self.channel_3.frequency_high_and_control,
+            0xFF20 => self.channel_4.length,
+            0xFF21 => self.channel_4.volume_envelope,
+            0xFF22 => self.channel_4.frequency_and_randomness,
+            0xFF23 => self.channel_4.control,
+            0xFF24 => self.master_volume,
+            0xFF25 => self.panning,
+            0xFF26 => self.read_sound_on(),
+            0xFF15 | 0xFF1F => Wrapping(0xFF),
+            0xFF30..=0xFF3F => self.channel_3.wave_ram[address.0 as usize - 0xFF30],
+            _ => unreachable!("Not an APU register: 0x{:04X}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        // While the APU is off, only NR52 and wave RAM are writable
+        if !self.is_on() && !matches!(address.0, 0xFF26 | 0xFF30..=0xFF3F) {
+            return;
+        }
+        match address.0 {
+            0xFF10 => self.channel_1.sweep = value,
+            0xFF11 => self.channel_1.write_duty_and_length(value),
+            0xFF12 => self.channel_1.write_volume_envelope(value),
+            0xFF13 => self.channel_1.frequency_low = value,
+            0xFF14 => self.channel_1.write_frequency_high_and_control(value),
+            0xFF16 => self.channel_2.write_duty_and_length(value),
+            0xFF17 => self.channel_2.write_volume_envelope(value),
+            0xFF18 => self.channel_2.frequency_low = value,
+            0xFF19 => self.channel_2.write_frequency_high_and_control(value),
+            0xFF1A => self.channel_3.write_dac_enable(value),
+            0xFF1B => self.channel_3.write_length(value),
+            0xFF1C => self.channel_3.output_level = value,
+            0xFF1D => self.channel_3.frequency_low = value,
+            0xFF1E => self.channel_3.write_frequency_high_and_control(value),
+            0xFF20 => self.channel_4.write_length(value),
+            0xFF21 => self.channel_4.write_volume_envelope(value),
+            0xFF22 => self.channel_4.frequency_and_randomness = value,
+            0xFF23 => self.channel_4.write_control(value),
+            0xFF24 => self.master_volume = value,
+            0xFF25 => self.panning = value,
+            0xFF26 => self.write_sound_on(value),
+            0xFF15 | 0xFF1F => {}
+            0xFF30..=0xFF3F => self.channel_3.wave_ram[address.0 as usize - 0xFF30] = value,
+            _ => unreachable!("Not an APU register: 0x{:04X}", address),
+        }
+    }
+
+    // Step   Length  Sweep  Envelope
+    //  0      clock
+    //  1
+    //  2      clock   clock
+    //  3
+    //  4      clock
+    //  5
+    //  6      clock   clock
+    //  7                     clock
+    fn step_frame_sequencer(&mut self) {
+        if matches!(self.frame_sequencer_step, 0 | 2 | 4 | 6) {
+            self.channel_1.clock_length();
+            self.channel_2.clock_length();
+            self.channel_3.clock_length();
+            self.channel_4.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel_1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel_1.clock_envelope();
+            self.channel_2.clock_envelope();
+            self.channel_4.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    // CGB PCM12 (0xFF76) and PCM34 (0xFF77), read-only: the digital outputs of two channels, the
+    // first one in the low nibble
+    fn read_pcm(&self, low_output: u8, high_output: u8) -> Wrapping<u8> {
+        if !self.is_on() {
+            return Wrapping(0);
+        }
+        Wrapping((high_output << 4) | low_output)
+    }
+
+    pub fn read_pcm12(&self) -> Wrapping<u8> {
+        self.read_pcm(self.channel_1.output(), self.channel_2.output())
+    }
+
+    pub fn read_pcm34(&self) -> Wrapping<u8> {
+        self.read_pcm(self.channel_3.output(), self.channel_4.output())
+    }
+
+    // Each DAC converts its channel's 0-15 output to an analog value between -1.0 and 1.0
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, output: u8| {
+            if enabled {
+                output as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.channel_1.is_dac_enabled(), self.channel_1.output()),
+            dac(self.channel_2.is_dac_enabled(), self.channel_2.output()),
+            dac(self.channel_3.is_dac_enabled(), self.channel_3.output()),
+            dac(self.channel_4.is_dac_enabled(), self.channel_4.output()),
+        ]
+    }
+
+    // Mixes the channels panned to each side (NR51), scaled by each side's volume (NR50)
+    fn mix(&self) -> (f32, f32) {
+        if !self.is_on() {
+            return (0.0, 0.0);
+        }
+        let outputs = self.dac_outputs();
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            if utils::is_bit_set(&self.panning, channel as u8 + 4) {
+                left += output;
+            }
+            if utils::is_bit_set(&self.panning, channel as u8) {
+                right += output;
+            }
+        }
+        let left_volume = ((self.master_volume.0 >> 4) & 0x07) + 1;
+        let right_volume = (self.master_volume.0 & 0x07) + 1;
+        (
+            left / 4.0 * left_volume as f32 / 8.0,
+            right / 4.0 * right_volume as f32 / 8.0,
+        )
+    }
+
+    // The DMG output goes through capacitors, which remove the DC offset
+    fn high_pass(&mut self, (left, right): (f32, f32)) -> (f32, f32) {
+        let charge_factor =
+            HIGH_PASS_CHARGE_FACTOR.powf(CPU_FREQUENCY as f32 / self.sample_rate as f32);
+        let filtered_left = left - self.high_pass_capacitors.0;
+        let filtered_right = right - self.high_pass_capacitors.1;
+        self.high_pass_capacitors = (
+            left - filtered_left * charge_factor,
+            right - filtered_right * charge_factor,
+        );
+        (filtered_left, filtered_right)
+    }
+
+    pub fn tick(&mut self) {
+        self.frame_sequencer_dots += 1;
+        if self.frame_sequencer_dots == DOTS_PER_FRAME_SEQUENCER_STEP {
+            self.frame_sequencer_dots = 0;
+            self.step_frame_sequencer();
+        }
+
+        self.channel_1.tick();
+        self.channel_2.tick();
+        self.channel_3.tick();
+        self.channel_4.tick();
+        if self.muted {
+            return;
+        }
+
+        // Resampling averages every dot since the last sample
+        let (left, right) = self.mix();
+        self.sample_sum = (self.sample_sum.0 + left, self.sample_sum.1 + right);
+        self.sample_sum_dots += 1;
+        self.sample_accumulator += self.sample_rate;
+        if self.sample_accumulator >= CPU_FREQUENCY {
+            self.sample_accumulator -= CPU_FREQUENCY;
+            let dots = self.sample_sum_dots as f32;
+            let sample = self.high_pass((self.sample_sum.0 / dots, self.sample_sum.1 / dots));
+            self.sample_sum = (0.0, 0.0);
+            self.sample_sum_dots = 0;
+            if self.samples.len() < self.sample_rate as usize {
+                self.samples.push(sample);
+            }
+        }
+    }
+
+    // Returns the (left, right) samples produced since the last call, at `sample_rate`
+    pub fn drain_samples(&mut self) -> Vec<(f32, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn ticks(&mut self, dots: u8) {
+        for _ in 0..dots {
+            self.tick();
+        }
+    }
+}
+
+impl Machine {
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        test_utils::{idle, machine_running, with_large_stack},
+    };
+
+    use super::{APU, CPU_FREQUENCY, DOTS_PER_FRAME_SEQUENCER_STEP};
+
+    // Turns the APU on at full volume, then writes `registers`
+    fn apu_with(registers: &[(u16, u8)]) -> APU {
+        let mut apu = APU::new();
+        apu.write(Wrapping(0xFF26), Wrapping(0x80));
+        apu.write(Wrapping(0xFF24), Wrapping(0x77));
+        for (address, value) in registers {
+            apu.write(Wrapping(*address), Wrapping(*value));
+        }
+        apu
+    }
+
+    fn rising_zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count()
+    }
+
+    #[test]
+    fn channel_1_plays_a_1khz_tone() {
+        // 131072 / (2048 - 1917) Hz, with a 50% duty cycle
+        let mut apu = apu_with(&[
+            (0xFF25, 0x11),
+            (0xFF11, 0x80),
+            (0xFF12, 0xF0),
+            (0xFF13, 0x7D),
+            (0xFF14, 0x87),
+        ]);
+        for _ in 0..CPU_FREQUENCY / 10 {
+            apu.tick();
+        }
+        let left: Vec<f32> = apu.drain_samples().iter().map(|sample| sample.0).collect();
+        assert!(left.iter().any(|sample| sample.abs() > 0.1));
+        // Skipping the first period, during which the high-pass filter charges up
+        let crossings = rising_zero_crossings(&left[48..]);
+        assert!((98..=100).contains(&crossings), "{} periods", crossings);
+    }
+
+    // Channel 3 outputs, sampled once per wave RAM position at the highest frequency
+    fn wave_outputs(output_level: u8) -> Vec<u8> {
+        let mut registers: Vec<(u16, u8)> = (0..16)
+            .map(|offset| (0xFF30 + offset, (offset as u8 % 8) * 0x22 + 0x01))
+            .collect();
+        registers.extend([
+            (0xFF1A, 0x80),
+            (0xFF1C, output_level),
+            (0xFF1D, 0xFF),
+            (0xFF1E, 0x87),
+        ]);
+        let mut apu = apu_with(&registers);
+        (0..64)
+            .map(|_| {
+                apu.tick();
+                apu.tick();
+                apu.channel_3.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn channel_3_plays_back_wave_ram() {
+        // Wave RAM holds two ramps from 0 to 15
+        let outputs = wave_outputs(0x20);
+        assert!(
+            outputs.windows(2).all(|pair| pair[1] == (pair[0] + 1) % 16),
+            "{:?}",
+            outputs
+        );
+        let halved = wave_outputs(0x40);
+        assert!(halved
+            .iter()
+            .zip(&outputs)
+            .all(|(halved, output)| *halved == output >> 1));
+    }
+
+    // Channel 4 outputs, sampled once per LFSR shift with the fastest clock, once the bits the
+    // trigger filled with 1s have been shifted out
+    fn noise_outputs(width: u8, count: usize) -> Vec<u8> {
+        let mut apu = apu_with(&[(0xFF21, 0xF0), (0xFF22, width), (0xFF23, 0x80)]);
+        for _ in 0..16 * 8 {
+            apu.tick();
+        }
+        (0..count)
+            .map(|_| {
+                for _ in 0..8 {
+                    apu.tick();
+                }
+                apu.channel_4.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_mode_noise_repeats_every_127_shifts() {
+        let short = noise_outputs(0x08, 3 * 127);
+        assert!(short.contains(&0) && short.contains(&15));
+        assert!(short.iter().zip(&short[127..]).all(|(a, b)| a == b));
+        // 127 is prime, so there is no shorter period, unlike the 15-bit mode's 32767
+        let long = noise_outputs(0x00, 2 * 127);
+        assert!(long.iter().zip(&long[127..]).any(|(a, b)| a != b));
+    }
+
+    // Dots until channel 2 gets silenced by its length counter, loaded with `length` on trigger
+    fn channel_2_length_dots(length: u8) -> u32 {
+        let mut apu = apu_with(&[(0xFF16, 64 - length), (0xFF17, 0xF0), (0xFF19, 0xC0)]);
+        let mut dots = 0;
+        while apu.channel_2.enabled {
+            apu.tick();
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn length_counters_are_clocked_on_even_sequencer_steps() {
+        // The sequencer starts at step 0 when the APU gets turned on
+        assert_eq!(
+            channel_2_length_dots(1),
+            DOTS_PER_FRAME_SEQUENCER_STEP as u32
+        );
+        assert_eq!(
+            channel_2_length_dots(2),
+            3 * DOTS_PER_FRAME_SEQUENCER_STEP as u32
+        );
+        assert_eq!(
+            channel_2_length_dots(3),
+            5 * DOTS_PER_FRAME_SEQUENCER_STEP as u32
+        );
+    }
+
+    #[test]
+    fn channels_panned_left_leave_the_right_output_silent() {
+        let mut apu = apu_with(&[
+            (0xFF25, 0x10),
+            (0xFF11, 0x80),
+            (0xFF12, 0xF0),
+            (0xFF13, 0x7D),
+            (0xFF14, 0x87),
+        ]);
+        for _ in 0..CPU_FREQUENCY / 100 {
+            apu.tick();
+        }
+        let samples = apu.drain_samples();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().any(|(left, _)| left.abs() > 0.1));
+        assert!(samples.iter().all(|(_, right)| *right == 0.0));
+        assert!(apu.drain_samples().is_empty());
+    }
+
+    #[test]
+    fn nr52_reports_channels_until_their_length_expires() {
+        let mut apu = apu_with(&[(0xFF16, 0x3F), (0xFF17, 0xF0), (0xFF19, 0xC0)]);
+        assert_eq!(apu.read(Wrapping(0xFF26)), Wrapping(0xF2));
+        for _ in 0..DOTS_PER_FRAME_SEQUENCER_STEP {
+            apu.tick();
+        }
+        assert_eq!(apu.read(Wrapping(0xFF26)), Wrapping(0xF0));
+
+        // Turning the APU off clears the registers, which stay read-only until it is turned on
+        apu.write(Wrapping(0xFF26), Wrapping(0x00));
+        assert_eq!(apu.read(Wrapping(0xFF26)), Wrapping(0x70));
+        assert_eq!(apu.read(Wrapping(0xFF24)), Wrapping(0x00));
+        assert_eq!(apu.read(Wrapping(0xFF17)), Wrapping(0x00));
+        apu.write(Wrapping(0xFF24), Wrapping(0x77));
+        assert_eq!(apu.read(Wrapping(0xFF24)), Wrapping(0x00));
+    }
+
+    #[test]
+    fn registers_read_back_through_their_masks() {
+        // NR10 to NR51, with the bits that read as 1 according to Pan Docs
+        let masks = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF,
+            0xBF, 0xFF, 0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00,
+        ];
+        let mut apu = apu_with(&[]);
+        for (address, mask) in (0xFF10..=0xFF25).zip(masks) {
+            apu.write(Wrapping(address), Wrapping(0x00));
+            assert_eq!(apu.read(Wrapping(address)).0, mask, "0x{:04X}", address);
+            apu.write(Wrapping(address), Wrapping(0xFF));
+            assert_eq!(apu.read(Wrapping(address)).0, 0xFF, "0x{:04X}", address);
+        }
+        // Bits 4-6 of NR52 are unused
+        apu.write(Wrapping(0xFF26), Wrapping(0x00));
+        assert_eq!(apu.read(Wrapping(0xFF26)).0, 0x70);
+    }
+
+    #[test]
+    fn pcm_registers_pack_the_channel_outputs() {
+        let mut registers: Vec<(u16, u8)> =
+            (0xFF30..0xFF40).map(|address| (address, 0x77)).collect();
+        registers.extend([
+            // Channel 1 at volume 10, channel 3 at 100%, and channel 4 at volume 5
+            (0xFF11, 0x80),
+            (0xFF12, 0xA0),
+            (0xFF13, 0x00),
+            (0xFF14, 0x87),
+            (0xFF1A, 0x80),
+            (0xFF1C, 0x20),
+            (0xFF1D, 0x00),
+            (0xFF1E, 0x87),
+            (0xFF21, 0x50),
+            (0xFF22, 0x00),
+            (0xFF23, 0x80),
+        ]);
+        let mut apu = apu_with(&registers);
+        for _ in 0..100 {
+            apu.tick();
+        }
+        let mut pcm12_values = vec![];
+        let mut pcm34_values = vec![];
+        for _ in 0..10000 {
+            apu.tick();
+            pcm12_values.push(apu.read_pcm12().0);
+            pcm34_values.push(apu.read_pcm34().0);
+        }
+        pcm12_values.sort();
+        pcm12_values.dedup();
+        pcm34_values.sort();
+        pcm34_values.dedup();
+        // Channel 2 stays silent
+        assert_eq!(pcm12_values, [0x00, 0x0A]);
+        assert_eq!(pcm34_values, [0x07, 0x57]);
+
+        apu.write(Wrapping(0xFF26), Wrapping(0x00));
+        assert_eq!(apu.read_pcm12(), Wrapping(0));
+        assert_eq!(apu.read_pcm34(), Wrapping(0));
+    }
+
+    // Noise on both outputs, then the bytes of the samples of a couple of frames
+    fn play_noise(machine: &mut Machine) -> Vec<u8> {
+        for (address, value) in [
+            (0xFF26, 0x80),
+            (0xFF24, 0x77),
+            (0xFF25, 0x88),
+            (0xFF21, 0xF0),
+            (0xFF22, 0x21),
+            (0xFF23, 0x80),
+        ] {
+            machine.write_u8(Wrapping(address), Wrapping(value));
+        }
+        noise_samples(machine)
+    }
+
+    fn noise_samples(machine: &mut Machine) -> Vec<u8> {
+        machine.apu_mut().drain_samples();
+        machine.run_frames(2);
+        let samples = machine.apu_mut().drain_samples();
+        assert!(samples.iter().any(|(left, _)| left.abs() > 0.1));
+        samples
+            .iter()
+            .flat_map(|(left, right)| [left.to_le_bytes(), right.to_le_bytes()].concat())
+            .collect()
+    }
+
+    #[test]
+    fn noise_is_reproducible_across_machines_and_save_states() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            let mut other_machine = machine_running(idle());
+            assert_eq!(play_noise(&mut machine), play_noise(&mut other_machine));
+
+            // The LFSR is part of the state, so that playback resumes identically on a machine
+            // that never played noise
+            let state = machine.save_state();
+            let samples = noise_samples(&mut machine);
+            let mut fresh_machine = machine_running(idle());
+            fresh_machine.load_state(&state).unwrap();
+            assert_eq!(noise_samples(&mut fresh_machine), samples);
+        });
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+const ENVELOPE_INCREASE_BIT: u8 = 3;
+const ENVELOPE_PERIOD_MASK: u8 = 0x07;
+const MAX_VOLUME: u8 = 0x0F;
+
+// The DAC is on whenever the upper 5 bits of NRx2 are not all 0
+pub fn is_dac_enabled(nrx2: u8) -> bool {
+    nrx2 & 0xF8 != 0
+}
+
+/// Volume envelope, configured through NRx2: initial volume (bits 4-7), direction (bit 3), and
+/// period (bits 0-2, 0 stopping the envelope).
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct Envelope {
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.timer = nrx2 & ENVELOPE_PERIOD_MASK;
+    }
+
+    // Clocked at 64 Hz by the frame sequencer
+    pub fn clock(&mut self, nrx2: u8) {
+        let period = nrx2 & ENVELOPE_PERIOD_MASK;
+        if period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+        if nrx2 & (1 << ENVELOPE_INCREASE_BIT) != 0 {
+            if self.volume < MAX_VOLUME {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
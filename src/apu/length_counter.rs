@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+/// Counts down at 256 Hz while enabled (NRx4 bit 6), turning its channel off when reaching 0.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct LengthCounter {
+    /// 64 for the square and noise channels, 256 for the wave channel.
+    max_length: u16,
+    counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max_length: u16) -> Self {
+        LengthCounter {
+            max_length,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    // NRx1 holds the initial length timer, the counter runs from there up to the maximum
+    pub fn load(&mut self, initial_length_timer: u16) {
+        self.counter = self.max_length - initial_length_timer;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max_length;
+        }
+    }
+
+    // Returns whether the counter just expired, in which case the channel gets turned off
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
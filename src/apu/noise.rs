@@ -0,0 +1,140 @@
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+use super::{
+    envelope::{self, Envelope},
+    length_counter::LengthCounter,
+};
+
+const NOISE_LENGTH: u16 = 64;
+// Indexed by the divisor code in NR43 bits 0-2
+const DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+// The LFSR gets filled with 1s on trigger
+const LFSR_INITIAL_STATE: u16 = 0x7FFF;
+
+const SHORT_MODE_BIT: u8 = 3;
+const LENGTH_ENABLE_BIT: u8 = 6;
+const TRIGGER_BIT: u8 = 7;
+
+/// Noise channel, outputting the low bit of a linear-feedback shift register.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct NoiseChannel {
+    /// NR41 (0xFF20): initial length timer (bits 0-5).
+    pub length: Wrapping<u8>,
+    /// NR42 (0xFF21): volume envelope.
+    pub volume_envelope: Wrapping<u8>,
+    /// NR43 (0xFF22): clock shift (bits 4-7), LFSR width (bit 3), and divisor code (bits 0-2).
+    pub frequency_and_randomness: Wrapping<u8>,
+    /// NR44 (0xFF23): trigger (bit 7) and length enable (bit 6).
+    pub control: Wrapping<u8>,
+
+    pub enabled: bool,
+    /// Starts out filled with 1s as on trigger, and is part of save states, so that the noise
+    /// produced is the same on every run.
+    lfsr: u16,
+    frequency_timer: u32,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        NoiseChannel::new()
+    }
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        NoiseChannel {
+            length: Wrapping(0),
+            volume_envelope: Wrapping(0),
+            frequency_and_randomness: Wrapping(0),
+            control: Wrapping(0),
+            enabled: false,
+            lfsr: LFSR_INITIAL_STATE,
+            frequency_timer: 0,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(NOISE_LENGTH),
+        }
+    }
+
+    // The LFSR shifts every (divisor << clock shift) T-cycles
+    fn frequency_timer_period(&self) -> u32 {
+        let value = self.frequency_and_randomness.0;
+        (DIVISORS[(value & 0x07) as usize] as u32) << (value >> 4)
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        envelope::is_dac_enabled(self.volume_envelope.0)
+    }
+
+    pub fn write_length(&mut self, value: Wrapping<u8>) {
+        self.length = value;
+        self.length_counter.load((value.0 & 0x3F) as u16);
+    }
+
+    pub fn write_volume_envelope(&mut self, value: Wrapping<u8>) {
+        self.volume_envelope = value;
+        if !envelope::is_dac_enabled(value.0) {
+            self.enabled = false;
+        }
+    }
+
+    pub fn write_control(&mut self, value: Wrapping<u8>) {
+        self.control = value;
+        self.length_counter.enabled = utils::is_bit_set(&value, LENGTH_ENABLE_BIT);
+        if utils::is_bit_set(&value, TRIGGER_BIT) {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = envelope::is_dac_enabled(self.volume_envelope.0);
+        self.length_counter.trigger();
+        self.frequency_timer = self.frequency_timer_period();
+        self.envelope.trigger(self.volume_envelope.0);
+        self.lfsr = LFSR_INITIAL_STATE;
+    }
+
+    fn shift_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        // In short mode, the feedback also goes to bit 6, making for a 7-bit LFSR
+        if utils::is_bit_set(&self.frequency_and_randomness, SHORT_MODE_BIT) {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.frequency_timer_period();
+            self.shift_lfsr();
+        }
+    }
+
+    // Clocked at 256 Hz by the frame sequencer
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // Clocked at 64 Hz by the frame sequencer
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.volume_envelope.0);
+    }
+
+    // Digital output, from 0 to 15: the channel is high when the low bit of the LFSR is 0
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 == 1 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
@@ -0,0 +1,210 @@
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+use super::{
+    envelope::{self, Envelope},
+    length_counter::LengthCounter,
+};
+
+const SQUARE_LENGTH: u16 = 64;
+// Which of the 8 steps of a period output high, for 12.5%, 25%, 50%, and 75% duty cycles
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+const SWEEP_DECREASE_BIT: u8 = 3;
+const LENGTH_ENABLE_BIT: u8 = 6;
+const TRIGGER_BIT: u8 = 7;
+const MAX_FREQUENCY: u16 = 2047;
+
+/// Square wave channel.  Channel 1 has a frequency sweep unit, channel 2 does not.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct SquareChannel {
+    /// NR10 (0xFF10): sweep pace (bits 4-6), direction (bit 3), and step (bits 0-2).
+    pub sweep: Wrapping<u8>,
+    /// NRx1: duty cycle (bits 6-7) and initial length timer (bits 0-5).
+    pub duty_and_length: Wrapping<u8>,
+    /// NRx2: volume envelope.
+    pub volume_envelope: Wrapping<u8>,
+    /// NRx3: lower 8 bits of the 11-bit frequency.
+    pub frequency_low: Wrapping<u8>,
+    /// NRx4: trigger (bit 7), length enable (bit 6), and upper 3 bits of the frequency.
+    pub frequency_high_and_control: Wrapping<u8>,
+
+    pub enabled: bool,
+    has_sweep: bool,
+    duty_step: u8,
+    frequency_timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    pub fn new(has_sweep: bool) -> Self {
+        SquareChannel {
+            sweep: Wrapping(0),
+            duty_and_length: Wrapping(0),
+            volume_envelope: Wrapping(0),
+            frequency_low: Wrapping(0),
+            frequency_high_and_control: Wrapping(0),
+            enabled: false,
+            has_sweep,
+            duty_step: 0,
+            frequency_timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(SQUARE_LENGTH),
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn frequency(&self) -> u16 {
+        ((self.frequency_high_and_control.0 as u16 & 0x07) << 8) | self.frequency_low.0 as u16
+    }
+
+    fn set_frequency(&mut self, frequency: u16) {
+        self.frequency_low = Wrapping(frequency as u8);
+        self.frequency_high_and_control =
+            Wrapping((self.frequency_high_and_control.0 & 0xF8) | ((frequency >> 8) as u8 & 0x07));
+    }
+
+    // The duty step advances every (2048 - frequency) * 4 T-cycles
+    fn frequency_timer_period(&self) -> u16 {
+        (2048 - self.frequency()) * 4
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        envelope::is_dac_enabled(self.volume_envelope.0)
+    }
+
+    pub fn write_duty_and_length(&mut self, value: Wrapping<u8>) {
+        self.duty_and_length = value;
+        self.length.load((value.0 & 0x3F) as u16);
+    }
+
+    pub fn write_volume_envelope(&mut self, value: Wrapping<u8>) {
+        self.volume_envelope = value;
+        if !envelope::is_dac_enabled(value.0) {
+            self.enabled = false;
+        }
+    }
+
+    pub fn write_frequency_high_and_control(&mut self, value: Wrapping<u8>) {
+        self.frequency_high_and_control = value;
+        self.length.enabled = utils::is_bit_set(&value, LENGTH_ENABLE_BIT);
+        if utils::is_bit_set(&value, TRIGGER_BIT) {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = envelope::is_dac_enabled(self.volume_envelope.0);
+        self.length.trigger();
+        self.frequency_timer = self.frequency_timer_period();
+        self.envelope.trigger(self.volume_envelope.0);
+        if self.has_sweep {
+            self.shadow_frequency = self.frequency();
+            self.sweep_timer = self.sweep_period();
+            self.sweep_enabled = self.sweep_pace() != 0 || self.sweep_shift() != 0;
+            // The overflow check happens immediately when the sweep has a step
+            if self.sweep_shift() != 0 {
+                self.next_sweep_frequency();
+            }
+        }
+    }
+
+    fn sweep_pace(&self) -> u8 {
+        (self.sweep.0 >> 4) & 0x07
+    }
+
+    // A pace of 0 is treated as 8 for the timer
+    fn sweep_period(&self) -> u8 {
+        match self.sweep_pace() {
+            0 => 8,
+            pace => pace,
+        }
+    }
+
+    fn sweep_shift(&self) -> u8 {
+        self.sweep.0 & 0x07
+    }
+
+    // Computes the next frequency from the shadow frequency, disabling the channel on overflow
+    fn next_sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift();
+        let frequency = if utils::is_bit_set(&self.sweep, SWEEP_DECREASE_BIT) {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    pub fn tick(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.frequency_timer_period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    // Clocked at 256 Hz by the frame sequencer
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // Clocked at 64 Hz by the frame sequencer
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.volume_envelope.0);
+    }
+
+    // Clocked at 128 Hz by the frame sequencer
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = self.sweep_period();
+        // A pace of 0 does not update the frequency
+        if !self.sweep_enabled || self.sweep_pace() == 0 {
+            return;
+        }
+        let frequency = self.next_sweep_frequency();
+        if frequency <= MAX_FREQUENCY && self.sweep_shift() != 0 {
+            self.shadow_frequency = frequency;
+            self.set_frequency(frequency);
+            // The new frequency is checked for overflow again, but not written back
+            self.next_sweep_frequency();
+        }
+    }
+
+    // Digital output, from 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let duty = DUTY_PATTERNS[(self.duty_and_length.0 >> 6) as usize];
+        if (duty >> (7 - self.duty_step)) & 1 == 1 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
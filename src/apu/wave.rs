@@ -0,0 +1,138 @@
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+use super::length_counter::LengthCounter;
+
+const WAVE_LENGTH: u16 = 256;
+pub const WAVE_RAM_SIZE: usize = 16;
+
+const DAC_ENABLE_BIT: u8 = 7;
+const LENGTH_ENABLE_BIT: u8 = 6;
+const TRIGGER_BIT: u8 = 7;
+
+/// Wave channel, playing back the 32 4-bit samples of wave RAM.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct WaveChannel {
+    /// NR30 (0xFF1A): DAC enable (bit 7).
+    pub dac_enable: Wrapping<u8>,
+    /// NR31 (0xFF1B): initial length timer.
+    pub length: Wrapping<u8>,
+    /// NR32 (0xFF1C): output level (bits 5-6).
+    pub output_level: Wrapping<u8>,
+    /// NR33 (0xFF1D): lower 8 bits of the 11-bit frequency.
+    pub frequency_low: Wrapping<u8>,
+    /// NR34 (0xFF1E): trigger (bit 7), length enable (bit 6), and upper 3 bits of the frequency.
+    pub frequency_high_and_control: Wrapping<u8>,
+    /// 0xFF30-0xFF3F: two samples per byte, upper nibble first.
+    pub wave_ram: [Wrapping<u8>; WAVE_RAM_SIZE],
+
+    pub enabled: bool,
+    /// Index of the current sample in wave RAM, from 0 to 31.
+    position: u8,
+    frequency_timer: u16,
+    length_counter: LengthCounter,
+}
+
+impl Default for WaveChannel {
+    fn default() -> Self {
+        WaveChannel::new()
+    }
+}
+
+impl WaveChannel {
+    pub fn new() -> Self {
+        WaveChannel {
+            dac_enable: Wrapping(0),
+            length: Wrapping(0),
+            output_level: Wrapping(0),
+            frequency_low: Wrapping(0),
+            frequency_high_and_control: Wrapping(0),
+            wave_ram: [Wrapping(0); WAVE_RAM_SIZE],
+            enabled: false,
+            position: 0,
+            frequency_timer: 0,
+            length_counter: LengthCounter::new(WAVE_LENGTH),
+        }
+    }
+
+    fn frequency(&self) -> u16 {
+        ((self.frequency_high_and_control.0 as u16 & 0x07) << 8) | self.frequency_low.0 as u16
+    }
+
+    // The position advances every (2048 - frequency) * 2 T-cycles, twice as fast as square waves
+    fn frequency_timer_period(&self) -> u16 {
+        (2048 - self.frequency()) * 2
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        utils::is_bit_set(&self.dac_enable, DAC_ENABLE_BIT)
+    }
+
+    pub fn write_dac_enable(&mut self, value: Wrapping<u8>) {
+        self.dac_enable = value;
+        if !self.is_dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn write_length(&mut self, value: Wrapping<u8>) {
+        self.length = value;
+        self.length_counter.load(value.0 as u16);
+    }
+
+    pub fn write_frequency_high_and_control(&mut self, value: Wrapping<u8>) {
+        self.frequency_high_and_control = value;
+        self.length_counter.enabled = utils::is_bit_set(&value, LENGTH_ENABLE_BIT);
+        if utils::is_bit_set(&value, TRIGGER_BIT) {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.is_dac_enabled();
+        self.length_counter.trigger();
+        self.frequency_timer = self.frequency_timer_period();
+        self.position = 0;
+    }
+
+    pub fn tick(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.frequency_timer_period();
+            self.position = (self.position + 1) % (2 * WAVE_RAM_SIZE as u8);
+        }
+    }
+
+    // Clocked at 256 Hz by the frame sequencer
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn current_sample(&self) -> u8 {
+        let byte = self.wave_ram[(self.position / 2) as usize].0;
+        if self.position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        }
+    }
+
+    // Digital output, from 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        // Output levels: mute, 100%, 50%, and 25%
+        match (self.output_level.0 >> 5) & 0x03 {
+            0 => 0,
+            level => self.current_sample() >> (level - 1),
+        }
+    }
+}
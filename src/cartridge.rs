@@ -0,0 +1,330 @@
+use std::io::{self, Error};
+
+use serde::{Deserialize, Serialize};
+
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0144;
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const ROM_SIZE_ADDRESS: usize = 0x0148;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x014E;
+const HEADER_END: usize = 0x0150;
+
+// MBC2 has 512 half-bytes of RAM built in, and reports no RAM in its header
+const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MapperType {
+    ROMOnly,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+    Other, // TODO
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RAMSize {
+    NoRAM,
+    Ram2kb,
+    Ram8kb,
+    Ram4banks8kb,
+    Ram16banks8kb,
+    Ram8banks8kb,
+}
+
+impl RAMSize {
+    pub fn byte_size(&self) -> usize {
+        match self {
+            RAMSize::NoRAM => 0,
+            RAMSize::Ram2kb => 0x800,
+            RAMSize::Ram8kb => 0x2000,
+            RAMSize::Ram4banks8kb => 0x8000,
+            RAMSize::Ram16banks8kb => 0x20000,
+            RAMSize::Ram8banks8kb => 0x10000,
+        }
+    }
+}
+
+/// Metadata parsed from the cartridge header, at 0x0100-0x014F.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cartridge {
+    title: String,
+    /// Raw cartridge type byte (0x0147), from which `mapper_type` and `has_battery` are derived.
+    pub cartridge_type: u8,
+    pub mapper_type: MapperType,
+    pub has_battery: bool,
+    /// MBC5 cartridges with a rumble motor, driven by bit 3 of the RAM bank register.
+    pub has_rumble: bool,
+    pub rom_banks: u16,
+    pub ram_size: RAMSize,
+    /// 0x80 for games supporting CGB enhancements, 0xC0 for CGB-only games.
+    pub cgb_flag: u8,
+    header_checksum_valid: bool,
+    global_checksum_valid: bool,
+}
+
+// Checksum of 0x0134-0x014C, which the boot ROM verifies before starting the game
+fn header_checksum(bytes: &[u8]) -> u8 {
+    bytes[TITLE_START..HEADER_CHECKSUM_ADDRESS]
+        .iter()
+        .fold(0u8, |checksum, byte| {
+            checksum.wrapping_sub(*byte).wrapping_sub(1)
+        })
+}
+
+// Sum of all ROM bytes but the global checksum itself, which nothing verifies on hardware
+fn global_checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .enumerate()
+        .filter(|(address, _)| !(GLOBAL_CHECKSUM_ADDRESS..HEADER_END).contains(address))
+        .fold(0u16, |checksum, (_, byte)| {
+            checksum.wrapping_add(*byte as u16)
+        })
+}
+
+// Titles are meant to be uppercase ASCII, padded with 0x00.  Anything else that is not printable
+// gets replaced, as some games use the last bytes for other purposes.
+fn decode_title(bytes: &[u8]) -> String {
+    let length = bytes
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    bytes[..length]
+        .iter()
+        .map(|byte| match byte {
+            0x20..=0x7E => *byte as char,
+            _ => '?',
+        })
+        .collect()
+}
+
+impl Cartridge {
+    // Placeholder until a ROM header gets parsed, which is not a meaningful default cartridge
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Cartridge {
+            title: String::new(),
+            cartridge_type: 0,
+            mapper_type: MapperType::ROMOnly,
+            has_battery: false,
+            has_rumble: false,
+            rom_banks: 2,
+            ram_size: RAMSize::NoRAM,
+            cgb_flag: 0,
+            header_checksum_valid: false,
+            global_checksum_valid: false,
+        }
+    }
+
+    pub fn from_header(bytes: &[u8]) -> Result<Self, io::Error> {
+        if bytes.len() < HEADER_END {
+            return Err(Error::other("ROM too small to contain a cartridge header."));
+        }
+
+        let cgb_flag = bytes[CGB_FLAG_ADDRESS];
+        // On CGB cartridges, the last byte of the title area is the CGB flag
+        let title_end = if cgb_flag & 0x80 != 0 {
+            CGB_FLAG_ADDRESS
+        } else {
+            TITLE_END
+        };
+        let title = decode_title(&bytes[TITLE_START..title_end]);
+
+        let cartridge_type = bytes[CARTRIDGE_TYPE_ADDRESS];
+        let mapper_type = match cartridge_type {
+            0x00 | 0x08 | 0x09 => MapperType::ROMOnly,
+            0x01..=0x03 => MapperType::MBC1,
+            0x05 | 0x06 => MapperType::MBC2,
+            0x0F..=0x13 => MapperType::MBC3,
+            0x19..=0x1E => MapperType::MBC5,
+            byte => {
+                println!("Unhandled mapper type: 0x{:02X}", byte);
+                MapperType::Other
+            }
+        };
+        let has_battery = matches!(
+            cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        );
+        let has_rumble = matches!(cartridge_type, 0x1C..=0x1E);
+
+        let rom_banks = match bytes[ROM_SIZE_ADDRESS] {
+            byte @ 0x00..=0x08 => 2 << byte,
+            byte => return Err(Error::other(format!("Unhandled ROM size: 0x{:02X}", byte))),
+        };
+        let ram_size = match bytes[RAM_SIZE_ADDRESS] {
+            0x00 => RAMSize::NoRAM,
+            0x01 => RAMSize::Ram2kb,
+            0x02 => RAMSize::Ram8kb,
+            0x03 => RAMSize::Ram4banks8kb,
+            0x04 => RAMSize::Ram16banks8kb,
+            0x05 => RAMSize::Ram8banks8kb,
+            byte => return Err(Error::other(format!("Unhandled RAM size: 0x{:02X}", byte))),
+        };
+
+        let header_checksum_valid = header_checksum(bytes) == bytes[HEADER_CHECKSUM_ADDRESS];
+        let global_checksum_valid = global_checksum(bytes)
+            == u16::from_be_bytes([
+                bytes[GLOBAL_CHECKSUM_ADDRESS],
+                bytes[GLOBAL_CHECKSUM_ADDRESS + 1],
+            ]);
+
+        Ok(Cartridge {
+            title,
+            cartridge_type,
+            mapper_type,
+            has_battery,
+            has_rumble,
+            rom_banks,
+            ram_size,
+            cgb_flag,
+            header_checksum_valid,
+            global_checksum_valid,
+        })
+    }
+
+    /// The game's name, as printable ASCII.
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    /// Whether the game supports CGB enhancements, or requires a CGB.
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    // A bad header checksum locks up the boot ROM, but the game itself does not care
+    pub fn verify_header_checksum(&self) -> bool {
+        self.header_checksum_valid
+    }
+
+    // Only tooling cares about this one
+    pub fn verify_global_checksum(&self) -> bool {
+        self.global_checksum_valid
+    }
+
+    pub fn external_ram_size(&self) -> usize {
+        if self.mapper_type == MapperType::MBC2 {
+            MBC2_RAM_SIZE
+        } else {
+            self.ram_size.byte_size()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        global_checksum, header_checksum, Cartridge, MapperType, CARTRIDGE_TYPE_ADDRESS,
+        CGB_FLAG_ADDRESS, GLOBAL_CHECKSUM_ADDRESS, HEADER_CHECKSUM_ADDRESS, HEADER_END,
+        RAM_SIZE_ADDRESS, ROM_SIZE_ADDRESS, TITLE_START,
+    };
+
+    // The header fields of a game, the title area including any manufacturer code
+    fn header(
+        title: &[u8],
+        cgb_flag: u8,
+        cartridge_type: u8,
+        rom_size: u8,
+        ram_size: u8,
+    ) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_END];
+        bytes[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        bytes[CGB_FLAG_ADDRESS] = cgb_flag;
+        bytes[CARTRIDGE_TYPE_ADDRESS] = cartridge_type;
+        bytes[ROM_SIZE_ADDRESS] = rom_size;
+        bytes[RAM_SIZE_ADDRESS] = ram_size;
+        bytes[HEADER_CHECKSUM_ADDRESS] = header_checksum(&bytes);
+        bytes
+    }
+
+    #[test]
+    fn parses_game_headers() {
+        let tetris = Cartridge::from_header(&header(b"TETRIS", 0x00, 0x00, 0x00, 0x00)).unwrap();
+        assert_eq!(tetris.title, "TETRIS");
+        assert_eq!(tetris.mapper_type, MapperType::ROMOnly);
+        assert_eq!(tetris.rom_banks, 2);
+        assert_eq!(tetris.external_ram_size(), 0);
+        assert!(!tetris.has_battery);
+        assert!(!tetris.supports_cgb());
+        assert!(tetris.verify_header_checksum());
+
+        let mario = Cartridge::from_header(&header(b"SUPER MARIOLAND", 0x00, 0x01, 0x01, 0x00));
+        let mario = mario.unwrap();
+        assert_eq!(mario.title, "SUPER MARIOLAND");
+        assert_eq!(mario.mapper_type, MapperType::MBC1);
+        assert_eq!(mario.rom_banks, 4);
+
+        let red = Cartridge::from_header(&header(b"POKEMON RED", 0x00, 0x13, 0x05, 0x03)).unwrap();
+        assert_eq!(red.title, "POKEMON RED");
+        assert_eq!(red.mapper_type, MapperType::MBC3);
+        assert_eq!(red.rom_banks, 64);
+        assert_eq!(red.external_ram_size(), 0x8000);
+        assert!(red.has_battery);
+
+        // The CGB flag takes the place of the last title character
+        let crystal = Cartridge::from_header(&header(b"PM_CRYSTALBYTE", 0xC0, 0x10, 0x06, 0x03));
+        let crystal = crystal.unwrap();
+        assert_eq!(crystal.title, "PM_CRYSTALBYTE");
+        assert_eq!(crystal.mapper_type, MapperType::MBC3);
+        assert_eq!(crystal.rom_banks, 128);
+        assert!(crystal.has_battery);
+        assert!(crystal.supports_cgb());
+    }
+
+    #[test]
+    fn titles_are_trimmed_and_made_printable() {
+        let title = |bytes: &[u8], cgb_flag| {
+            Cartridge::from_header(&header(bytes, cgb_flag, 0x00, 0x00, 0x00))
+                .unwrap()
+                .title()
+        };
+        assert_eq!(title(b"", 0x00), "");
+        assert_eq!(title(b"ZELDA\0\0\0", 0x00), "ZELDA");
+        assert_eq!(title(b"ZELDA\xFF\x01", 0x00), "ZELDA??");
+        assert_eq!(title(b"AB\0CD", 0x00), "AB?CD");
+        // Only a CGB flag with bit 7 set takes the place of the last character
+        assert_eq!(title(b"POKEMON_SLVAAXE", 0x80), "POKEMON_SLVAAXE");
+        assert_eq!(title(b"ABCDEFGHIJKLMNO", 0x41), "ABCDEFGHIJKLMNOA");
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(Cartridge::from_header(&[0; HEADER_END - 1]).is_err());
+        assert!(Cartridge::from_header(&header(b"", 0x00, 0x00, 0x09, 0x00)).is_err());
+        let mut bytes = header(b"TETRIS", 0x00, 0x00, 0x00, 0x00);
+        bytes[HEADER_CHECKSUM_ADDRESS] ^= 0xFF;
+        assert!(!Cartridge::from_header(&bytes)
+            .unwrap()
+            .verify_header_checksum());
+    }
+
+    #[test]
+    fn checksums_detect_corrupted_bytes() {
+        let mut rom = header(b"TETRIS", 0x00, 0x00, 0x00, 0x00);
+        rom.resize(0x8000, 0);
+        rom[0x0150..0x0200].fill(0xA5);
+        let checksum = global_checksum(&rom).to_be_bytes();
+        rom[GLOBAL_CHECKSUM_ADDRESS..HEADER_END].copy_from_slice(&checksum);
+        let cartridge = Cartridge::from_header(&rom).unwrap();
+        assert!(cartridge.verify_header_checksum());
+        assert!(cartridge.verify_global_checksum());
+
+        // Outside of the header, only the global checksum notices
+        let mut corrupted = rom.clone();
+        corrupted[0x4000] = 0x01;
+        let cartridge = Cartridge::from_header(&corrupted).unwrap();
+        assert!(cartridge.verify_header_checksum());
+        assert!(!cartridge.verify_global_checksum());
+
+        let mut corrupted = rom;
+        corrupted[0x0134] = b'Z';
+        let cartridge = Cartridge::from_header(&corrupted).unwrap();
+        assert!(!cartridge.verify_header_checksum());
+        assert!(!cartridge.verify_global_checksum());
+    }
+}
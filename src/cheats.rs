@@ -0,0 +1,236 @@
+use std::num::Wrapping;
+
+use crate::machine::Machine;
+
+// The only GameShark code type meaningful on DMG: write to RAM, without bank switching
+const GAME_SHARK_RAM_WRITE: u8 = 0x01;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cheat {
+    /// Patches reads of a ROM address.  When `compare` is set, the ROM byte must match it for the
+    /// patch to apply, which restricts the code to one ROM bank.
+    GameGenie {
+        address: u16,
+        replacement: u8,
+        compare: Option<u8>,
+    },
+    /// Forces a RAM value, rewritten every frame.
+    GameShark { address: u16, value: u8 },
+}
+
+// Dashes only separate groups of digits
+fn hex_digits(code: &str) -> Result<Vec<u8>, String> {
+    code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| {
+            c.to_digit(16)
+                .map(|digit| digit as u8)
+                .ok_or_else(|| format!("Invalid character '{}' in cheat code {}", c, code))
+        })
+        .collect()
+}
+
+impl Cheat {
+    /// Parses either a Game Genie code (`ABC-DEF` or `ABC-DEF-GHI`) or a GameShark code
+    /// (`01VVAAAA`, with the address in little-endian).
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let code = code.trim();
+        let groups: Vec<&str> = code.split('-').collect();
+        match groups.as_slice() {
+            [game_shark] if game_shark.len() == 8 => Cheat::parse_game_shark(game_shark),
+            [_, _] | [_, _, _] if groups.iter().all(|group| group.len() == 3) => {
+                Cheat::parse_game_genie(code)
+            }
+            _ => Err(format!(
+                "Unrecognized cheat code {}, expected ABC-DEF, ABC-DEF-GHI, or 01VVAAAA",
+                code
+            )),
+        }
+    }
+
+    // Digits ABC-DEF-GHI: AB is the replacement, FCDE the address with F complemented, and GI the
+    // compare value, rotated and scrambled.  H is not used.
+    fn parse_game_genie(code: &str) -> Result<Self, String> {
+        let d = hex_digits(code)?;
+        let replacement = (d[0] << 4) | d[1];
+        let address = (((d[5] ^ 0xF) as u16) << 12)
+            | ((d[2] as u16) << 8)
+            | ((d[3] as u16) << 4)
+            | d[4] as u16;
+        if address > 0x7FFF {
+            return Err(format!(
+                "Game Genie code {} targets 0x{:04X}, outside of ROM",
+                code, address
+            ));
+        }
+        let compare = (d.len() == 9).then(|| ((d[6] << 4) | d[8]).rotate_right(2) ^ 0xBA);
+        Ok(Cheat::GameGenie {
+            address,
+            replacement,
+            compare,
+        })
+    }
+
+    fn parse_game_shark(code: &str) -> Result<Self, String> {
+        let d = hex_digits(code)?;
+        let code_type = (d[0] << 4) | d[1];
+        if code_type != GAME_SHARK_RAM_WRITE {
+            return Err(format!(
+                "Unsupported GameShark code type 0x{:02X} in {}",
+                code_type, code
+            ));
+        }
+        let value = (d[2] << 4) | d[3];
+        let address = u16::from_le_bytes([(d[4] << 4) | d[5], (d[6] << 4) | d[7]]);
+        // External RAM and WRAM: echo RAM, OAM and I/O registers are not meant to be written to
+        if !(0xA000..=0xDFFF).contains(&address) {
+            return Err(format!(
+                "GameShark code {} targets 0x{:04X}, outside of 0xA000-0xDFFF RAM",
+                code, address
+            ));
+        }
+        Ok(Cheat::GameShark { address, value })
+    }
+}
+
+/// Active cheat codes.  Game Genie codes are applied by `Machine::read_u8`, GameShark codes upon
+/// entering VBlank.
+#[derive(Clone, Debug)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: Vec::new() }
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn patch_rom_read(&self, address: Wrapping<u16>, value: Wrapping<u8>) -> Wrapping<u8> {
+        for cheat in &self.cheats {
+            if let Cheat::GameGenie {
+                address: patched_address,
+                replacement,
+                compare,
+            } = cheat
+            {
+                if *patched_address == address.0 && compare.is_none_or(|c| c == value.0) {
+                    return Wrapping(*replacement);
+                }
+            }
+        }
+        value
+    }
+
+    pub fn game_shark_writes(&self) -> Vec<(Wrapping<u16>, Wrapping<u8>)> {
+        self.cheats
+            .iter()
+            .filter_map(|cheat| match cheat {
+                Cheat::GameShark { address, value } => Some((Wrapping(*address), Wrapping(*value))),
+                Cheat::GameGenie { .. } => None,
+            })
+            .collect()
+    }
+}
+
+impl Machine {
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        let cheat = Cheat::parse(code)?;
+        self.cheats_mut().add(cheat);
+        Ok(())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats_mut().clear();
+    }
+
+    pub fn apply_game_shark_cheats(&mut self) {
+        for (address, value) in self.cheats().game_shark_writes() {
+            self.write_u8(address, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use super::{Cheat, Cheats};
+
+    #[test]
+    fn game_genie_patch_applies_only_when_the_compare_byte_matches() {
+        let cheat = Cheat::parse("3E1-23B-EA3").unwrap();
+        assert_eq!(
+            cheat,
+            Cheat::GameGenie {
+                address: 0x4123,
+                replacement: 0x3E,
+                compare: Some(0x42),
+            }
+        );
+        let mut cheats = Cheats::new();
+        cheats.add(cheat);
+        let patch =
+            |address: u16, value: u8| cheats.patch_rom_read(Wrapping(address), Wrapping(value)).0;
+        assert_eq!(patch(0x4123, 0x42), 0x3E);
+        // Another ROM bank mapped at that address
+        assert_eq!(patch(0x4123, 0x43), 0x43);
+        assert_eq!(patch(0x4124, 0x42), 0x42);
+
+        // Without a compare value, the patch applies whatever the ROM byte
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat::parse("3E1-23B").unwrap());
+        assert_eq!(
+            cheats.patch_rom_read(Wrapping(0x4123), Wrapping(0x43)).0,
+            0x3E
+        );
+    }
+
+    #[test]
+    fn game_shark_codes_target_ram() {
+        assert_eq!(
+            Cheat::parse("01FF34C1"),
+            Ok(Cheat::GameShark {
+                address: 0xC134,
+                value: 0xFF,
+            })
+        );
+        assert_eq!(
+            Cheat::parse("010500A0"),
+            Ok(Cheat::GameShark {
+                address: 0xA000,
+                value: 0x05,
+            })
+        );
+        assert!(Cheat::parse("0105FFDF").is_ok());
+        // LY, which used to be accepted
+        assert_eq!(
+            Cheat::parse("010544FF"),
+            Err("GameShark code 010544FF targets 0xFF44, outside of 0xA000-0xDFFF RAM".to_string())
+        );
+        for code in ["0105FF9F", "010500E0", "010580FF"] {
+            assert!(Cheat::parse(code).is_err(), "{}", code);
+        }
+    }
+}
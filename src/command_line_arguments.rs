@@ -3,8 +3,9 @@ use clap::Parser;
 #[derive(Clone, Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct CommandLineArguments {
+    /// When omitted, the game starts right away from the state the DMG boot ROM leaves behind.
     #[arg(short, long)]
-    pub boot_rom: String,
+    pub boot_rom: Option<String>,
     #[arg(short, long)]
     pub game_rom: String,
     #[arg(short, long, default_value_t = false)]
@@ -1,23 +1,92 @@
 pub mod interrupts;
 pub mod timers;
 
-use std::num::Wrapping;
+use std::{collections::BTreeSet, num::Wrapping};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    application_state::ROMInformation,
+    cartridge::Cartridge,
     instructions::{
-        decode::{decode_instruction_at_address, DecodedInstruction},
-        type_def::Immediate16,
+        decode::{
+            decode_instruction_after_halt_bug, decode_instruction_at_address, DecodedInstruction,
+        },
+        type_def::{Immediate16, Instruction},
     },
     machine::Machine,
     memory::Memory,
-    registers::{Registers, R16},
+    registers::{Flag, Registers, R16},
 };
 
-#[derive(Clone, Debug, Hash)]
+const KEY1_UNUSED_BITS: u8 = 0x7E;
+
+#[derive(Clone, Debug)]
+pub enum StepResult {
+    /// An instruction was executed, taking (T-cycles, M-cycles).
+    Executed(DecodedInstruction, (u8, u8)),
+    /// No instruction was executed as the CPU is in low power mode, awaiting an interrupt.
+    Halted((u8, u8)),
+    /// The instruction at this address was about to be executed, and was not.
+    BreakpointHit(u16),
+    /// The CPU is locked up by this illegal opcode, at this address.  Reported again on every step
+    /// until reset.
+    IllegalOpcode(u8, u16),
+}
+
+/// What the CPU did during a step, as reported by `CPU::step_instruction`.
+#[derive(Clone, Debug)]
+pub enum StepActivity {
+    /// The opcode and operands are in `raw`, their decoding in `instruction`.
+    Executed(DecodedInstruction),
+    /// An interrupt handler got called.  Like on every step, the handler's first instruction
+    /// executed as part of the dispatch.
+    InterruptDispatched,
+    /// HALT is waiting for an interrupt.
+    Halted,
+    /// STOP is waiting for a button press.
+    Stopped,
+    /// The CPU is locked up by this illegal opcode, at this address.
+    LockedUp(u8, u16),
+}
+
+/// What a single step did, as reported by `CPU::step_instruction`.
+#[derive(Clone, Debug)]
+pub struct StepInfo {
+    pub activity: StepActivity,
+    /// T-cycles taken by the step, which for conditional instructions depends on whether the
+    /// condition held.
+    pub t_cycles: u8,
+    /// Registers and flags after the step.
+    pub registers: Registers,
+}
+
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
 pub struct CPU {
     // CPU state
     pub low_power_mode: bool,
+    /// Set by STOP.  The whole machine stops, the PPU and timers included, until a button of a
+    /// selected row is pressed.
+    pub stopped: bool,
+    /// Set by a HALT executed with IME clear and an interrupt pending, see `Instruction::HALT`.
+    pub halt_bug: bool,
+    /// Set by EI, IME gets set once the following instruction completes.
+    pub ime_pending: bool,
+    /// Set by executing an illegal opcode, upon which the CPU hangs until reset, not even
+    /// servicing interrupts.  PC stays on the opcode.
+    pub locked_up: bool,
+    /// CGB double-speed mode, in which the CPU, timers, serial, and DMA run twice as fast relative
+    /// to the PPU and APU.
+    pub double_speed: bool,
+    /// Bit 0 of KEY1 (0xFF4D), the speed switch happens on the next STOP.
+    pub speed_switch_armed: bool,
+
+    // Debugging state
+    /// Addresses of instructions before which execution stops.  Not part of save states, the
+    /// breakpoints of the running machine are kept when loading one.
+    #[serde(skip)]
+    breakpoints: BTreeSet<u16>,
+    /// The breakpoint that was last reported, so that resuming execution steps over it.
+    breakpoint_hit: Option<u16>,
 
     // Subsystems
     memory: Memory,
@@ -25,39 +94,129 @@ pub struct CPU {
 }
 
 impl CPU {
-    pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, rom_information: &ROMInformation) -> Self {
+    pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, cartridge: &Cartridge) -> Self {
         CPU {
             low_power_mode: false,
-            memory: Memory::new(boot_rom, game_rom, rom_information),
+            stopped: false,
+            halt_bug: false,
+            ime_pending: false,
+            locked_up: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            breakpoints: BTreeSet::new(),
+            breakpoint_hit: None,
+            memory: Memory::new(boot_rom, game_rom, cartridge),
             registers: Registers::new(),
         }
     }
 
-    pub fn execute_one_instruction(
-        machine: &mut Machine,
-    ) -> (Option<DecodedInstruction>, (u8, u8)) {
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn take_breakpoints_from(&mut self, other: &mut CPU) {
+        self.breakpoints = std::mem::take(&mut other.breakpoints);
+    }
+
+    pub fn execute_one_instruction(machine: &mut Machine) -> StepResult {
+        if machine.cpu().locked_up {
+            let pc = machine.cpu().registers.pc;
+            return StepResult::IllegalOpcode(machine.read_u8_unrestricted(pc).0, pc.0);
+        }
         if machine.cpu_mut().low_power_mode {
             if machine.interrupts.is_interrupt_pending() {
                 machine.cpu_mut().low_power_mode = false;
                 // Fall through on wakeup to execute one instruction
             } else {
                 // Otherwise, force the other components to move forward
-                return (None, (4, 1));
+                return StepResult::Halted((4, 1));
             }
         }
-        let next_instruction = decode_instruction_at_address(machine, machine.cpu().registers.pc);
+        // A breakpoint is only reported once, the next step executes the instruction
+        let pc = machine.cpu().registers.pc.0;
+        if machine.cpu().has_breakpoint(pc) && machine.cpu().breakpoint_hit != Some(pc) {
+            machine.cpu_mut().breakpoint_hit = Some(pc);
+            return StepResult::BreakpointHit(pc);
+        }
+        machine.cpu_mut().breakpoint_hit = None;
+        let pc = machine.cpu().registers.pc;
+        let halt_bug = std::mem::take(&mut machine.cpu_mut().halt_bug);
+        let next_instruction = if halt_bug {
+            decode_instruction_after_halt_bug(machine, pc)
+        } else {
+            decode_instruction_at_address(machine, pc)
+        };
+        if let Instruction::Illegal(opcode) = next_instruction.instruction {
+            machine.cpu_mut().locked_up = true;
+            return StepResult::IllegalOpcode(opcode, pc.0);
+        }
+        // The opcode and operands take an M-cycle each to fetch, before any other memory access
+        machine.skip_cpu_m_cycles(next_instruction.instruction_size);
         // println!("About to execute {}", next_instruction);
-        // This will be the default PC, unless instruction semantics overwrite it
+        // This will be the default PC, unless instruction semantics overwrite it.  After the HALT
+        // bug, one less byte was consumed, as the opcode was read twice.
         machine.cpu_mut().registers.pc =
-            machine.cpu_mut().registers.pc + Wrapping(next_instruction.instruction_size as u16);
+            pc + Wrapping(next_instruction.instruction_size as u16 - halt_bug as u16);
+        // Checked before and after, so that the EI itself does not enable interrupts, while a DI
+        // right after it cancels the pending enable
+        let ime_was_pending = machine.cpu().ime_pending;
         let cycles = next_instruction.instruction.execute(machine);
-        (Some(next_instruction), cycles)
+        if ime_was_pending && machine.cpu().ime_pending {
+            machine.cpu_mut().ime_pending = false;
+            machine.interrupts_mut().interrupt_master_enable = true;
+        }
+        StepResult::Executed(next_instruction, cycles)
+    }
+
+    // Steps the whole machine once, and reports what the CPU did.  Breakpoints get stepped over.
+    // The CPU not executing an instruction is reported rather than waited out, as HALT, STOP and
+    // lockups can last forever.
+    pub fn step_instruction(machine: &mut Machine) -> StepInfo {
+        let mut step = machine.step();
+        // A breakpoint is only reported once, so the next step executes the instruction
+        if step.breakpoint_hit.is_some() {
+            step = machine.step();
+        }
+        let activity = if let Some(instruction) = step.instruction_executed {
+            StepActivity::Executed(instruction)
+        } else if step.interrupt_dispatched {
+            StepActivity::InterruptDispatched
+        } else if let Some((opcode, address)) = step.illegal_opcode {
+            StepActivity::LockedUp(opcode, address)
+        } else if machine.cpu().stopped {
+            StepActivity::Stopped
+        } else {
+            StepActivity::Halted
+        };
+        // The machine steps in dots, of which there are half as many in double-speed mode
+        let t_cycles = (step.dots as u8) << machine.cpu().double_speed as u8;
+        StepInfo {
+            activity,
+            t_cycles,
+            registers: machine.registers().clone(),
+        }
+    }
+
+    pub fn read_key1(&self) -> Wrapping<u8> {
+        Wrapping(KEY1_UNUSED_BITS | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8)
+    }
+
+    pub fn write_key1(&mut self, value: Wrapping<u8>) {
+        self.speed_switch_armed = value.0 & 1 == 1;
     }
 
     pub fn pop_r16<'a>(machine: &'a mut Machine, r16: &R16) -> &'a mut Machine {
-        let lower = machine.read_u8(machine.cpu().registers.sp);
+        let lower = machine.cpu_read_u8(machine.cpu().registers.sp);
         machine.cpu_mut().registers.sp += 1;
-        let higher = machine.read_u8(machine.cpu().registers.sp);
+        let higher = machine.cpu_read_u8(machine.cpu().registers.sp);
         machine.cpu_mut().registers.sp += 1;
         let imm16 = Immediate16 {
             lower_byte: lower,
@@ -70,33 +229,45 @@ impl CPU {
     // Note: pushes the higher byte goes to higher address!!!
     pub fn push_imm16<'a>(machine: &'a mut Machine, imm16: Immediate16) -> &'a mut Machine {
         machine.cpu_mut().registers.sp -= 1;
-        machine.write_u8(machine.cpu().registers.sp, imm16.higher_byte);
+        machine.cpu_write_u8(machine.cpu().registers.sp, imm16.higher_byte);
         machine.cpu_mut().registers.sp -= 1;
-        machine.write_u8(machine.cpu().registers.sp, imm16.lower_byte);
+        machine.cpu_write_u8(machine.cpu().registers.sp, imm16.lower_byte);
         machine
     }
 
-    pub fn gbdoctor_string(machine: &Machine) -> String {
-        let cpu = &machine.cpu();
+    // One-line summary of the register file, e.g.
+    // A:01 F:Z-HC BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100 IME:1
+    pub fn dump_state(machine: &Machine) -> String {
+        let registers = &machine.cpu().registers;
+        let flag = |flag: Flag, letter: char| {
+            if registers.read_flag(flag) {
+                letter
+            } else {
+                '-'
+            }
+        };
         let mut res = String::new();
-        res.push_str(&format!("A:{:02X} ", cpu.registers.read_a()));
-        res.push_str(&format!("F:{:02X} ", cpu.registers.read_f()));
-        res.push_str(&format!("B:{:02X} ", cpu.registers.read_b()));
-        res.push_str(&format!("C:{:02X} ", cpu.registers.read_c()));
-        res.push_str(&format!("D:{:02X} ", cpu.registers.read_d()));
-        res.push_str(&format!("E:{:02X} ", cpu.registers.read_e()));
-        res.push_str(&format!("H:{:02X} ", cpu.registers.read_h()));
-        res.push_str(&format!("L:{:02X} ", cpu.registers.read_l()));
-        res.push_str(&format!("SP:{:04X} ", cpu.registers.sp));
-        let pc = cpu.registers.pc;
-        res.push_str(&format!("PC:{:04X} ", pc));
+        res.push_str(&format!("A:{:02X} ", registers.read_a()));
         res.push_str(&format!(
-            "PCMEM:{:02X},{:02X},{:02X},{:02X}",
-            machine.read_u8(pc),
-            machine.read_u8(pc + Wrapping(1)),
-            machine.read_u8(pc + Wrapping(2)),
-            machine.read_u8(pc + Wrapping(3))
+            "F:{}{}{}{} ",
+            flag(Flag::Z, 'Z'),
+            flag(Flag::N, 'N'),
+            flag(Flag::H, 'H'),
+            flag(Flag::C, 'C')
+        ));
+        res.push_str(&format!("BC:{:04X} ", registers.bc));
+        res.push_str(&format!("DE:{:04X} ", registers.de));
+        res.push_str(&format!("HL:{:04X} ", registers.hl));
+        res.push_str(&format!("SP:{:04X} ", registers.sp));
+        res.push_str(&format!("PC:{:04X} ", registers.pc));
+        res.push_str(&format!(
+            "IME:{}",
+            machine.interrupts().interrupt_master_enable as u8
         ));
+        // An EI was just executed, IME gets set after the next instruction
+        if machine.cpu().ime_pending {
+            res.push_str(" (pending)");
+        }
         res
     }
 
@@ -133,4 +304,372 @@ impl Machine {
     pub fn registers_mut(&mut self) -> &mut Registers {
         &mut self.cpu_mut().registers
     }
+
+    // Line in the format of Gameboy Doctor logs, to be emitted before each instruction.  Gameboy
+    // Doctor also expects LY to always read 0x90, see `PPU::read_ly`.
+    pub fn gb_doctor_line(&self) -> String {
+        let registers = self.registers();
+        let mut res = String::new();
+        res.push_str(&format!("A:{:02X} ", registers.read_a()));
+        res.push_str(&format!("F:{:02X} ", registers.read_f()));
+        res.push_str(&format!("B:{:02X} ", registers.read_b()));
+        res.push_str(&format!("C:{:02X} ", registers.read_c()));
+        res.push_str(&format!("D:{:02X} ", registers.read_d()));
+        res.push_str(&format!("E:{:02X} ", registers.read_e()));
+        res.push_str(&format!("H:{:02X} ", registers.read_h()));
+        res.push_str(&format!("L:{:02X} ", registers.read_l()));
+        res.push_str(&format!("SP:{:04X} ", registers.sp));
+        let pc = registers.pc;
+        res.push_str(&format!("PC:{:04X} ", pc));
+        // Not going through `read_u8`, so that logging does not trigger watchpoints
+        res.push_str(&format!(
+            "PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.read_u8_unrestricted(pc),
+            self.read_u8_unrestricted(pc + Wrapping(1)),
+            self.read_u8_unrestricted(pc + Wrapping(2)),
+            self.read_u8_unrestricted(pc + Wrapping(3))
+        ));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        cartridge::Cartridge,
+        conditions::Condition,
+        inputs::Button,
+        instructions::{assembler::Assembler, type_def::Instruction},
+        machine::Machine,
+        registers::R8,
+        test_utils::{
+            cgb_machine_running, machine_running, machine_with_rom, step_until_pc, with_large_stack,
+        },
+    };
+
+    use super::{StepActivity, CPU};
+
+    // System counter increments per dot, over a few DIV periods
+    fn system_counter_speed(machine: &mut Machine) -> u64 {
+        let start_counter = machine.timers().system_counter;
+        let start_dots = machine.dot_count;
+        while machine.dot_count - start_dots < 0x1000 {
+            machine.step();
+        }
+        let counter = (machine.timers().system_counter - start_counter).0 as u64;
+        counter / (machine.dot_count - start_dots)
+    }
+
+    fn executed(activity: StepActivity) -> Instruction {
+        match activity {
+            StepActivity::Executed(decoded) => decoded.instruction,
+            activity => panic!("Expected an instruction, got {:?}", activity),
+        }
+    }
+
+    #[test]
+    fn conditional_jumps_take_longer_when_taken() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .ld_a_u8(1)
+                .label("loop")
+                .dec_r8(R8::A)
+                .jr_cc_label(Condition::NZ, "loop")
+                .jr_cc_label(Condition::Z, "loop");
+            let mut machine = machine_running(code);
+            CPU::step_instruction(&mut machine);
+            CPU::step_instruction(&mut machine);
+            let not_taken = CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                executed(not_taken.activity),
+                Instruction::JR_cc_i8(Condition::NZ, _)
+            ));
+            assert_eq!(not_taken.t_cycles, 8);
+            let taken = CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                executed(taken.activity),
+                Instruction::JR_cc_i8(Condition::Z, _)
+            ));
+            assert_eq!(taken.t_cycles, 12);
+            assert_eq!(taken.registers.pc, Wrapping(0x0152));
+        });
+    }
+
+    #[test]
+    fn waits_are_reported_rather_than_waited_out() {
+        with_large_stack(|| {
+            // Interrupts are disabled, so nothing ever ends the HALT
+            let mut machine = machine_running(Assembler::new(0x0150).di().halt());
+            CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                executed(CPU::step_instruction(&mut machine).activity),
+                Instruction::HALT
+            ));
+            let halted = CPU::step_instruction(&mut machine);
+            assert!(matches!(halted.activity, StepActivity::Halted));
+            assert_eq!(halted.t_cycles, 4);
+
+            let mut machine =
+                machine_running(Assembler::new(0x0150).instruction(Instruction::Illegal(0xD3)));
+            let locked_up = CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                locked_up.activity,
+                StepActivity::LockedUp(0xD3, 0x0150)
+            ));
+
+            let mut machine =
+                machine_running(Assembler::new(0x0150).instruction(Instruction::STOP));
+            CPU::step_instruction(&mut machine);
+            let stopped = CPU::step_instruction(&mut machine);
+            assert!(matches!(stopped.activity, StepActivity::Stopped));
+        });
+    }
+
+    #[test]
+    fn interrupt_dispatch_is_a_step_of_its_own() {
+        with_large_stack(|| {
+            let mut machine = machine_running(Assembler::new(0x0150).nop());
+            machine.interrupts_mut().interrupt_master_enable = true;
+            machine.interrupts_mut().interrupt_enable = Wrapping(1);
+            machine.interrupts_mut().interrupt_flag = Wrapping(1);
+            let dispatch = CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                dispatch.activity,
+                StepActivity::InterruptDispatched
+            ));
+            // The handler's first instruction, a NOP in an all-zero ROM, ran along the dispatch
+            assert_eq!(dispatch.t_cycles, 24);
+            assert_eq!(dispatch.registers.pc, Wrapping(0x0041));
+            assert_eq!(dispatch.registers.sp, Wrapping(0xFFFC));
+        });
+    }
+
+    #[test]
+    fn armed_stop_switches_to_double_speed() {
+        with_large_stack(|| {
+            let arm = Assembler::new(0x0150).ld_a_u8(1).ldh_u8_a(0x4D);
+            let mut machine = cgb_machine_running(arm.clone().label("loop").jr_label("loop"));
+            CPU::step_instruction(&mut machine);
+            CPU::step_instruction(&mut machine);
+            assert_eq!(machine.read_u8(Wrapping(0xFF4D)), Wrapping(0x7F));
+            // Armed, but not switched yet
+            assert_eq!(system_counter_speed(&mut machine), 1);
+
+            let code = arm
+                .instruction(Instruction::STOP)
+                .label("loop")
+                .jr_label("loop");
+            let mut machine = cgb_machine_running(code);
+            for _ in 0..3 {
+                CPU::step_instruction(&mut machine);
+            }
+            assert_eq!(machine.read_u8(Wrapping(0xFF4D)), Wrapping(0xFE));
+            // Like any STOP, the switch resets DIV
+            assert_eq!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+            assert!(!machine.cpu().stopped);
+            // The timers now run twice as fast as the PPU
+            assert_eq!(system_counter_speed(&mut machine), 2);
+        });
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .ld_a_u8(1)
+                .inc_r8(R8::A)
+                .label("idle")
+                .jr_label("idle");
+            let mut machine = machine_running(code);
+            machine.cpu_mut().add_breakpoint(0x0152);
+            machine.step();
+            let step = machine.step();
+            assert_eq!(step.breakpoint_hit, Some(0x0152));
+            assert!(step.instruction_executed.is_none());
+            assert_eq!(machine.registers().pc, Wrapping(0x0152));
+            assert_eq!(machine.registers().read_r8(&R8::A), Wrapping(1));
+            // Stepping again executes the instruction
+            let step = machine.step();
+            assert_eq!(step.breakpoint_hit, None);
+            assert_eq!(machine.registers().read_r8(&R8::A), Wrapping(2));
+        });
+    }
+
+    #[test]
+    fn halt_with_a_masked_pending_interrupt_runs_the_next_byte_twice() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .di()
+                .ld_a_u8(0x04)
+                .ldh_u8_a(0xFF)
+                .ldh_u8_a(0x0F)
+                .ld_a_u8(0x00)
+                .halt()
+                .inc_r8(R8::A);
+            let idle = code.address();
+            let mut machine = machine_running(code.label("idle").jr_label("idle"));
+            step_until_pc(&mut machine, idle, 10);
+            assert!(!machine.cpu().low_power_mode);
+            assert_eq!(machine.registers().read_r8(&R8::A), Wrapping(2));
+        });
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150).ei().inc_r8(R8::B).inc_r8(R8::B);
+            let mut machine = machine_running(code);
+            machine.interrupts_mut().interrupt_enable = Wrapping(1);
+            CPU::step_instruction(&mut machine);
+            machine.interrupts_mut().interrupt_flag = Wrapping(1);
+            let b = machine.registers().read_r8(&R8::B);
+            assert!(matches!(
+                executed(CPU::step_instruction(&mut machine).activity),
+                Instruction::INC_r8(R8::B)
+            ));
+            assert!(matches!(
+                CPU::step_instruction(&mut machine).activity,
+                StepActivity::InterruptDispatched
+            ));
+            assert_eq!(machine.registers().read_r8(&R8::B), b + Wrapping(1));
+
+            // A DI right after EI cancels it
+            let mut machine = machine_running(Assembler::new(0x0150).ei().di().nop().nop());
+            machine.interrupts_mut().interrupt_enable = Wrapping(1);
+            machine.interrupts_mut().interrupt_flag = Wrapping(1);
+            for _ in 0..4 {
+                assert!(matches!(
+                    CPU::step_instruction(&mut machine).activity,
+                    StepActivity::Executed(_)
+                ));
+            }
+            assert!(!machine.interrupts().interrupt_master_enable);
+        });
+    }
+
+    #[test]
+    fn vblank_is_serviced_first() {
+        with_large_stack(|| {
+            let mut machine = machine_running(Assembler::new(0x0150).nop());
+            machine.interrupts_mut().interrupt_master_enable = true;
+            machine.interrupts_mut().interrupt_enable = Wrapping(0x05);
+            machine.interrupts_mut().interrupt_flag = Wrapping(0x05);
+            let dispatch = CPU::step_instruction(&mut machine);
+            assert!(matches!(
+                dispatch.activity,
+                StepActivity::InterruptDispatched
+            ));
+            // Past the NOP at the VBlank vector
+            assert_eq!(dispatch.registers.pc, Wrapping(0x0041));
+            assert_eq!(machine.interrupts().interrupt_flag.0 & 0x1F, 0x04);
+            assert!(!machine.interrupts().interrupt_master_enable);
+            assert_eq!(machine.read_u8(Wrapping(0xFFFC)), Wrapping(0x50));
+            assert_eq!(machine.read_u8(Wrapping(0xFFFD)), Wrapping(0x01));
+        });
+    }
+
+    #[test]
+    fn dump_state_formats_the_register_file() {
+        with_large_stack(|| {
+            let mut machine = machine_running(Assembler::new(0x0150).nop());
+            let registers = machine.registers_mut();
+            registers.af = Wrapping(0x01B0);
+            registers.bc = Wrapping(0x0013);
+            registers.de = Wrapping(0x00D8);
+            registers.hl = Wrapping(0x014D);
+            registers.sp = Wrapping(0xFFFE);
+            registers.pc = Wrapping(0x0100);
+            assert_eq!(
+                CPU::dump_state(&machine),
+                "A:01 F:Z-HC BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100 IME:0"
+            );
+
+            machine.registers_mut().af = Wrapping(0xFF40);
+            machine.interrupts_mut().interrupt_master_enable = true;
+            assert_eq!(
+                CPU::dump_state(&machine),
+                "A:FF F:-N-- BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100 IME:1"
+            );
+        });
+    }
+
+    #[test]
+    fn gb_doctor_line_shows_the_bytes_at_pc() {
+        with_large_stack(|| {
+            let rom = Assembler::new(0x0150).nop().rom();
+            let cartridge = Cartridge::from_header(&rom).unwrap();
+            let mut machine = Box::new(Machine::new_post_boot(rom, cartridge, true));
+            // The entry point is a NOP followed by a JP to the code
+            assert_eq!(
+                machine.gb_doctor_line(),
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01"
+            );
+            machine.step();
+            assert!(machine
+                .gb_doctor_line()
+                .ends_with("PC:0101 PCMEM:C3,50,01,00"));
+            // LY stays on the first VBlank line, however long the PPU runs
+            for _ in 0..1000 {
+                machine.step();
+                assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0x90));
+            }
+        });
+    }
+
+    #[test]
+    fn stop_resets_div_and_waits_for_a_button() {
+        with_large_stack(|| {
+            // Selects the action buttons, then stops
+            let code = Assembler::new(0x0150).ld_a_u8(0x10).ldh_u8_a(0x00);
+            let stop = code.address();
+            let code = code.instruction(Instruction::STOP).inc_r8(R8::B);
+            let idle = code.address();
+            let mut rom = code.label("idle").jr_label("idle").rom();
+            // The byte following STOP is skipped, even when it is not 0x00
+            rom[stop as usize + 1] = 0x04; // INC B
+            let mut machine = machine_with_rom(rom);
+            step_until_pc(&mut machine, stop, 10);
+            assert_ne!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+            machine.step();
+            assert!(machine.cpu().stopped);
+            assert_eq!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+            for _ in 0..1000 {
+                machine.step();
+            }
+            assert!(machine.cpu().stopped);
+            assert_eq!(machine.registers().pc, Wrapping(stop + 2));
+            // Nothing ticks while stopped
+            assert_eq!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+
+            machine.set_button(Button::Start, true);
+            step_until_pc(&mut machine, idle, 10);
+            assert!(!machine.cpu().stopped);
+            assert_eq!(machine.registers().read_r8(&R8::B), Wrapping(1));
+        });
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_up_and_are_reported() {
+        with_large_stack(|| {
+            for opcode in [
+                0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+            ] {
+                let code = Assembler::new(0x0150)
+                    .nop()
+                    .instruction(Instruction::Illegal(opcode));
+                let mut machine = machine_running(code);
+                machine.step();
+                let step = machine.step();
+                assert_eq!(step.illegal_opcode, Some((opcode, 0x0151)));
+                assert!(step.instruction_executed.is_none());
+                // The CPU stays stuck, while time goes on for the rest of the machine
+                let dot_count = machine.dot_count;
+                let step = machine.step();
+                assert_eq!(step.illegal_opcode, Some((opcode, 0x0151)));
+                assert!(machine.dot_count > dot_count);
+            }
+        });
+    }
 }
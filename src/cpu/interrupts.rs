@@ -1,8 +1,10 @@
 use std::num::Wrapping;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{instructions::type_def::Immediate16, machine::Machine};
 
-use super::CPU;
+use super::{StepResult, CPU};
 
 pub const VBLANK_INTERRUPT_BIT: u8 = 0;
 const VBLANK_INTERRUPT_ADDRESS: u16 = 0x40;
@@ -15,10 +17,9 @@ const SERIAL_INTERRUPT_ADDRESS: u16 = 0x58;
 pub const JOYPAD_INTERRUPT_BIT: u8 = 4;
 const JOYPAD_INTERRUPT_ADDRESS: u16 = 0x60;
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
 pub struct Interrupts {
     pub interrupt_master_enable: bool,
-    pub interrupt_master_enable_delayed: bool,
     pub interrupt_enable: Wrapping<u8>,
     pub interrupt_flag: Wrapping<u8>,
 }
@@ -34,31 +35,61 @@ fn interrupt_handler_offset(interrupt_bit: u8) -> Wrapping<u16> {
     })
 }
 
+impl Default for Interrupts {
+    fn default() -> Self {
+        Interrupts::new()
+    }
+}
+
 impl Interrupts {
     pub fn new() -> Self {
         Interrupts {
             interrupt_master_enable: false,
-            interrupt_master_enable_delayed: false,
             interrupt_enable: Wrapping(0),
             interrupt_flag: Wrapping(0),
         }
     }
 
     pub fn handle_interrupts(machine: &mut Machine) -> (u8, u8) {
+        if machine.cpu().locked_up {
+            return (0, 0);
+        }
         if let Some(interrupt) = machine.interrupts.should_handle_interrupt() {
             machine.interrupts.interrupt_flag =
                 machine.interrupts.interrupt_flag & Wrapping(!(1 << interrupt));
             machine.interrupts.interrupt_master_enable = false;
+            // A halted CPU wakes up to service the interrupt, which takes one more M-cycle
+            let was_halted = std::mem::take(&mut machine.cpu_mut().low_power_mode);
+            let wake_up_m_cycles = was_halted as u8;
             // Here the CPU:
             // - NOPs twice (2 M-cycles)
             // - PUSHes PC (2 M-cycles)
             // - sets PC to the handle (1 M-cycle)
-            // Currently simulating this whole thing at once, but might need granularity
-            CPU::push_imm16(machine, Immediate16::from_u16(machine.cpu().registers.pc));
+            // After `EI; HALT` with an interrupt pending, the HALT bug leaves PC on the byte after
+            // HALT un-incremented, so the handler returns to the HALT itself
+            machine.skip_cpu_m_cycles(wake_up_m_cycles + 2);
+            let mut return_address = machine.cpu().registers.pc;
+            if std::mem::take(&mut machine.cpu_mut().halt_bug) {
+                return_address -= 1;
+            }
+            CPU::push_imm16(machine, Immediate16::from_u16(return_address));
             machine.cpu_mut().registers.pc = interrupt_handler_offset(interrupt);
+            machine.skip_cpu_m_cycles(1);
             // Execute the first instruction of the interrupt handler to match GB doctor
-            let (_, (t_cycles, m_cycles)) = CPU::execute_one_instruction(machine);
-            (20 + t_cycles, 5 + m_cycles)
+            let (t_cycles, m_cycles) = match CPU::execute_one_instruction(machine) {
+                StepResult::Executed(_, cycles) | StepResult::Halted(cycles) => cycles,
+                // Left for the next step to report, so that execution stops at the handler
+                StepResult::BreakpointHit(_) => {
+                    machine.cpu_mut().breakpoint_hit = None;
+                    (0, 0)
+                }
+                // Likewise, the CPU stays locked up for the next step to report
+                StepResult::IllegalOpcode(_, _) => (0, 0),
+            };
+            (
+                20 + 4 * wake_up_m_cycles + t_cycles,
+                5 + wake_up_m_cycles + m_cycles,
+            )
         } else {
             (0, 0)
         }
@@ -74,7 +105,8 @@ impl Interrupts {
         self.interrupt_flag |= 1 << interrupt_bit;
     }
 
-    // Returns the bit index of the interrupt to handle (0 = VBlank... 4 = Joypad)
+    // Returns the bit index of the interrupt to handle (0 = VBlank... 4 = Joypad), only the
+    // highest-priority one gets serviced, the others stay pending in IF
     fn should_handle_interrupt(&self) -> Option<u8> {
         if !self.interrupt_master_enable {
             return None;
@@ -1,89 +1,136 @@
 use std::num::Wrapping;
 
-use crate::machine::Machine;
+use serde::{Deserialize, Serialize};
 
-use super::interrupts::{Interrupts, TIMER_INTERRUPT_BIT};
+use crate::{machine::Machine, memory_bus::MemoryBus};
+
+use super::interrupts::TIMER_INTERRUPT_BIT;
 
 const DIVIDE_REGISTER_ADDRESS: u16 = 0xFF04;
 const TIMER_COUNTER_ADDRESS: u16 = 0xFF05;
 const TIMER_MODULO_ADDRESS: u16 = 0xFF06;
 const TIMER_CONTROL_ADDRESS: u16 = 0xFF07;
 
-#[derive(Clone, Debug, Hash)]
+// TAC bit 2 enables the timer, bits 0-1 select its frequency
+const TIMER_ENABLE_BIT: u8 = 2;
+const TIMER_FREQUENCY_MASK: u8 = 0x3;
+// Bits 3-7 of TAC are unused and read as 1
+const TIMER_CONTROL_UNUSED_BITS: u8 = 0xF8;
+const TIMER_RELOAD_DELAY: u8 = 4;
+
+/// Progress of TIMA after it overflows, with the T-cycles left in the current step.
+#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+enum TimerReload {
+    Idle,
+    /// TIMA reads 0 for one M-cycle.  Writing TIMA meanwhile cancels the reload.
+    Delayed(u8),
+    /// TIMA was reloaded from TMA and the interrupt requested at the start of this M-cycle.
+    /// Writing TIMA meanwhile is ignored, and writing TMA also writes TIMA.
+    Reloading(u8),
+}
+
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
 pub struct Timers {
-    pub divide_register: Wrapping<u8>,
-    divide_register_dots: u16,
+    /// Internal counter, incremented every T-cycle.  DIV (0xFF04) is its upper byte, and TIMA is
+    /// clocked by the falling edges of one of its bits.
+    pub system_counter: Wrapping<u16>,
     // When we reset this, we must account for the fact that the reset would happen at the end of
     // the resetting instruction, rather than the beginning.  So we mark this to know to reset it
     // later.
     divide_register_to_be_reset: bool,
     pub timer_counter: Wrapping<u8>,
-    timer_counter_dots: u16,
+    timer_reload: TimerReload,
     pub timer_modulo: Wrapping<u8>,
     pub timer_control: Wrapping<u8>,
 }
 
+impl Default for Timers {
+    fn default() -> Self {
+        Timers::new()
+    }
+}
+
 impl Timers {
     pub fn new() -> Self {
         Timers {
-            divide_register: Wrapping(0),
+            system_counter: Wrapping(0),
             divide_register_to_be_reset: false,
-            divide_register_dots: 0,
             timer_counter: Wrapping(0),
-            timer_counter_dots: 0,
+            timer_reload: TimerReload::Idle,
             timer_modulo: Wrapping(0),
             timer_control: Wrapping(0),
         }
     }
 
-    fn get_timer_counter_threshold(&self) -> u16 {
-        match self.timer_control.0 & 0x3 {
-            0b00 => 1024,
-            0b01 => 16,
-            0b10 => 64,
-            0b11 => 256,
+    // The system counter bit clocking TIMA, which divides the T-cycle frequency by 1024, 16, 64, or
+    // 256 respectively
+    fn get_timer_counter_bit(&self) -> u8 {
+        match self.timer_control.0 & TIMER_FREQUENCY_MASK {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
             _ => unreachable!(),
         }
     }
 
-    pub fn tick(&mut self, interrupts: &mut Interrupts) {
-        // TODO: Reset this on STOP
-        // TODO: Freeze this while in STOP mode
-        self.divide_register_dots += 1;
-        if self.divide_register_dots == 256 {
-            self.divide_register_dots = 0;
-            self.divide_register += 1;
-        }
+    // The selected system counter bit, ANDed with the enable bit
+    fn timer_input(&self) -> bool {
+        self.timer_control.0 & (1 << TIMER_ENABLE_BIT) != 0
+            && (self.system_counter.0 >> self.get_timer_counter_bit()) & 1 != 0
+    }
 
-        if (self.timer_control.0 & 0b100) != 0 {
-            self.timer_counter_dots += 1;
-            if self.timer_counter_dots == self.get_timer_counter_threshold() {
-                self.timer_counter_dots = 0;
-                self.timer_counter += 1;
-                if self.timer_counter.0 == 0 {
-                    self.timer_counter = self.timer_modulo;
-                    interrupts.request(TIMER_INTERRUPT_BIT);
-                }
+    // TIMA is clocked by the falling edges of its input, whatever causes them: the system counter
+    // counting, but also DIV being reset or TAC being written while the input is high
+    fn update_timer_input(&mut self, previous_input: bool) {
+        if previous_input && !self.timer_input() {
+            self.timer_counter += 1;
+            if self.timer_counter.0 == 0 {
+                self.timer_reload = TimerReload::Delayed(TIMER_RELOAD_DELAY);
             }
         }
     }
 
-    pub fn ticks(&mut self, interrupts: &mut Interrupts, dots: u8) {
-        for _ in 0..dots {
-            self.tick(interrupts);
+    fn tick_timer_reload(&mut self, bus: &mut impl MemoryBus) {
+        self.timer_reload = match self.timer_reload {
+            TimerReload::Idle | TimerReload::Reloading(1) => TimerReload::Idle,
+            TimerReload::Delayed(1) => {
+                self.timer_counter = self.timer_modulo;
+                bus.request_interrupt(TIMER_INTERRUPT_BIT);
+                TimerReload::Reloading(TIMER_RELOAD_DELAY)
+            }
+            TimerReload::Delayed(dots) => TimerReload::Delayed(dots - 1),
+            TimerReload::Reloading(dots) => TimerReload::Reloading(dots - 1),
+        };
+    }
+
+    pub fn tick(&mut self, bus: &mut impl MemoryBus) {
+        // TODO: Freeze this while in STOP mode
+        self.tick_timer_reload(bus);
+        let previous_input = self.timer_input();
+        self.system_counter += 1;
+        self.update_timer_input(previous_input);
+    }
+
+    /// Advances the timers by `t_cycles` T-cycles, requesting the timer interrupt from `bus`.
+    pub fn ticks(&mut self, bus: &mut impl MemoryBus, t_cycles: u8) {
+        for _ in 0..t_cycles {
+            self.tick(bus);
         }
         if self.divide_register_to_be_reset {
             self.divide_register_to_be_reset = false;
-            self.divide_register = Wrapping(0);
+            let previous_input = self.timer_input();
+            self.system_counter = Wrapping(0);
+            self.update_timer_input(previous_input);
         }
     }
 
     pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
         match address.0 {
-            DIVIDE_REGISTER_ADDRESS => self.divide_register,
+            DIVIDE_REGISTER_ADDRESS => Wrapping((self.system_counter.0 >> 8) as u8),
             TIMER_COUNTER_ADDRESS => self.timer_counter,
             TIMER_MODULO_ADDRESS => self.timer_modulo,
-            TIMER_CONTROL_ADDRESS => self.timer_control,
+            TIMER_CONTROL_ADDRESS => self.timer_control | Wrapping(TIMER_CONTROL_UNUSED_BITS),
             _ => unreachable!(),
         }
     }
@@ -92,14 +139,32 @@ impl Timers {
         match address.0 {
             DIVIDE_REGISTER_ADDRESS => {
                 // Writing any value to this register resets it.  However, if we were to reset it
-                // here for a 4 t-cycle instruction, it would have started counting 4 by the time
-                // where it should actually be reset.  So instead we mark it to be reset after
-                // simulating the current instruction's t-cycles.
+                // here, at the start of the M-cycle of the write, it would have started counting 4
+                // by the time where it should actually be reset.  So instead we mark it to be reset
+                // after simulating the t-cycles left in the instruction, which are that M-cycle's.
                 self.divide_register_to_be_reset = true;
             }
-            TIMER_COUNTER_ADDRESS => self.timer_counter = value,
-            TIMER_MODULO_ADDRESS => self.timer_modulo = value,
-            TIMER_CONTROL_ADDRESS => self.timer_control = value,
+            TIMER_COUNTER_ADDRESS => match self.timer_reload {
+                TimerReload::Idle => self.timer_counter = value,
+                TimerReload::Delayed(_) => {
+                    self.timer_counter = value;
+                    self.timer_reload = TimerReload::Idle;
+                }
+                TimerReload::Reloading(_) => {}
+            },
+            TIMER_MODULO_ADDRESS => {
+                self.timer_modulo = value;
+                if let TimerReload::Reloading(_) = self.timer_reload {
+                    self.timer_counter = value;
+                }
+            }
+            // Both the frequency and the enable bits select the timer input, so changing either
+            // can cause a falling edge, e.g. switching to a bit that is low from one that is high
+            TIMER_CONTROL_ADDRESS => {
+                let previous_input = self.timer_input();
+                self.timer_control = value & Wrapping(!TIMER_CONTROL_UNUSED_BITS);
+                self.update_timer_input(previous_input);
+            }
             _ => unreachable!(),
         }
     }
@@ -113,3 +178,164 @@ impl Machine {
         &mut self.timers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{cpu::interrupts::TIMER_INTERRUPT_BIT, memory_bus::MemoryBus};
+
+    use super::{
+        Timers, DIVIDE_REGISTER_ADDRESS, TIMER_CONTROL_ADDRESS, TIMER_COUNTER_ADDRESS,
+        TIMER_MODULO_ADDRESS,
+    };
+
+    // Enabled, clocked by bit 3 of the system counter, i.e. every 16 T-cycles
+    const TAC_16_T_CYCLES: u8 = 0x05;
+
+    // The timers only ever request interrupts from their bus
+    struct MockBus {
+        requested_interrupts: Vec<u8>,
+    }
+
+    impl MockBus {
+        fn new() -> Self {
+            MockBus {
+                requested_interrupts: Vec::new(),
+            }
+        }
+    }
+
+    impl MemoryBus for MockBus {
+        fn read_u8(&self, _address: Wrapping<u16>) -> Wrapping<u8> {
+            Wrapping(0xFF)
+        }
+
+        fn write_u8(&mut self, _address: Wrapping<u16>, _value: Wrapping<u8>) {}
+
+        fn request_interrupt(&mut self, interrupt_bit: u8) {
+            self.requested_interrupts.push(interrupt_bit);
+        }
+    }
+
+    fn write(timers: &mut Timers, address: u16, value: u8) {
+        timers.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    fn read(timers: &Timers, address: u16) -> u8 {
+        timers.read_u8(Wrapping(address)).0
+    }
+
+    #[test]
+    fn overflow_reloads_and_requests_interrupt_from_bus() {
+        let mut bus = MockBus::new();
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, TAC_16_T_CYCLES);
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x42);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0xFE);
+        timers.ticks(&mut bus, 16);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0xFF);
+        timers.ticks(&mut bus, 16);
+        // TIMA reads 0 for one M-cycle before getting reloaded
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x00);
+        timers.ticks(&mut bus, 3);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x00);
+        assert!(bus.requested_interrupts.is_empty());
+        timers.ticks(&mut bus, 1);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x42);
+        assert_eq!(bus.requested_interrupts, vec![TIMER_INTERRUPT_BIT]);
+    }
+
+    // Runs a 4 T-cycle instruction writing DIV, with the system counter at `system_counter`
+    fn tima_after_div_write(system_counter: u16) -> u8 {
+        let mut bus = MockBus::new();
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, TAC_16_T_CYCLES);
+        timers.system_counter = Wrapping(system_counter);
+        write(&mut timers, DIVIDE_REGISTER_ADDRESS, 0x12);
+        timers.ticks(&mut bus, 4);
+        assert_eq!(read(&timers, DIVIDE_REGISTER_ADDRESS), 0);
+        read(&timers, TIMER_COUNTER_ADDRESS)
+    }
+
+    #[test]
+    fn div_write_clocks_tima_when_its_input_is_high() {
+        // Bit 3 is still high once the instruction is done, the reset makes it fall
+        assert_eq!(tima_after_div_write(0x0008), 1);
+        assert_eq!(tima_after_div_write(0xAB0B), 1);
+        // Bit 3 is low by then, resetting changes nothing
+        assert_eq!(tima_after_div_write(0x0000), 0);
+        assert_eq!(tima_after_div_write(0xAB10), 0);
+    }
+
+    // Timers that just overflowed, TIMA reading 0 for the next 4 T-cycles before the reload
+    fn overflowed_timers(bus: &mut MockBus) -> Timers {
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, TAC_16_T_CYCLES);
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x42);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0xFF);
+        timers.ticks(bus, 16);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x00);
+        timers
+    }
+
+    #[test]
+    fn tima_write_before_reload_cancels_it() {
+        let mut bus = MockBus::new();
+        let mut timers = overflowed_timers(&mut bus);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x10);
+        timers.ticks(&mut bus, 8);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x10);
+        assert!(bus.requested_interrupts.is_empty());
+    }
+
+    #[test]
+    fn tima_write_during_reload_is_dropped() {
+        let mut bus = MockBus::new();
+        let mut timers = overflowed_timers(&mut bus);
+        timers.ticks(&mut bus, 4);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x10);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x42);
+        // Once the reload M-cycle is over, writes apply again
+        timers.ticks(&mut bus, 4);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x10);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x10);
+        assert_eq!(bus.requested_interrupts, vec![TIMER_INTERRUPT_BIT]);
+    }
+
+    #[test]
+    fn tma_write_during_reload_applies_to_tima() {
+        let mut bus = MockBus::new();
+        let mut timers = overflowed_timers(&mut bus);
+        timers.ticks(&mut bus, 4);
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x99);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x99);
+        assert_eq!(read(&timers, TIMER_MODULO_ADDRESS), 0x99);
+        // Later on, TMA writes only affect the next reload
+        timers.ticks(&mut bus, 4);
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x55);
+        assert_eq!(read(&timers, TIMER_COUNTER_ADDRESS), 0x99);
+    }
+
+    // Writes TAC with the system counter at `system_counter`, starting from a 16 T-cycle timer
+    fn tima_after_tac_switch(system_counter: u16, timer_control: u8) -> u8 {
+        let mut timers = Timers::new();
+        timers.system_counter = Wrapping(system_counter);
+        write(&mut timers, TIMER_CONTROL_ADDRESS, TAC_16_T_CYCLES);
+        write(&mut timers, TIMER_CONTROL_ADDRESS, timer_control);
+        read(&timers, TIMER_COUNTER_ADDRESS)
+    }
+
+    #[test]
+    fn tac_switch_clocks_tima_when_its_input_falls() {
+        // Bit 3 is high, bit 5 is low: switching to the 64 T-cycle tap is a falling edge
+        assert_eq!(tima_after_tac_switch(0x0008, 0x06), 1);
+        // So is disabling the timer while its input is high
+        assert_eq!(tima_after_tac_switch(0x0008, 0x01), 1);
+        // Bit 3 is low, so switching to the high bit 5 is a rising edge
+        assert_eq!(tima_after_tac_switch(0x0020, 0x06), 0);
+        assert_eq!(tima_after_tac_switch(0x0020, 0x01), 0);
+        // Bits 3 and 5 both high, the input stays high
+        assert_eq!(tima_after_tac_switch(0x0028, 0x06), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::machine::Machine;
+
+const OAM_DMA_TRANSFER_LENGTH: u8 = 0xA0;
+// One byte gets transferred every M-cycle
+const DOTS_PER_TRANSFERRED_BYTE: u8 = 4;
+
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct DMA {
+    /// Last value written to 0xFF46, i.e. the high byte of the source address of the transfer.
+    pub source_high_byte: Wrapping<u8>,
+    /// Number of bytes already transferred, or `None` when there is no transfer in flight.
+    progress: Option<u8>,
+    progress_dots: u8,
+}
+
+impl Default for DMA {
+    fn default() -> Self {
+        DMA::new()
+    }
+}
+
+impl DMA {
+    pub fn new() -> Self {
+        DMA {
+            source_high_byte: Wrapping(0),
+            progress: None,
+            progress_dots: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    pub fn start(&mut self, source_high_byte: Wrapping<u8>) {
+        self.source_high_byte = source_high_byte;
+        // Starting a new transfer while one is in flight restarts from scratch
+        self.progress = Some(0);
+        self.progress_dots = 0;
+    }
+
+    pub fn tick(machine: &mut Machine) {
+        let Some(progress) = machine.dma().progress else {
+            return;
+        };
+        machine.dma_mut().progress_dots += 1;
+        if machine.dma().progress_dots < DOTS_PER_TRANSFERRED_BYTE {
+            return;
+        }
+        machine.dma_mut().progress_dots = 0;
+
+        let source_address = source_address(machine.dma().source_high_byte, progress);
+        // The DMA controller itself is not subject to the bus restrictions it puts on the CPU
+        let byte = machine.read_u8_unrestricted(source_address);
+        machine.ppu_mut().object_attribute_memory[progress as usize] = byte.0;
+
+        let progress = progress + 1;
+        machine.dma_mut().progress = if progress == OAM_DMA_TRANSFER_LENGTH {
+            None
+        } else {
+            Some(progress)
+        };
+    }
+
+    pub fn ticks(machine: &mut Machine, dots: u8) {
+        for _ in 0..dots {
+            DMA::tick(machine);
+        }
+    }
+}
+
+// Sources past 0xDF00 are not meant to be used, but the transfer still reads from WRAM, as if from
+// the echo RAM mirroring it at 0xE000-0xFDFF, which carries on over 0xFE00-0xFFFF
+fn source_address(source_high_byte: Wrapping<u8>, progress: u8) -> Wrapping<u16> {
+    let high_byte = if source_high_byte.0 >= 0xE0 {
+        source_high_byte.0 - 0x20
+    } else {
+        source_high_byte.0
+    };
+    Wrapping(((high_byte as u16) << 8) | progress as u16)
+}
+
+// While a transfer is in flight, the CPU can only access HRAM, plus the I/O registers and IE which
+// are not on the buses the transfer occupies, so that 0xFF46 can restart it.  Elsewhere, reads
+// return 0xFF and writes get dropped.
+pub fn is_accessible_during_dma(address: Wrapping<u16>) -> bool {
+    address.0 >= 0xFF00
+}
+
+impl Machine {
+    pub fn dma(&self) -> &DMA {
+        &self.dma
+    }
+
+    pub fn dma_mut(&mut self) -> &mut DMA {
+        &mut self.dma
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::test_utils::{idle, machine_running, with_large_stack};
+
+    use super::source_address;
+
+    #[test]
+    fn sources_past_wram_read_wram() {
+        assert_eq!(source_address(Wrapping(0xC1), 0x10), Wrapping(0xC110));
+        assert_eq!(source_address(Wrapping(0xE1), 0x10), Wrapping(0xC110));
+        assert_eq!(source_address(Wrapping(0xFE), 0x9F), Wrapping(0xDE9F));
+        assert_eq!(source_address(Wrapping(0xFF), 0x00), Wrapping(0xDF00));
+    }
+
+    #[test]
+    fn transfer_from_0xc000_fills_oam_in_160_m_cycles() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            for offset in 0..0xA0 {
+                machine.poke(0xC000 + offset, 0xA0 - offset as u8);
+            }
+            machine.write_u8(Wrapping(0xFF46), Wrapping(0xC0));
+            assert_eq!(
+                machine.read_u8_unrestricted(Wrapping(0xFF46)),
+                Wrapping(0xC0)
+            );
+            for _ in 0..0x9F {
+                machine.tick(4);
+            }
+            assert!(machine.dma().is_active());
+            machine.tick(4);
+            assert!(!machine.dma().is_active());
+            for offset in 0..0xA0 {
+                assert_eq!(
+                    machine.ppu().object_attribute_memory[offset],
+                    0xA0 - offset as u8
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn transfer_from_0xfe00_copies_wram() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            for offset in 0..0xA0 {
+                machine.poke(0xDE00 + offset, offset as u8 ^ 0x5A);
+            }
+            machine.write_u8(Wrapping(0xFF46), Wrapping(0xFE));
+            for _ in 0..0xA0 {
+                machine.tick(4);
+            }
+            assert!(!machine.dma().is_active());
+            for offset in 0..0xA0 {
+                assert_eq!(
+                    machine.ppu().object_attribute_memory[offset],
+                    offset as u8 ^ 0x5A
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn only_hram_and_io_registers_are_accessible_during_a_transfer() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            machine.poke(0xC100, 0x42);
+            machine.poke(0xFF90, 0x24);
+            machine.write_u8(Wrapping(0xFF46), Wrapping(0xC0));
+            machine.tick(4);
+            assert!(machine.dma().is_active());
+            assert_eq!(machine.read_u8(Wrapping(0xC100)), Wrapping(0xFF));
+            assert_eq!(machine.read_u8(Wrapping(0xFF90)), Wrapping(0x24));
+            machine.write_u8(Wrapping(0xC100), Wrapping(0x00));
+            machine.write_u8(Wrapping(0xFF91), Wrapping(0x99));
+            assert_eq!(machine.read_u8(Wrapping(0xFF91)), Wrapping(0x99));
+            // LY, which games poll while waiting for the transfer to end
+            let ly = machine.read_u8(Wrapping(0xFF44));
+            assert_eq!(ly, machine.ppu().read_ly());
+            assert_ne!(ly, Wrapping(0xFF));
+
+            for _ in 0..0xA0 {
+                machine.tick(4);
+            }
+            assert!(!machine.dma().is_active());
+            // The write to WRAM got dropped
+            assert_eq!(machine.read_u8(Wrapping(0xC100)), Wrapping(0x42));
+        });
+    }
+}
@@ -0,0 +1,214 @@
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::machine::Machine;
+
+// Transfers are made of blocks of 0x10 bytes, HBlank transfers copy one per HBlank
+const HDMA_BLOCK_LENGTH: u16 = 0x10;
+const HDMA_HBLANK_MODE_BIT: u8 = 0x80;
+// Destinations are within VRAM, the upper bits of 0xFF53 are ignored
+const HDMA_DESTINATION_MASK: u16 = 0x1FF0;
+
+/// CGB VRAM DMA, configured through 0xFF51-0xFF55: either a general transfer, all at once, or an
+/// HBlank transfer, one block at the start of each HBlank.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct HDMA {
+    pub source: Wrapping<u16>,
+    /// Offset in VRAM, i.e. relative to 0x8000.
+    pub destination: Wrapping<u16>,
+    /// Blocks left to transfer, minus one, as reported in the low bits of 0xFF55.
+    remaining_blocks: u8,
+    is_hblank_transfer_active: bool,
+}
+
+impl Default for HDMA {
+    fn default() -> Self {
+        HDMA::new()
+    }
+}
+
+impl HDMA {
+    pub fn new() -> Self {
+        HDMA {
+            source: Wrapping(0),
+            destination: Wrapping(0),
+            remaining_blocks: 0x7F,
+            is_hblank_transfer_active: false,
+        }
+    }
+
+    pub fn is_hblank_transfer_active(&self) -> bool {
+        self.is_hblank_transfer_active
+    }
+
+    pub fn write_source_high(&mut self, value: Wrapping<u8>) {
+        self.source = Wrapping((self.source.0 & 0x00FF) | ((value.0 as u16) << 8));
+    }
+
+    // The low 4 bits are ignored, transfers are aligned on blocks
+    pub fn write_source_low(&mut self, value: Wrapping<u8>) {
+        self.source = Wrapping((self.source.0 & 0xFF00) | (value.0 & 0xF0) as u16);
+    }
+
+    pub fn write_destination_high(&mut self, value: Wrapping<u8>) {
+        let destination = (self.destination.0 & 0x00FF) | ((value.0 as u16) << 8);
+        self.destination = Wrapping(destination & HDMA_DESTINATION_MASK);
+    }
+
+    pub fn write_destination_low(&mut self, value: Wrapping<u8>) {
+        let destination = (self.destination.0 & 0xFF00) | value.0 as u16;
+        self.destination = Wrapping(destination & HDMA_DESTINATION_MASK);
+    }
+
+    // Bit 7 is clear while an HBlank transfer is active.  Once a transfer is done, the remaining
+    // block count has wrapped to 0x7F, so this reads 0xFF.
+    pub fn read_hdma5(&self) -> Wrapping<u8> {
+        let inactive_bit = if self.is_hblank_transfer_active {
+            0
+        } else {
+            HDMA_HBLANK_MODE_BIT
+        };
+        Wrapping(inactive_bit | self.remaining_blocks)
+    }
+
+    // Copies the next block, returns whether the transfer is over
+    fn transfer_block(machine: &mut Machine) -> bool {
+        let HDMA {
+            source,
+            destination,
+            ..
+        } = *machine.hdma();
+        for offset in 0..HDMA_BLOCK_LENGTH {
+            let offset = Wrapping(offset);
+            let byte = machine.read_u8_unrestricted(source + offset);
+            machine.ppu_mut().write_vram(destination + offset, byte);
+        }
+        let hdma = machine.hdma_mut();
+        hdma.source += HDMA_BLOCK_LENGTH;
+        hdma.destination =
+            (hdma.destination + Wrapping(HDMA_BLOCK_LENGTH)) & Wrapping(HDMA_DESTINATION_MASK);
+        hdma.remaining_blocks = hdma.remaining_blocks.wrapping_sub(1) & 0x7F;
+        hdma.remaining_blocks == 0x7F
+    }
+
+    // Writing with bit 7 clear while an HBlank transfer is active cancels it, leaving the
+    // remaining block count readable.  Otherwise, this starts a transfer of `(value & 0x7F) + 1`
+    // blocks.
+    pub fn write_hdma5(machine: &mut Machine, value: Wrapping<u8>) {
+        let is_hblank_transfer = value.0 & HDMA_HBLANK_MODE_BIT != 0;
+        if machine.hdma().is_hblank_transfer_active && !is_hblank_transfer {
+            machine.hdma_mut().is_hblank_transfer_active = false;
+            return;
+        }
+        machine.hdma_mut().remaining_blocks = value.0 & !HDMA_HBLANK_MODE_BIT;
+        if is_hblank_transfer {
+            machine.hdma_mut().is_hblank_transfer_active = true;
+            // Started during HBlank, or with the LCD off, the first block goes right away
+            if machine.ppu().mode() == 0 {
+                HDMA::hblank(machine);
+            }
+        } else {
+            // The CPU is stalled during a general transfer, which is not accounted for here
+            while !HDMA::transfer_block(machine) {}
+        }
+    }
+
+    /// Called upon entering HBlank, transfers a block if an HBlank transfer is active.
+    pub fn hblank(machine: &mut Machine) {
+        if machine.hdma().is_hblank_transfer_active && HDMA::transfer_block(machine) {
+            machine.hdma_mut().is_hblank_transfer_active = false;
+        }
+    }
+}
+
+impl Machine {
+    pub fn hdma(&self) -> &HDMA {
+        &self.hdma
+    }
+
+    pub fn hdma_mut(&mut self) -> &mut HDMA {
+        &mut self.hdma
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        test_utils::{cgb_machine_running, idle, with_large_stack},
+    };
+
+    // A CGB machine with 0x80 bytes of data at 0xC000, and the transfer registers set to copy them
+    // to VRAM offset `destination`
+    fn machine_ready_to_copy(destination: u16) -> Box<Machine> {
+        let mut machine = cgb_machine_running(idle());
+        for offset in 0..0x80 {
+            machine.write_u8(Wrapping(0xC000 + offset), Wrapping(offset as u8 + 1));
+        }
+        for (address, value) in [
+            (0xFF51, 0xC0),
+            (0xFF52, 0x00),
+            (0xFF53, (destination >> 8) as u8),
+            (0xFF54, destination as u8),
+        ] {
+            machine.write_u8(Wrapping(address), Wrapping(value));
+        }
+        machine
+    }
+
+    fn vram(machine: &Machine, from: usize, length: usize) -> Vec<u8> {
+        machine.ppu().vram[from..from + length].to_vec()
+    }
+
+    fn tick_until_mode(machine: &mut Machine, mode: u8) {
+        while machine.ppu().mode() != mode {
+            machine.tick(1);
+        }
+    }
+
+    #[test]
+    fn general_transfer_copies_every_block_at_once() {
+        with_large_stack(|| {
+            let mut machine = machine_ready_to_copy(0x0010);
+            machine.write_u8(Wrapping(0xFF55), Wrapping(0x03));
+            let expected: Vec<u8> = (1..=0x40).collect();
+            assert_eq!(vram(&machine, 0x0010, 0x40), expected);
+            assert_eq!(vram(&machine, 0x0050, 0x10), [0; 0x10]);
+            assert_eq!(machine.read_u8(Wrapping(0xFF55)), Wrapping(0xFF));
+        });
+    }
+
+    #[test]
+    fn hblank_transfer_copies_a_block_per_hblank() {
+        with_large_stack(|| {
+            let mut machine = machine_ready_to_copy(0x0800);
+            tick_until_mode(&mut machine, 3);
+            machine.write_u8(Wrapping(0xFF55), Wrapping(0x82));
+            assert_eq!(machine.read_u8(Wrapping(0xFF55)), Wrapping(0x02));
+            assert_eq!(vram(&machine, 0x0800, 0x10), [0; 0x10]);
+            for (blocks, hdma5) in [(1, 0x01), (2, 0x00), (3, 0xFF)] {
+                tick_until_mode(&mut machine, 0);
+                let expected: Vec<u8> = (1..=0x10 * blocks).collect();
+                assert_eq!(vram(&machine, 0x0800, 0x10 * blocks as usize), expected);
+                assert_eq!(machine.read_u8(Wrapping(0xFF55)), Wrapping(hdma5));
+                tick_until_mode(&mut machine, 3);
+            }
+            tick_until_mode(&mut machine, 0);
+            assert_eq!(vram(&machine, 0x0830, 0x10), [0; 0x10]);
+
+            // Cancelling keeps the remaining block count readable
+            let mut machine = machine_ready_to_copy(0x0800);
+            tick_until_mode(&mut machine, 3);
+            machine.write_u8(Wrapping(0xFF55), Wrapping(0x83));
+            tick_until_mode(&mut machine, 0);
+            machine.write_u8(Wrapping(0xFF55), Wrapping(0x00));
+            assert_eq!(machine.read_u8(Wrapping(0xFF55)), Wrapping(0x82));
+            tick_until_mode(&mut machine, 3);
+            tick_until_mode(&mut machine, 0);
+            assert_eq!(vram(&machine, 0x0810, 0x10), [0; 0x10]);
+        });
+    }
+}
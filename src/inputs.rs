@@ -1,23 +1,324 @@
-use std::num::Wrapping;
+use std::{collections::BTreeMap, num::Wrapping};
 
-#[derive(Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cpu::interrupts::{Interrupts, JOYPAD_INTERRUPT_BIT},
+    machine::Machine,
+    movie::Movie,
+};
+
+const SELECT_DIRECTIONS_BIT: u8 = 4;
+const SELECT_ACTIONS_BIT: u8 = 5;
+const SELECT_BITS: u8 = (1 << SELECT_DIRECTIONS_BIT) | (1 << SELECT_ACTIONS_BIT);
+// Bits 6-7 are unused and read as 1
+const UNUSED_BITS: u8 = 0xC0;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Bit in `pressed_buttons`: directions in the lower nibble, actions in the upper one, each in
+    // the order of their input line
+    fn mask(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// Which host key presses which button.  Keys are named after the `KeyboardEvent.key` values of
+/// the web, e.g. "ArrowUp", "Enter" or "z", and each button is bound to at most one key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    buttons: BTreeMap<String, Button>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::new()
+    }
+}
+
+impl KeyMap {
+    /// Arrow keys for the directions, Z for A, X for B, Enter for Start and Shift for Select.
+    pub fn new() -> Self {
+        let mut key_map = KeyMap::empty();
+        key_map.bind("ArrowRight", Button::Right);
+        key_map.bind("ArrowLeft", Button::Left);
+        key_map.bind("ArrowUp", Button::Up);
+        key_map.bind("ArrowDown", Button::Down);
+        key_map.bind("z", Button::A);
+        key_map.bind("x", Button::B);
+        key_map.bind("Shift", Button::Select);
+        key_map.bind("Enter", Button::Start);
+        key_map
+    }
+
+    pub fn empty() -> Self {
+        KeyMap {
+            buttons: BTreeMap::new(),
+        }
+    }
+
+    /// Binds `key` to `button`, replacing the key previously bound to `button`, if any.
+    pub fn bind(&mut self, key: &str, button: Button) {
+        self.buttons.retain(|_, bound| *bound != button);
+        self.buttons.insert(key.to_string(), button);
+    }
+
+    pub fn button(&self, key: &str) -> Option<Button> {
+        self.buttons.get(key).copied()
+    }
+
+    pub fn key(&self, button: Button) -> Option<&str> {
+        self.buttons
+            .iter()
+            .find(|(_, bound)| **bound == button)
+            .map(|(key, _)| key.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Inputs {
+    /// P1 (0xFF00) as written, only the row select bits are kept.
     pub inputs_register: Wrapping<u8>,
+    /// One bit per `Button`, set while pressed.
+    pressed_buttons: u8,
+    /// Host preference rather than machine state: not part of save states, the key map of the
+    /// running machine is kept when loading one.
+    #[serde(skip, default = "KeyMap::new")]
+    key_map: KeyMap,
+}
+
+impl Default for Inputs {
+    fn default() -> Self {
+        Inputs::new()
+    }
 }
 
 impl Inputs {
     pub fn new() -> Self {
         Inputs {
             inputs_register: Wrapping(0),
+            pressed_buttons: 0,
+            key_map: KeyMap::new(),
+        }
+    }
+
+    pub fn key_map(&self) -> &KeyMap {
+        &self.key_map
+    }
+
+    pub fn set_mapping(&mut self, key_map: KeyMap) {
+        self.key_map = key_map;
+    }
+
+    pub fn take_key_map_from(&mut self, other: &mut Inputs) {
+        self.key_map = std::mem::replace(&mut other.key_map, KeyMap::empty());
+    }
+
+    // State of the four input lines, which are pulled low (0) by pressed buttons of the selected
+    // rows (a row is selected when its bit is 0), and read as 1 when no row is selected
+    fn input_lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.inputs_register.0 & (1 << SELECT_DIRECTIONS_BIT) == 0 {
+            pressed |= self.pressed_buttons & 0x0F;
+        }
+        if self.inputs_register.0 & (1 << SELECT_ACTIONS_BIT) == 0 {
+            pressed |= self.pressed_buttons >> 4;
+        }
+        !pressed & 0x0F
+    }
+
+    /// Whether a pressed button pulls an input line low, which is what wakes the CPU from STOP.
+    pub fn is_any_input_line_low(&self) -> bool {
+        self.input_lines() != 0x0F
+    }
+
+    // The joypad interrupt is requested whenever an input line goes from high to low
+    fn update_input_lines(&self, previous_lines: u8, interrupts: &mut Interrupts) {
+        if previous_lines & !self.input_lines() != 0 {
+            interrupts.request(JOYPAD_INTERRUPT_BIT);
         }
     }
 
     pub fn read(&self) -> Wrapping<u8> {
-        self.inputs_register
+        Wrapping(UNUSED_BITS | (self.inputs_register.0 & SELECT_BITS) | self.input_lines())
     }
 
-    pub fn write(&mut self, value: Wrapping<u8>) {
+    // Selecting a row with buttons held down also pulls the lines low
+    pub fn write(&mut self, value: Wrapping<u8>, interrupts: &mut Interrupts) {
+        let previous_lines = self.input_lines();
         // Lower nibble is read-only
-        self.inputs_register = Wrapping((value.0 & 0xF0) | (self.inputs_register.0 & 0x0F));
+        self.inputs_register = Wrapping(value.0 & SELECT_BITS);
+        self.update_input_lines(previous_lines, interrupts);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool, interrupts: &mut Interrupts) {
+        let previous_lines = self.input_lines();
+        if pressed {
+            self.pressed_buttons |= button.mask();
+        } else {
+            self.pressed_buttons &= !button.mask();
+        }
+        self.update_input_lines(previous_lines, interrupts);
+    }
+
+    /// Presses or releases the button bound to `key`.  Returns false if `key` is not bound.
+    pub fn set_key(&mut self, key: &str, pressed: bool, interrupts: &mut Interrupts) -> bool {
+        match self.key_map.button(key) {
+            Some(button) => {
+                self.set_button(button, pressed, interrupts);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Bitmask of the pressed buttons, bit n being set while the n-th `Button` is pressed.
+    pub fn pressed_buttons(&self) -> u8 {
+        self.pressed_buttons
+    }
+
+    pub fn set_pressed_buttons(&mut self, buttons: u8, interrupts: &mut Interrupts) {
+        let previous_lines = self.input_lines();
+        self.pressed_buttons = buttons;
+        self.update_input_lines(previous_lines, interrupts);
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed_buttons & button.mask() != 0
+    }
+}
+
+impl Machine {
+    pub fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    pub fn inputs_mut(&mut self) -> &mut Inputs {
+        &mut self.inputs
+    }
+
+    /// Presses or releases `button`.  While a movie is recorded, this only takes effect upon
+    /// entering VBlank, and while one is played, not at all, see `Machine::update_movie`.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.set_host_buttons(|buttons| {
+            if pressed {
+                buttons | button.mask()
+            } else {
+                buttons & !button.mask()
+            }
+        });
+    }
+
+    /// Sets the state of all buttons at once, with the same restrictions as `set_button`.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.set_host_buttons(|_| buttons);
+    }
+
+    /// Presses or releases the button bound to `key`, with the same restrictions as
+    /// `set_button`.  Returns false if `key` is not bound.
+    pub fn set_key(&mut self, key: &str, pressed: bool) -> bool {
+        match self.inputs().key_map().button(key) {
+            Some(button) => {
+                self.set_button(button, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Host input goes through the movie, so that replaying it gets the exact same input
+    fn set_host_buttons(&mut self, update: impl FnOnce(u8) -> u8) {
+        match self.movie_mut() {
+            Movie::Idle => {
+                let buttons = update(self.inputs().pressed_buttons());
+                self.inputs
+                    .set_pressed_buttons(buttons, &mut self.interrupts);
+            }
+            Movie::Recording {
+                latched_buttons, ..
+            } => *latched_buttons = update(*latched_buttons),
+            Movie::Playing { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::cpu::interrupts::Interrupts;
+
+    use super::{Button, Inputs, KeyMap};
+
+    fn joypad_requested(interrupts: &Interrupts) -> bool {
+        interrupts.interrupt_flag.0 & 0x10 != 0
+    }
+
+    #[test]
+    fn pressing_a_selected_button_requests_the_joypad_interrupt() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        interrupts.interrupt_flag = Wrapping(0);
+        // Only the directions are selected
+        inputs.write(Wrapping(0x20), &mut interrupts);
+        inputs.set_button(Button::A, true, &mut interrupts);
+        assert!(!joypad_requested(&interrupts));
+        inputs.set_button(Button::Right, true, &mut interrupts);
+        assert!(joypad_requested(&interrupts));
+        assert_eq!(inputs.read().0 & 0x0F, 0x0E);
+
+        // Releasing does not request it
+        interrupts.interrupt_flag = Wrapping(0);
+        inputs.set_button(Button::Right, false, &mut interrupts);
+        assert!(!joypad_requested(&interrupts));
+        // Selecting the row of a held button does
+        inputs.write(Wrapping(0x10), &mut interrupts);
+        assert!(joypad_requested(&interrupts));
+    }
+
+    #[test]
+    fn selected_rows_read_their_pressed_buttons_as_0() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.write(Wrapping(0x30), &mut interrupts);
+        inputs.set_button(Button::Down, true, &mut interrupts);
+        assert_eq!(inputs.read(), Wrapping(0xFF));
+        // Down is the fourth line of the directions row
+        inputs.write(Wrapping(0x20), &mut interrupts);
+        assert_eq!(inputs.read(), Wrapping(0xE7));
+        inputs.write(Wrapping(0x10), &mut interrupts);
+        assert_eq!(inputs.read(), Wrapping(0xDF));
+        inputs.set_button(Button::Start, true, &mut interrupts);
+        assert_eq!(inputs.read(), Wrapping(0xD7));
+    }
+
+    #[test]
+    fn remapped_keys_press_their_new_button() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        let mut key_map = KeyMap::new();
+        key_map.bind("a", Button::A);
+        inputs.set_mapping(key_map);
+        // The previous key of A is unbound
+        assert!(!inputs.set_key("z", true, &mut interrupts));
+        assert_eq!(inputs.pressed_buttons(), 0);
+        assert!(inputs.set_key("a", true, &mut interrupts));
+        assert_eq!(inputs.pressed_buttons(), 1 << 4);
+        // A is the first line of the actions row
+        inputs.write(Wrapping(0x10), &mut interrupts);
+        assert_eq!(inputs.read(), Wrapping(0xDE));
+        assert!(inputs.set_key("a", false, &mut interrupts));
+        assert_eq!(inputs.read(), Wrapping(0xDF));
     }
 }
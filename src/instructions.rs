@@ -1,4 +1,6 @@
+pub mod assembler;
 pub mod decode;
 mod display;
+mod encode;
 mod semantics;
 pub mod type_def;
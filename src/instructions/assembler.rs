@@ -0,0 +1,314 @@
+use std::{collections::HashMap, num::Wrapping};
+
+use crate::{
+    conditions::Condition,
+    registers::{R16, R8},
+};
+
+use super::type_def::{Immediate16, Instruction};
+
+// Where the boot ROM hands over to the game
+const ENTRY_POINT: u16 = 0x0100;
+// Room for the entry point, up to the end of the cartridge header
+const HEADER_END: u16 = 0x0150;
+// Two banks, the size of a ROM-only cartridge, which is what an all-zero header announces
+const ROM_SIZE: usize = 0x8000;
+
+#[derive(Clone, Debug)]
+enum LabelUse {
+    /// The 16-bit operand of a JP or CALL, at this offset in the code.
+    Absolute(usize),
+    /// The signed 8-bit operand of a JR, at this offset in the code.
+    Relative(usize),
+}
+
+/// Builds code instruction by instruction, for test ROMs.  Jumps can target labels defined either
+/// before or after them, which get resolved by `assemble`.
+///
+/// Each method consumes and returns the assembler, so that programs read as a chain of calls:
+/// `Assembler::new(0x0150).ld_a_u8(0x42).label("loop").jr_label("loop")`.
+#[derive(Clone, Debug)]
+pub struct Assembler {
+    /// Address the code gets loaded at.
+    origin: u16,
+    code: Vec<u8>,
+    labels: HashMap<String, u16>,
+    label_uses: Vec<(LabelUse, String)>,
+}
+
+impl Assembler {
+    pub fn new(origin: u16) -> Self {
+        Assembler {
+            origin,
+            code: Vec::new(),
+            labels: HashMap::new(),
+            label_uses: Vec::new(),
+        }
+    }
+
+    /// Address the code gets loaded at.
+    pub fn origin(&self) -> u16 {
+        self.origin
+    }
+
+    /// Address of the next instruction.
+    pub fn address(&self) -> u16 {
+        self.origin + self.code.len() as u16
+    }
+
+    pub fn instruction(mut self, instruction: Instruction) -> Self {
+        self.code.extend(instruction.encode());
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        let address = self.address();
+        if self.labels.insert(label.to_string(), address).is_some() {
+            panic!("Label {} defined twice", label);
+        }
+        self
+    }
+
+    // Emits the instruction with a placeholder operand, patched by `assemble`
+    fn instruction_to_label(mut self, instruction: Instruction, label: &str) -> Self {
+        let operand_offset = self.code.len() + 1;
+        let label_use = match instruction {
+            Instruction::JR_i8(_) | Instruction::JR_cc_i8(_, _) => {
+                LabelUse::Relative(operand_offset)
+            }
+            _ => LabelUse::Absolute(operand_offset),
+        };
+        self.label_uses.push((label_use, label.to_string()));
+        self.instruction(instruction)
+    }
+
+    pub fn nop(self) -> Self {
+        self.instruction(Instruction::NOP)
+    }
+
+    pub fn halt(self) -> Self {
+        self.instruction(Instruction::HALT)
+    }
+
+    pub fn di(self) -> Self {
+        self.instruction(Instruction::DI)
+    }
+
+    pub fn ei(self) -> Self {
+        self.instruction(Instruction::EI)
+    }
+
+    pub fn ld_a_u8(self, value: u8) -> Self {
+        self.ld_r8_u8(R8::A, value)
+    }
+
+    pub fn ld_r8_u8(self, r8: R8, value: u8) -> Self {
+        self.instruction(Instruction::LD_r8_u8(r8, Wrapping(value)))
+    }
+
+    pub fn ld_r8_r8(self, destination: R8, source: R8) -> Self {
+        self.instruction(Instruction::LD_r8_r8(destination, source))
+    }
+
+    pub fn ld_r16_u16(self, r16: R16, value: u16) -> Self {
+        self.instruction(Instruction::LD_r16_d16(
+            r16,
+            Immediate16::from_u16(Wrapping(value)),
+        ))
+    }
+
+    pub fn ld_mu16_a(self, address: u16) -> Self {
+        self.instruction(Instruction::LD_mu16_A(Immediate16::from_u16(Wrapping(
+            address,
+        ))))
+    }
+
+    pub fn ld_a_mu16(self, address: u16) -> Self {
+        self.instruction(Instruction::LD_A_mu16(Immediate16::from_u16(Wrapping(
+            address,
+        ))))
+    }
+
+    /// LDH [0xFF00 + offset], A
+    pub fn ldh_u8_a(self, offset: u8) -> Self {
+        self.instruction(Instruction::LD_FFu8_A(Wrapping(offset)))
+    }
+
+    /// LDH A, [0xFF00 + offset]
+    pub fn ldh_a_u8(self, offset: u8) -> Self {
+        self.instruction(Instruction::LD_A_FFu8(Wrapping(offset)))
+    }
+
+    pub fn inc_r8(self, r8: R8) -> Self {
+        self.instruction(Instruction::INC_r8(r8))
+    }
+
+    pub fn dec_r8(self, r8: R8) -> Self {
+        self.instruction(Instruction::DEC_r8(r8))
+    }
+
+    pub fn inc_r16(self, r16: R16) -> Self {
+        self.instruction(Instruction::INC_r16(r16))
+    }
+
+    pub fn dec_r16(self, r16: R16) -> Self {
+        self.instruction(Instruction::DEC_r16(r16))
+    }
+
+    pub fn add_a_r8(self, r8: R8) -> Self {
+        self.instruction(Instruction::ADD_A_r8(r8))
+    }
+
+    pub fn add_a_u8(self, value: u8) -> Self {
+        self.instruction(Instruction::ADD_A_u8(Wrapping(value)))
+    }
+
+    pub fn sub_a_u8(self, value: u8) -> Self {
+        self.instruction(Instruction::SUB_A_u8(Wrapping(value)))
+    }
+
+    pub fn cp_a_u8(self, value: u8) -> Self {
+        self.instruction(Instruction::CP_A_u8(Wrapping(value)))
+    }
+
+    pub fn jp(self, address: u16) -> Self {
+        self.instruction(Instruction::JP_u16(Immediate16::from_u16(Wrapping(
+            address,
+        ))))
+    }
+
+    pub fn jp_label(self, label: &str) -> Self {
+        self.instruction_to_label(
+            Instruction::JP_u16(Immediate16::from_u16(Wrapping(0))),
+            label,
+        )
+    }
+
+    pub fn jp_cc_label(self, cc: Condition, label: &str) -> Self {
+        self.instruction_to_label(
+            Instruction::JP_cc_u16(cc, Immediate16::from_u16(Wrapping(0))),
+            label,
+        )
+    }
+
+    pub fn jr_label(self, label: &str) -> Self {
+        self.instruction_to_label(Instruction::JR_i8(Wrapping(0)), label)
+    }
+
+    pub fn jr_cc_label(self, cc: Condition, label: &str) -> Self {
+        self.instruction_to_label(Instruction::JR_cc_i8(cc, Wrapping(0)), label)
+    }
+
+    pub fn call_label(self, label: &str) -> Self {
+        self.instruction_to_label(
+            Instruction::CALL_a16(Immediate16::from_u16(Wrapping(0))),
+            label,
+        )
+    }
+
+    pub fn ret(self) -> Self {
+        self.instruction(Instruction::RET)
+    }
+
+    /// The code, with label references resolved.  Panics on undefined labels, and on relative jumps
+    /// out of range.
+    pub fn assemble(self) -> Vec<u8> {
+        let mut code = self.code;
+        for (label_use, label) in &self.label_uses {
+            let Some(&target) = self.labels.get(label) else {
+                panic!("Undefined label {}", label);
+            };
+            match *label_use {
+                LabelUse::Absolute(offset) => {
+                    code[offset..offset + 2].copy_from_slice(&target.to_le_bytes());
+                }
+                // Relative to the address following the JR, its operand being its last byte
+                LabelUse::Relative(offset) => {
+                    let next_address = self.origin as i32 + offset as i32 + 1;
+                    let displacement = target as i32 - next_address;
+                    let Ok(displacement) = i8::try_from(displacement) else {
+                        panic!(
+                            "Label {} is out of JR range ({} bytes)",
+                            label, displacement
+                        );
+                    };
+                    code[offset] = displacement as u8;
+                }
+            }
+        }
+        code
+    }
+
+    /// A 32 KiB ROM-only image with the code at its origin, which must be past the cartridge
+    /// header.  The entry point jumps to the origin.  There is no Nintendo logo nor header
+    /// checksum, so the image only runs without a boot ROM, e.g. on `Machine::new_post_boot`.
+    pub fn rom(self) -> Vec<u8> {
+        if self.origin < HEADER_END {
+            panic!(
+                "Code at 0x{:04X} would overwrite the cartridge header",
+                self.origin
+            );
+        }
+        let origin = self.origin as usize;
+        let code = self.assemble();
+        if origin + code.len() > ROM_SIZE {
+            panic!("Code does not fit in a 32 KiB ROM");
+        }
+        let mut rom = vec![0; ROM_SIZE];
+        let entry_point = Assembler::new(ENTRY_POINT)
+            .nop()
+            .jp(origin as u16)
+            .assemble();
+        rom[ENTRY_POINT as usize..ENTRY_POINT as usize + entry_point.len()]
+            .copy_from_slice(&entry_point);
+        rom[origin..origin + code.len()].copy_from_slice(&code);
+        rom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        conditions::Condition,
+        registers::R8,
+        test_utils::{machine_running, step_until_pc, with_large_stack},
+    };
+
+    use super::Assembler;
+
+    #[test]
+    fn assembled_loop_runs() {
+        with_large_stack(|| {
+            // Sums 1 to 10 into A, stored to WRAM once done
+            let code = Assembler::new(0x0150)
+                .ld_a_u8(0)
+                .ld_r8_u8(R8::B, 10)
+                .label("loop")
+                .add_a_r8(R8::B)
+                .dec_r8(R8::B)
+                .jr_cc_label(Condition::NZ, "loop")
+                .ld_mu16_a(0xC000);
+            let done = code.address();
+            let code = code.label("done").jr_label("done");
+            let mut machine = machine_running(code);
+            step_until_pc(&mut machine, done, 100);
+            assert_eq!(machine.registers().read_r8(&R8::A), Wrapping(55));
+            assert_eq!(machine.registers().read_r8(&R8::B), Wrapping(0));
+            assert_eq!(machine.read_u8(Wrapping(0xC000)), Wrapping(55));
+        });
+    }
+
+    #[test]
+    fn labels_resolve_forward_and_backward() {
+        let code = Assembler::new(0x0150)
+            .label("back")
+            .jr_label("forward")
+            .nop()
+            .label("forward")
+            .jp_label("back")
+            .assemble();
+        assert_eq!(code, vec![0x18, 0x01, 0x00, 0xC3, 0x50, 0x01]);
+    }
+}
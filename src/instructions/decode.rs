@@ -3,6 +3,7 @@ use std::{fmt, num::Wrapping};
 use crate::{
     conditions::Condition,
     machine::Machine,
+    memory_bus::MemoryBus,
     registers::{R16, R8},
 };
 
@@ -18,7 +19,7 @@ pub struct DecodedInstruction {
 
 impl fmt::Display for DecodedInstruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.as_string())
+        write!(f, "{}", self.disassemble())
     }
 }
 
@@ -32,25 +33,59 @@ impl DecodedInstruction {
     }
 }
 
+impl Machine {
+    // Returns the instruction at `address`, its length in bytes, and its assembly text, with jump
+    // and call targets labelled from the loaded symbols
+    pub fn disassemble(&self, address: Wrapping<u16>) -> (Instruction, usize, String) {
+        let decoded = decode_instruction_at_address(self, address);
+        let text = decoded.disassemble_with_labels(|target| self.label(target));
+        (decoded.instruction, decoded.instruction_size as usize, text)
+    }
+}
+
 pub fn decode_instruction_at_address(
-    machine: &Machine,
+    bus: &impl MemoryBus,
+    address: Wrapping<u16>,
+) -> DecodedInstruction {
+    decode_instruction(bus, address, false)
+}
+
+// After the HALT bug, PC fails to increment past the opcode, so the opcode byte is read again as
+// the first operand byte
+pub fn decode_instruction_after_halt_bug(
+    bus: &impl MemoryBus,
     address: Wrapping<u16>,
 ) -> DecodedInstruction {
+    decode_instruction(bus, address, true)
+}
+
+fn decode_instruction(
+    bus: &impl MemoryBus,
+    address: Wrapping<u16>,
+    halt_bug: bool,
+) -> DecodedInstruction {
+    let byte_address = |o: u16| {
+        if halt_bug && o > 0 {
+            address + Wrapping(o - 1)
+        } else {
+            address + Wrapping(o)
+        }
+    };
     let mut bytes_read: u16 = 0;
     let next_i8 = |bytes_read: &mut u16| {
         let o = *bytes_read;
         *bytes_read += 1;
-        Wrapping(machine.read_u8(address + Wrapping(o)).0 as i8)
+        Wrapping(bus.read_u8(byte_address(o)).0 as i8)
     };
     let next_u8 = |bytes_read: &mut u16| {
         let o = *bytes_read;
         *bytes_read += 1;
-        machine.read_u8(address + Wrapping(o))
+        bus.read_u8(byte_address(o))
     };
     let next_imm16 = |bytes_read: &mut u16| {
         let o = *bytes_read;
         *bytes_read += 2;
-        Immediate16::from_memory(machine, address + Wrapping(o))
+        Immediate16::from_memory(bus, byte_address(o))
     };
     let i = match next_u8(&mut bytes_read).0 {
         0x00 => Instruction::NOP,
@@ -70,7 +105,11 @@ pub fn decode_instruction_at_address(
         0x0E => Instruction::LD_r8_u8(R8::C, next_u8(&mut bytes_read)),
         0x0F => Instruction::RRCA,
 
-        0x10 => Instruction::STOP,
+        0x10 => {
+            // STOP is followed by a byte that it skips, normally 0x00
+            next_u8(&mut bytes_read);
+            Instruction::STOP
+        }
         0x11 => Instruction::LD_r16_d16(R16::DE, next_imm16(&mut bytes_read)),
         0x12 => Instruction::LD_mr16_r8(R16::DE, R8::A),
         0x13 => Instruction::INC_r16(R16::DE),
@@ -601,6 +640,8 @@ pub fn decode_instruction_at_address(
         address: address,
         instruction: i,
         instruction_size: bytes_read as u8,
-        raw: machine.read_range(address, bytes_read as usize).into(),
+        raw: (0..bytes_read)
+            .map(|o| bus.read_u8(byte_address(o)))
+            .collect(),
     }
 }
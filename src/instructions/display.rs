@@ -1,7 +1,129 @@
-use std::num::Wrapping;
+use std::{fmt, num::Wrapping};
 
 use super::{decode::DecodedInstruction, type_def::Instruction};
 
+// Signed offsets are displayed as `+$05` or `-$05`
+fn signed_offset(i8: &Wrapping<i8>) -> String {
+    let sign = if i8.0 < 0 { '-' } else { '+' };
+    format!("{}${:02X}", sign, i8.0.unsigned_abs())
+}
+
+/// Canonical assembly syntax.  Relative jumps show their offset, since the target depends on where
+/// the instruction lives: see `DecodedInstruction::disassemble` for the resolved version.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::ADC_A_mHL => write!(f, "ADC A, (HL)"),
+            Instruction::ADC_A_r8(r8) => write!(f, "ADC A, {}", r8),
+            Instruction::ADC_A_u8(u8) => write!(f, "ADC A, ${:02X}", u8),
+            Instruction::ADD_A_mHL => write!(f, "ADD A, (HL)"),
+            Instruction::ADD_A_r8(r8) => write!(f, "ADD A, {}", r8),
+            Instruction::ADD_A_u8(u8) => write!(f, "ADD A, ${:02X}", u8),
+            Instruction::ADD_HL_r16(r16) => write!(f, "ADD HL, {}", r16),
+            Instruction::ADD_SP_i8(i8) => write!(f, "ADD SP, {}", signed_offset(i8)),
+            Instruction::AND_A_mHL => write!(f, "AND A, (HL)"),
+            Instruction::AND_A_r8(r8) => write!(f, "AND A, {}", r8),
+            Instruction::AND_u8(u8) => write!(f, "AND A, ${:02X}", u8),
+            Instruction::BIT_u3_mHL(bit) => write!(f, "BIT {}, (HL)", bit),
+            Instruction::BIT_u3_r8(bit, r8) => write!(f, "BIT {}, {}", bit, r8),
+            Instruction::CALL_a16(imm16) => write!(f, "CALL ${:04X}", imm16.as_u16()),
+            Instruction::CALL_cc_u16(cc, imm16) => {
+                write!(f, "CALL {}, ${:04X}", cc, imm16.as_u16())
+            }
+            Instruction::CCF => write!(f, "CCF"),
+            Instruction::CP_A_mHL => write!(f, "CP A, (HL)"),
+            Instruction::CP_A_r8(r8) => write!(f, "CP A, {}", r8),
+            Instruction::CP_A_u8(u8) => write!(f, "CP A, ${:02X}", u8),
+            Instruction::CPL => write!(f, "CPL"),
+            Instruction::DAA => write!(f, "DAA"),
+            Instruction::DEC_mHL => write!(f, "DEC (HL)"),
+            Instruction::DEC_r16(r16) => write!(f, "DEC {}", r16),
+            Instruction::DEC_r8(r8) => write!(f, "DEC {}", r8),
+            Instruction::DI => write!(f, "DI"),
+            Instruction::EI => write!(f, "EI"),
+            Instruction::HALT => write!(f, "HALT"),
+            Instruction::Illegal(opcode) => write!(f, "ILLEGAL ${:02X}", opcode),
+            Instruction::INC_mHL => write!(f, "INC (HL)"),
+            Instruction::INC_r16(r16) => write!(f, "INC {}", r16),
+            Instruction::INC_r8(r8) => write!(f, "INC {}", r8),
+            Instruction::JP_cc_u16(cc, imm16) => write!(f, "JP {}, ${:04X}", cc, imm16.as_u16()),
+            Instruction::JP_HL => write!(f, "JP HL"),
+            Instruction::JP_u16(imm16) => write!(f, "JP ${:04X}", imm16.as_u16()),
+            Instruction::JR_cc_i8(cc, i8) => write!(f, "JR {}, {}", cc, signed_offset(i8)),
+            Instruction::JR_i8(i8) => write!(f, "JR {}", signed_offset(i8)),
+            Instruction::JR_r8(r8) => write!(f, "JR {}", r8),
+            Instruction::LD_A_FFC => write!(f, "LD A, ($FF00+C)"),
+            Instruction::LD_A_FFu8(u8) => write!(f, "LD A, ($FF{:02X})", u8),
+            Instruction::LD_A_mHLdec => write!(f, "LD A, (HL-)"),
+            Instruction::LD_A_mHLinc => write!(f, "LD A, (HL+)"),
+            Instruction::LD_A_mr16(r16) => write!(f, "LD A, ({})", r16),
+            Instruction::LD_A_mu16(imm16) => write!(f, "LD A, (${:04X})", imm16.as_u16()),
+            Instruction::LD_FFC_A => write!(f, "LD ($FF00+C), A"),
+            Instruction::LD_FFu8_A(u8) => write!(f, "LD ($FF{:02X}), A", u8),
+            Instruction::LD_H_mHL => write!(f, "LD H, (HL)"),
+            Instruction::LD_HL_SP_i8(i8) => write!(f, "LD HL, SP{}", signed_offset(i8)),
+            Instruction::LD_L_mHL => write!(f, "LD L, (HL)"),
+            Instruction::LD_mHL_u8(u8) => write!(f, "LD (HL), ${:02X}", u8),
+            Instruction::LD_mHLdec_A => write!(f, "LD (HL-), A"),
+            Instruction::LD_mHLinc_A => write!(f, "LD (HL+), A"),
+            Instruction::LD_mr16_r8(r16, r8) => write!(f, "LD ({}), {}", r16, r8),
+            Instruction::LD_mu16_A(imm16) => write!(f, "LD (${:04X}), A", imm16.as_u16()),
+            Instruction::LD_mu16_SP(imm16) => write!(f, "LD (${:04X}), SP", imm16.as_u16()),
+            Instruction::LD_r16_d16(r16, imm16) => write!(f, "LD {}, ${:04X}", r16, imm16.as_u16()),
+            Instruction::LD_r8_mr16(r8, r16) => write!(f, "LD {}, ({})", r8, r16),
+            Instruction::LD_r8_r8(r8a, r8b) => write!(f, "LD {}, {}", r8a, r8b),
+            Instruction::LD_r8_u8(r8, u8) => write!(f, "LD {}, ${:02X}", r8, u8),
+            Instruction::LD_SP_HL => write!(f, "LD SP, HL"),
+            Instruction::LD_SP_u16(imm16) => write!(f, "LD SP, ${:04X}", imm16.as_u16()),
+            Instruction::NOP => write!(f, "NOP"),
+            Instruction::OR_A_mHL => write!(f, "OR A, (HL)"),
+            Instruction::OR_A_r8(r8) => write!(f, "OR A, {}", r8),
+            Instruction::OR_A_u8(u8) => write!(f, "OR A, ${:02X}", u8),
+            Instruction::POP_r16(r16) => write!(f, "POP {}", r16),
+            Instruction::PUSH_r16(r16) => write!(f, "PUSH {}", r16),
+            Instruction::RES_u3_mHL(bit) => write!(f, "RES {}, (HL)", bit),
+            Instruction::RES_u3_r8(bit, r8) => write!(f, "RES {}, {}", bit, r8),
+            Instruction::RET => write!(f, "RET"),
+            Instruction::RET_cc(cc) => write!(f, "RET {}", cc),
+            Instruction::RETI => write!(f, "RETI"),
+            Instruction::RL_mHL => write!(f, "RL (HL)"),
+            Instruction::RL_r8(r8) => write!(f, "RL {}", r8),
+            Instruction::RLA => write!(f, "RLA"),
+            Instruction::RLC_mHL => write!(f, "RLC (HL)"),
+            Instruction::RLC_r8(r8) => write!(f, "RLC {}", r8),
+            Instruction::RLCA => write!(f, "RLCA"),
+            Instruction::RR_mHL => write!(f, "RR (HL)"),
+            Instruction::RR_r8(r8) => write!(f, "RR {}", r8),
+            Instruction::RRA => write!(f, "RRA"),
+            Instruction::RRC_mHL => write!(f, "RRC (HL)"),
+            Instruction::RRC_r8(r8) => write!(f, "RRC {}", r8),
+            Instruction::RRCA => write!(f, "RRCA"),
+            Instruction::RST(imm16) => write!(f, "RST ${:02X}", imm16.as_u16()),
+            Instruction::SBC_A_mHL => write!(f, "SBC A, (HL)"),
+            Instruction::SBC_A_r8(r8) => write!(f, "SBC A, {}", r8),
+            Instruction::SBC_A_u8(u8) => write!(f, "SBC A, ${:02X}", u8),
+            Instruction::SCF => write!(f, "SCF"),
+            Instruction::SET_u3_mHL(bit) => write!(f, "SET {}, (HL)", bit),
+            Instruction::SET_u3_r8(bit, r8) => write!(f, "SET {}, {}", bit, r8),
+            Instruction::SLA_mHL => write!(f, "SLA (HL)"),
+            Instruction::SLA_r8(r8) => write!(f, "SLA {}", r8),
+            Instruction::SRA_mHL => write!(f, "SRA (HL)"),
+            Instruction::SRA_r8(r8) => write!(f, "SRA {}", r8),
+            Instruction::SRL_mHL => write!(f, "SRL (HL)"),
+            Instruction::SRL_r8(r8) => write!(f, "SRL {}", r8),
+            Instruction::STOP => write!(f, "STOP"),
+            Instruction::SUB_A_mHL => write!(f, "SUB A, (HL)"),
+            Instruction::SUB_A_r8(r8) => write!(f, "SUB A, {}", r8),
+            Instruction::SUB_A_u8(u8) => write!(f, "SUB A, ${:02X}", u8),
+            Instruction::SWAP_mHL => write!(f, "SWAP (HL)"),
+            Instruction::SWAP_r8(r8) => write!(f, "SWAP {}", r8),
+            Instruction::XOR_A_mHL => write!(f, "XOR A, (HL)"),
+            Instruction::XOR_A_r8(r8) => write!(f, "XOR A, {}", r8),
+            Instruction::XOR_A_u8(u8) => write!(f, "XOR A, ${:02X}", u8),
+        }
+    }
+}
+
 impl DecodedInstruction {
     fn resolve_relative(&self, i8: Wrapping<i8>) -> u16 {
         (self.address + Wrapping(self.instruction_size as u16))
@@ -9,125 +131,68 @@ impl DecodedInstruction {
             .wrapping_add_signed(i8.0 as i16)
     }
 
-    pub fn as_string(&self) -> String {
+    // Same as the `Instruction` display, except relative jumps show their target address
+    pub fn disassemble(&self) -> String {
+        self.disassemble_with_labels(|_| None)
+    }
+
+    // Same as `disassemble`, except jump and call targets show their label, when `label` has one
+    pub fn disassemble_with_labels<'a>(&self, label: impl Fn(u16) -> Option<&'a str>) -> String {
+        let target = |address: u16| match label(address) {
+            Some(label) => label.to_string(),
+            None => format!("${:04X}", address),
+        };
         match &self.instruction {
-            Instruction::ADC_A_mHL => String::from("ADC A [HL]"),
-            Instruction::ADC_A_r8(r8) => format!("ADC A, {}", r8),
-            Instruction::ADC_A_u8(u8) => format!("ADC A, 0x{:02X}", u8),
-            Instruction::ADD_A_mHL => String::from("ADD A [HL]"),
-            Instruction::ADD_A_r8(r8) => format!("ADD A, {}", r8),
-            Instruction::ADD_A_u8(u8) => format!("ADD A, 0x{:02X}", u8),
-            Instruction::ADD_HL_r16(r16) => format!("ADD HL, {}", r16),
-            Instruction::ADD_SP_i8(i8) => format!("ADD SP, 0x{:02X}", i8),
-            Instruction::AND_A_mHL => String::from("AND A, [HL]"),
-            Instruction::AND_A_r8(r8) => format!("AND A, {}", r8),
-            Instruction::AND_u8(u8) => format!("AND A, 0x{:02X}", u8),
-            Instruction::BIT_u3_mHL(bit) => format!("BIT {}, [HL]", bit),
-            Instruction::BIT_u3_r8(bit, reg) => format!("BIT {}, {}", bit, reg),
-            Instruction::CALL_a16(imm16) => format!("CALL 0x{:04X}", imm16.as_u16()),
-            Instruction::CALL_cc_u16(cc, imm16) => format!("CALL {}, 0x{:04X}", cc, imm16.as_u16()),
-            Instruction::CCF => String::from("CCF"),
-            Instruction::CP_A_mHL => String::from("CP A, [HL]"),
-            Instruction::CP_A_r8(r8) => format!("CP A, {}", r8),
-            Instruction::CP_A_u8(u8) => format!("CP A, 0x{:02X} (= {})", u8, u8),
-            Instruction::CPL => String::from("CPL"),
-            Instruction::DAA => String::from("DAA"),
-            Instruction::DEC_mHL => String::from("DEC [HL]"),
-            Instruction::DEC_r16(r16) => format!("DEC {}", r16),
-            Instruction::DEC_r8(r8) => format!("DEC {}", r8),
-            Instruction::DI => String::from("DI"),
-            Instruction::EI => String::from("EI"),
-            Instruction::HALT => String::from("HALT"),
-            Instruction::Illegal(opcode) => format!("ILLEGAL 0x{:02X}", opcode),
-            Instruction::INC_mHL => String::from("INC [HL]"),
-            Instruction::INC_r16(r16) => format!("INC {}", r16),
-            Instruction::INC_r8(r8) => format!("INC {}", r8),
-            Instruction::JP_cc_u16(cc, imm16) => format!("JP {}, 0x{:04X}", cc, imm16.as_u16()),
-            Instruction::JP_HL => String::from("JP HL"),
-            Instruction::JP_u16(imm16) => format!("JP 0x{:04X}", imm16.as_u16()),
-            Instruction::JR_cc_i8(cc, i8) => {
-                format!("JR {}, {} (=0x{:04X})", cc, i8, self.resolve_relative(*i8))
-            }
-            Instruction::JR_i8(i8) => format!("JR 0x{:02X}", i8),
-            Instruction::JR_r8(r8) => format!("JP {}", r8),
-            Instruction::LD_FFu8_A(u8) => format!(
-                "LD [0x{:04X}], A",
-                Wrapping(0xFF00) + Wrapping((*u8).0 as u16)
-            ),
-            Instruction::LD_A_FFu8(u8) => format!(
-                "LD A, [0x{:04X}]",
-                Wrapping(0xFF00) + Wrapping((*u8).0 as u16)
-            ),
-            Instruction::LD_A_FFC => String::from("LD A, [0xFF00 + C]"),
-            Instruction::LD_r16_d16(r16, imm16) => {
-                format!("LD {} 0x{:04X} (= {})", r16, imm16.as_u16(), imm16.as_u16())
+            Instruction::CALL_a16(imm16) => format!("CALL {}", target(imm16.as_u16().0)),
+            Instruction::CALL_cc_u16(cc, imm16) => {
+                format!("CALL {}, {}", cc, target(imm16.as_u16().0))
             }
-            Instruction::LD_SP_u16(imm) => {
-                format!("LD SP 0x{:04X} (= {})", imm.as_u16(), imm.as_u16())
+            Instruction::JP_cc_u16(cc, imm16) => format!("JP {}, {}", cc, target(imm16.as_u16().0)),
+            Instruction::JP_u16(imm16) => format!("JP {}", target(imm16.as_u16().0)),
+            Instruction::JR_cc_i8(cc, i8) => {
+                format!("JR {}, {}", cc, target(self.resolve_relative(*i8)))
             }
-            Instruction::LD_A_mr16(r16) => format!("LD A [{}]", r16),
-            Instruction::LD_A_mHLdec => String::from("LD A [HL-]"),
-            Instruction::LD_A_mHLinc => String::from("LD A [HL+]"),
-            Instruction::LD_A_mu16(imm16) => format!("LD A, [0x{:04X}]", imm16.as_u16()),
-            Instruction::LD_FFC_A => String::from("LD [0xFF00 + C], A"),
-            Instruction::LD_H_mHL => String::from("LD H, [HL]"),
-            Instruction::LD_HL_SP_i8(i8) => format!("LD HL, SP+{:02X}", i8),
-            Instruction::LD_L_mHL => String::from("LD L, [HL]"),
-            Instruction::LD_mHL_u8(u8) => format!("LD [HL], 0x{:02X}", u8),
-            Instruction::LD_mHLdec_A => String::from("LD [HL-], A"),
-            Instruction::LD_mHLinc_A => String::from("LD [HL+], A"),
-            Instruction::LD_mr16_r8(mr16, r8) => format!("LD [{}] {}", mr16, r8),
-            Instruction::LD_mu16_A(imm16) => format!("LD [0x{:04X}], A", imm16.as_u16()),
-            Instruction::LD_mu16_SP(imm16) => format!("LD [0x{:04X}] SP", imm16.as_u16()),
-            Instruction::LD_r8_mr16(r8, mr16) => format!("LD {} [{}]", r8, mr16),
-            Instruction::LD_r8_r8(r8a, r8b) => format!("LD {}, {}", r8a, r8b),
-            Instruction::LD_r8_u8(r8, u8) => format!("LD {}, 0x{:02X} (= {})", r8, u8, u8),
-            Instruction::LD_SP_HL => String::from("LD SP, HL"),
-            Instruction::NOP => String::from("NOP"),
-            Instruction::OR_A_mHL => String::from("OR A, [HL]"),
-            Instruction::OR_A_r8(r8) => format!("OR A, {}", r8),
-            Instruction::OR_A_u8(u8) => format!("OR A, 0x{:02X}", u8),
-            Instruction::POP_r16(r16) => format!("POP {}", r16),
-            Instruction::PUSH_r16(r16) => format!("PUSH {}", r16),
-            Instruction::RES_u3_mHL(u8) => format!("RES {}, [HL]", u8),
-            Instruction::RES_u3_r8(u8, r8) => format!("RES {}, {}", u8, r8),
-            Instruction::RET => String::from("RET"),
-            Instruction::RET_cc(cc) => format!("RET {}", cc),
-            Instruction::RETI => String::from("RETI"),
-            Instruction::RL_mHL => String::from("RL [HL]"),
-            Instruction::RL_r8(r8) => format!("RL {}", r8),
-            Instruction::RLA => String::from("RLA"),
-            Instruction::RLCA => String::from("RLCA"),
-            Instruction::RLC_mHL => String::from("RLC [HL]"),
-            Instruction::RLC_r8(r8) => format!("RLC {}", r8),
-            Instruction::RR_mHL => String::from("RRL [HL]"),
-            Instruction::RR_r8(r8) => format!("RRL {}", r8),
-            Instruction::RRA => String::from("RRA"),
-            Instruction::RRCA => String::from("RRCA"),
-            Instruction::RRC_mHL => String::from("RRC [HL]"),
-            Instruction::RRC_r8(r8) => format!("RRC {}", r8),
-            Instruction::RST(imm16) => format!("RST 0x{:04X}", imm16.as_u16()),
-            Instruction::SBC_A_mHL => String::from("SBC A, [HL]"),
-            Instruction::SBC_A_r8(r8) => format!("SBC A, {}", r8),
-            Instruction::SBC_A_u8(u8) => format!("SBC A, 0x{:02X}", u8),
-            Instruction::SCF => String::from("SCF"),
-            Instruction::SET_u3_mHL(u8) => format!("SET {}, [HL]", u8),
-            Instruction::SET_u3_r8(u8, r8) => format!("SET {}, {}", u8, r8),
-            Instruction::SLA_mHL => String::from("SLA [HL]"),
-            Instruction::SLA_r8(r8) => format!("SLA {}", r8),
-            Instruction::SRA_mHL => String::from("SRA [HL]"),
-            Instruction::SRA_r8(r8) => format!("SRA {}", r8),
-            Instruction::SRL_mHL => String::from("SRL [HL]"),
-            Instruction::SRL_r8(r8) => format!("SRL {}", r8),
-            Instruction::STOP => String::from("STOP"),
-            Instruction::SUB_A_mHL => String::from("SUB A, [HL]"),
-            Instruction::SUB_A_r8(r8) => format!("SUB A, {}", r8),
-            Instruction::SUB_A_u8(u8) => format!("SUB A, 0x{:02X}", u8),
-            Instruction::SWAP_mHL => String::from("SWAP [HL]"),
-            Instruction::SWAP_r8(r8) => format!("SWAP {}", r8),
-            Instruction::XOR_A_mHL => String::from("XOR A, [HL]"),
-            Instruction::XOR_A_r8(r8) => format!("XOR A, {}", r8),
-            Instruction::XOR_A_u8(u8) => format!("XOR A, 0x{:02X}", u8),
+            Instruction::JR_i8(i8) => format!("JR {}", target(self.resolve_relative(*i8))),
+            instruction => instruction.to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        instructions::assembler::Assembler,
+        test_utils::{machine_with_rom, with_large_stack},
+    };
+
+    #[test]
+    fn disassembles_each_instruction_category() {
+        with_large_stack(|| {
+            let cases: [(&[u8], &str, &str); 12] = [
+                (&[0x00], "NOP", "NOP"),
+                (&[0x2A], "LD A, (HL+)", "LD A, (HL+)"),
+                (&[0x3E, 0x42], "LD A, $42", "LD A, $42"),
+                (&[0xFA, 0x34, 0x12], "LD A, ($1234)", "LD A, ($1234)"),
+                (&[0xE0, 0x44], "LD ($FF44), A", "LD ($FF44), A"),
+                (&[0x86], "ADD A, (HL)", "ADD A, (HL)"),
+                (&[0xE8, 0xFE], "ADD SP, -$02", "ADD SP, -$02"),
+                (&[0x20, 0xFE], "JR NZ, -$02", "JR NZ, $0000"),
+                (&[0xC3, 0x50, 0x01], "JP $0150", "JP $0150"),
+                (&[0xD7], "RST $10", "RST $10"),
+                (&[0xCB, 0x58], "BIT 3, B", "BIT 3, B"),
+                (&[0xCB, 0x37], "SWAP A", "SWAP A"),
+            ];
+            for (bytes, display, disassembly) in cases {
+                let mut rom = Assembler::new(0x0150).rom();
+                rom[..bytes.len()].copy_from_slice(bytes);
+                let machine = machine_with_rom(rom);
+                let (instruction, size, text) = machine.disassemble(Wrapping(0x0000));
+                assert_eq!(instruction.to_string(), display);
+                assert_eq!(size, bytes.len(), "{}", display);
+                assert_eq!(text, disassembly);
+            }
+        });
+    }
+}
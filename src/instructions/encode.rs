@@ -0,0 +1,239 @@
+use crate::{
+    conditions::Condition,
+    registers::{R16, R8},
+};
+
+use super::type_def::{Immediate16, Instruction};
+
+const CB_PREFIX: u8 = 0xCB;
+
+// Index of an 8-bit register in the opcode tables, 6 being [HL]
+fn r8_index(r8: &R8) -> u8 {
+    match r8 {
+        R8::B => 0,
+        R8::C => 1,
+        R8::D => 2,
+        R8::E => 3,
+        R8::H => 4,
+        R8::L => 5,
+        R8::A => 7,
+        R8::F => panic!("F cannot be an instruction operand"),
+    }
+}
+
+// Index of a 16-bit register for the LD/INC/DEC/ADD opcodes
+fn r16_index(r16: &R16) -> u8 {
+    match r16 {
+        R16::BC => 0,
+        R16::DE => 1,
+        R16::HL => 2,
+        R16::SP => 3,
+        R16::AF | R16::PC => panic!("{} cannot be an instruction operand", r16),
+    }
+}
+
+// Index of a 16-bit register for the PUSH/POP opcodes, where AF takes the place of SP
+fn r16_stack_index(r16: &R16) -> u8 {
+    match r16 {
+        R16::BC => 0,
+        R16::DE => 1,
+        R16::HL => 2,
+        R16::AF => 3,
+        R16::SP | R16::PC => panic!("{} cannot be pushed or popped", r16),
+    }
+}
+
+fn condition_index(cc: &Condition) -> u8 {
+    match cc {
+        Condition::NZ => 0,
+        Condition::Z => 1,
+        Condition::NC => 2,
+        Condition::C => 3,
+    }
+}
+
+// In ROM, immediate 16-bit values are stored lower-byte-first.
+fn with_imm16(opcode: u8, imm16: &Immediate16) -> Vec<u8> {
+    vec![opcode, imm16.lower_byte.0, imm16.higher_byte.0]
+}
+
+impl Instruction {
+    pub fn length(&self) -> usize {
+        self.encode().len()
+    }
+
+    // Inverse of `decode_instruction_at_address`.  Variants that the decoder never produces are
+    // encoded as their equivalent opcode (e.g. `LD_H_mHL` as `LD_r8_mr16(H, HL)`).
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Instruction::ADC_A_mHL => vec![0x8E],
+            Instruction::ADC_A_r8(r8) => vec![0x88 | r8_index(r8)],
+            Instruction::ADC_A_u8(u8) => vec![0xCE, u8.0],
+            Instruction::ADD_A_mHL => vec![0x86],
+            Instruction::ADD_A_r8(r8) => vec![0x80 | r8_index(r8)],
+            Instruction::ADD_A_u8(u8) => vec![0xC6, u8.0],
+            Instruction::ADD_HL_r16(r16) => vec![0x09 | (r16_index(r16) << 4)],
+            Instruction::ADD_SP_i8(i8) => vec![0xE8, i8.0 as u8],
+            Instruction::AND_A_mHL => vec![0xA6],
+            Instruction::AND_A_r8(r8) => vec![0xA0 | r8_index(r8)],
+            Instruction::AND_u8(u8) => vec![0xE6, u8.0],
+            Instruction::BIT_u3_mHL(bit) => vec![CB_PREFIX, 0x46 | (bit << 3)],
+            Instruction::BIT_u3_r8(bit, r8) => vec![CB_PREFIX, 0x40 | (bit << 3) | r8_index(r8)],
+            Instruction::CALL_a16(imm16) => with_imm16(0xCD, imm16),
+            Instruction::CALL_cc_u16(cc, imm16) => {
+                with_imm16(0xC4 | (condition_index(cc) << 3), imm16)
+            }
+            Instruction::CCF => vec![0x3F],
+            Instruction::CP_A_mHL => vec![0xBE],
+            Instruction::CP_A_r8(r8) => vec![0xB8 | r8_index(r8)],
+            Instruction::CP_A_u8(u8) => vec![0xFE, u8.0],
+            Instruction::CPL => vec![0x2F],
+            Instruction::DAA => vec![0x27],
+            Instruction::DEC_mHL => vec![0x35],
+            Instruction::DEC_r16(r16) => vec![0x0B | (r16_index(r16) << 4)],
+            Instruction::DEC_r8(r8) => vec![0x05 | (r8_index(r8) << 3)],
+            Instruction::DI => vec![0xF3],
+            Instruction::EI => vec![0xFB],
+            Instruction::HALT => vec![0x76],
+            Instruction::Illegal(opcode) => vec![*opcode],
+            Instruction::INC_mHL => vec![0x34],
+            Instruction::INC_r16(r16) => vec![0x03 | (r16_index(r16) << 4)],
+            Instruction::INC_r8(r8) => vec![0x04 | (r8_index(r8) << 3)],
+            Instruction::JP_cc_u16(cc, imm16) => {
+                with_imm16(0xC2 | (condition_index(cc) << 3), imm16)
+            }
+            Instruction::JP_HL => vec![0xE9],
+            Instruction::JP_u16(imm16) => with_imm16(0xC3, imm16),
+            Instruction::JR_cc_i8(cc, i8) => vec![0x20 | (condition_index(cc) << 3), i8.0 as u8],
+            Instruction::JR_i8(i8) => vec![0x18, i8.0 as u8],
+            Instruction::JR_r8(r8) => panic!("JR {} has no encoding", r8),
+            Instruction::LD_A_FFC => vec![0xF2],
+            Instruction::LD_A_FFu8(u8) => vec![0xF0, u8.0],
+            Instruction::LD_A_mHLdec => vec![0x3A],
+            Instruction::LD_A_mHLinc => vec![0x2A],
+            Instruction::LD_A_mr16(r16) => Instruction::LD_r8_mr16(R8::A, r16.clone()).encode(),
+            Instruction::LD_A_mu16(imm16) => with_imm16(0xFA, imm16),
+            Instruction::LD_FFC_A => vec![0xE2],
+            Instruction::LD_FFu8_A(u8) => vec![0xE0, u8.0],
+            Instruction::LD_H_mHL => Instruction::LD_r8_mr16(R8::H, R16::HL).encode(),
+            Instruction::LD_HL_SP_i8(i8) => vec![0xF8, i8.0 as u8],
+            Instruction::LD_L_mHL => Instruction::LD_r8_mr16(R8::L, R16::HL).encode(),
+            Instruction::LD_mHL_u8(u8) => vec![0x36, u8.0],
+            Instruction::LD_mHLdec_A => vec![0x32],
+            Instruction::LD_mHLinc_A => vec![0x22],
+            Instruction::LD_mr16_r8(r16, r8) => match (r16, r8) {
+                (R16::BC, R8::A) => vec![0x02],
+                (R16::DE, R8::A) => vec![0x12],
+                (R16::HL, r8) => vec![0x70 | r8_index(r8)],
+                _ => panic!("LD [{}], {} has no encoding", r16, r8),
+            },
+            Instruction::LD_mu16_A(imm16) => with_imm16(0xEA, imm16),
+            Instruction::LD_mu16_SP(imm16) => with_imm16(0x08, imm16),
+            Instruction::LD_r16_d16(r16, imm16) => with_imm16(0x01 | (r16_index(r16) << 4), imm16),
+            Instruction::LD_r8_mr16(r8, r16) => match (r8, r16) {
+                (R8::A, R16::BC) => vec![0x0A],
+                (R8::A, R16::DE) => vec![0x1A],
+                (r8, R16::HL) => vec![0x46 | (r8_index(r8) << 3)],
+                _ => panic!("LD {}, [{}] has no encoding", r8, r16),
+            },
+            Instruction::LD_r8_r8(r8a, r8b) => vec![0x40 | (r8_index(r8a) << 3) | r8_index(r8b)],
+            Instruction::LD_r8_u8(r8, u8) => vec![0x06 | (r8_index(r8) << 3), u8.0],
+            Instruction::LD_SP_HL => vec![0xF9],
+            Instruction::LD_SP_u16(imm16) => with_imm16(0x31, imm16),
+            Instruction::NOP => vec![0x00],
+            Instruction::OR_A_mHL => vec![0xB6],
+            Instruction::OR_A_r8(r8) => vec![0xB0 | r8_index(r8)],
+            Instruction::OR_A_u8(u8) => vec![0xF6, u8.0],
+            Instruction::POP_r16(r16) => vec![0xC1 | (r16_stack_index(r16) << 4)],
+            Instruction::PUSH_r16(r16) => vec![0xC5 | (r16_stack_index(r16) << 4)],
+            Instruction::RES_u3_mHL(bit) => vec![CB_PREFIX, 0x86 | (bit << 3)],
+            Instruction::RES_u3_r8(bit, r8) => vec![CB_PREFIX, 0x80 | (bit << 3) | r8_index(r8)],
+            Instruction::RET_cc(cc) => vec![0xC0 | (condition_index(cc) << 3)],
+            Instruction::RET => vec![0xC9],
+            Instruction::RETI => vec![0xD9],
+            Instruction::RL_mHL => vec![CB_PREFIX, 0x16],
+            Instruction::RL_r8(r8) => vec![CB_PREFIX, 0x10 | r8_index(r8)],
+            Instruction::RLA => vec![0x17],
+            Instruction::RLC_mHL => vec![CB_PREFIX, 0x06],
+            Instruction::RLC_r8(r8) => vec![CB_PREFIX, r8_index(r8)],
+            Instruction::RLCA => vec![0x07],
+            Instruction::RR_mHL => vec![CB_PREFIX, 0x1E],
+            Instruction::RR_r8(r8) => vec![CB_PREFIX, 0x18 | r8_index(r8)],
+            Instruction::RRA => vec![0x1F],
+            Instruction::RRC_mHL => vec![CB_PREFIX, 0x0E],
+            Instruction::RRC_r8(r8) => vec![CB_PREFIX, 0x08 | r8_index(r8)],
+            Instruction::RRCA => vec![0x0F],
+            Instruction::RST(imm16) => vec![0xC7 | imm16.lower_byte.0],
+            Instruction::SBC_A_mHL => vec![0x9E],
+            Instruction::SBC_A_r8(r8) => vec![0x98 | r8_index(r8)],
+            Instruction::SBC_A_u8(u8) => vec![0xDE, u8.0],
+            Instruction::SCF => vec![0x37],
+            Instruction::SET_u3_mHL(bit) => vec![CB_PREFIX, 0xC6 | (bit << 3)],
+            Instruction::SET_u3_r8(bit, r8) => vec![CB_PREFIX, 0xC0 | (bit << 3) | r8_index(r8)],
+            Instruction::SLA_mHL => vec![CB_PREFIX, 0x26],
+            Instruction::SLA_r8(r8) => vec![CB_PREFIX, 0x20 | r8_index(r8)],
+            Instruction::SRA_mHL => vec![CB_PREFIX, 0x2E],
+            Instruction::SRA_r8(r8) => vec![CB_PREFIX, 0x28 | r8_index(r8)],
+            Instruction::SRL_mHL => vec![CB_PREFIX, 0x3E],
+            Instruction::SRL_r8(r8) => vec![CB_PREFIX, 0x38 | r8_index(r8)],
+            Instruction::STOP => vec![0x10, 0x00],
+            Instruction::SUB_A_mHL => vec![0x96],
+            Instruction::SUB_A_r8(r8) => vec![0x90 | r8_index(r8)],
+            Instruction::SUB_A_u8(u8) => vec![0xD6, u8.0],
+            Instruction::SWAP_mHL => vec![CB_PREFIX, 0x36],
+            Instruction::SWAP_r8(r8) => vec![CB_PREFIX, 0x30 | r8_index(r8)],
+            Instruction::XOR_A_mHL => vec![0xAE],
+            Instruction::XOR_A_r8(r8) => vec![0xA8 | r8_index(r8)],
+            Instruction::XOR_A_u8(u8) => vec![0xEE, u8.0],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{instructions::decode::decode_instruction_at_address, memory_bus::MemoryBus};
+
+    use super::CB_PREFIX;
+
+    // Just enough of a bus to decode from
+    struct Bytes(Vec<u8>);
+
+    impl MemoryBus for Bytes {
+        fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+            Wrapping(self.0.get(address.0 as usize).copied().unwrap_or(0))
+        }
+
+        fn write_u8(&mut self, _address: Wrapping<u16>, _value: Wrapping<u8>) {}
+
+        fn request_interrupt(&mut self, _interrupt_bit: u8) {}
+    }
+
+    fn assert_round_trip(bytes: Vec<u8>) {
+        let decoded = decode_instruction_at_address(&Bytes(bytes.clone()), Wrapping(0));
+        let encoded = decoded.instruction.encode();
+        assert_eq!(
+            encoded,
+            bytes[..decoded.instruction_size as usize],
+            "{}",
+            decoded
+        );
+        assert_eq!(decoded.instruction.length(), encoded.len());
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        for opcode in 0..=0xFF {
+            if opcode == CB_PREFIX {
+                continue;
+            }
+            // The byte following STOP is always encoded as 0x00
+            let operand = if opcode == 0x10 { 0x00 } else { 0x12 };
+            assert_round_trip(vec![opcode, operand, 0x34]);
+        }
+        for opcode in 0..=0xFF {
+            assert_round_trip(vec![CB_PREFIX, opcode]);
+        }
+    }
+}
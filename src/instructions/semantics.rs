@@ -29,6 +29,22 @@ fn sub_borrows(a: impl Into<u16>, b: impl Into<u16>, c: bool, bit: u8) -> bool {
     ((bit_mask | (a & input_mask)) - (b & input_mask) - (c as u32)) & bit_mask == 0
 }
 
+// SP plus a signed offset, for ADD SP and LD HL, SP.  The flags are those of adding the offset, as
+// an unsigned byte, to the low byte of SP: H and C are the carries out of bits 3 and 7, whatever
+// the sign of the offset, and Z and N are cleared.
+fn sp_plus_i8(cpu: &mut CPU, i8: &Wrapping<i8>) -> Wrapping<u16> {
+    let sp = cpu.registers().sp;
+    let low_byte = sp.0 as u8;
+    let offset = i8.0 as u8;
+    cpu.registers_mut().znhc(
+        false,
+        false,
+        add_produces_carry(low_byte, offset, false, 4),
+        add_produces_carry(low_byte, offset, false, 8),
+    );
+    Wrapping(sp.0.wrapping_add_signed(i8.0 as i16))
+}
+
 fn compare(cpu: &mut CPU, a: &Wrapping<u8>, b: &Wrapping<u8>) {
     cpu.registers_mut().znhc(
         *a == *b,
@@ -77,6 +93,17 @@ fn dec(cpu: &mut CPU, a: &Wrapping<u8>) -> Wrapping<u8> {
     res
 }
 
+// Shared by INC r8 and INC (HL), which write the returned value back themselves.  Unlike ADD,
+// this leaves Flag::C untouched.
+fn inc(cpu: &mut CPU, a: &Wrapping<u8>) -> Wrapping<u8> {
+    let res = a + Wrapping(1);
+    cpu.registers_mut()
+        .write_flag(Flag::Z, res.0 == 0)
+        .unset_flag(Flag::N)
+        .write_flag(Flag::H, add_produces_carry(a.0, 1 as u16, false, 4));
+    res
+}
+
 fn subc(cpu: &mut CPU, a: &Wrapping<u8>, b: &Wrapping<u8>, c: bool) {
     let res = a - b - Wrapping(c as u8);
     cpu.registers_mut().write_a(res).znhc(
@@ -106,17 +133,11 @@ fn call(machine: &mut Machine, address: Wrapping<u16>) {
 
 impl Instruction {
     pub fn execute(self: &Instruction, machine: &mut Machine) -> (u8, u8) {
-        // EI effects are delayed by one instruction, we resolve it here
-        if machine.interrupts().interrupt_master_enable_delayed {
-            machine.interrupts_mut().interrupt_master_enable_delayed = false;
-            machine.interrupts_mut().interrupt_master_enable = true;
-        }
-
         match self {
             Instruction::ADC_A_mHL => {
                 let a = machine.registers().read_a();
                 let hl = machine.registers().hl;
-                let b = machine.read_u8(hl);
+                let b = machine.cpu_read_u8(hl);
                 let c = machine.registers().read_flag(Flag::C);
                 adc(machine.cpu_mut(), &a, &b, c);
                 (8, 2)
@@ -139,7 +160,7 @@ impl Instruction {
 
             Instruction::ADD_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 add(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -171,20 +192,14 @@ impl Instruction {
             }
 
             Instruction::ADD_SP_i8(i8) => {
-                let a = machine.registers().sp;
-                let res = Wrapping(a.0.wrapping_add_signed(i8.0 as i16));
-                machine.registers_mut().write_r16(&R16::SP, res).znhc(
-                    false,
-                    false,
-                    add_produces_carry(a.0, i8.0, false, 4),
-                    add_produces_carry(a.0, i8.0, false, 8),
-                );
+                let res = sp_plus_i8(machine.cpu_mut(), i8);
+                machine.registers_mut().write_r16(&R16::SP, res);
                 (16, 4)
             }
 
             Instruction::AND_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 and(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -204,7 +219,7 @@ impl Instruction {
 
             Instruction::BIT_u3_mHL(bit_position) => {
                 let address = machine.registers().hl;
-                let value = ((machine.read_u8(address).0 >> bit_position) & 0x1) == 0x1;
+                let value = ((machine.cpu_read_u8(address).0 >> bit_position) & 0x1) == 0x1;
                 bit_complement(machine.cpu_mut(), value);
                 (12, 3)
             }
@@ -255,7 +270,7 @@ impl Instruction {
             Instruction::CP_A_mHL => {
                 let a = machine.registers().read_a();
                 let address = machine.registers().read_r16(&R16::HL);
-                let b = machine.read_u8(address);
+                let b = machine.cpu_read_u8(address);
                 compare(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -271,12 +286,12 @@ impl Instruction {
             }
 
             Instruction::DAA => {
-                let mut data = Wrapping(machine.registers().read_a().0 as u16);
+                let mut data = machine.registers().read_a();
                 let subtraction_flag = machine.registers().read_flag(Flag::N);
-                let mut half_carry = machine.registers().read_flag(Flag::H);
+                let half_carry = machine.registers().read_flag(Flag::H);
                 let mut carry = machine.registers().read_flag(Flag::C);
                 if subtraction_flag {
-                    // post-subtraction
+                    // post-subtraction: only the flags tell which digits borrowed
                     if half_carry {
                         data -= Wrapping(0x06);
                     }
@@ -284,31 +299,32 @@ impl Instruction {
                         data -= Wrapping(0x60);
                     }
                 } else {
-                    // post-addition
-                    if half_carry || ((data.0 & 0x0F) > 0x09) {
-                        data += Wrapping(0x06);
-                        half_carry = true; // set in case we entered because of the right condition
-                    }
-                    if carry || ((data.0 & 0x1FF) > 0x9F) {
+                    // post-addition: digits above 9 also need adjusting.  The high digit is checked
+                    // first, on the unadjusted value, since adjusting the low digit may carry into
+                    // it.
+                    if carry || data.0 > 0x99 {
                         data += Wrapping(0x60);
                         carry = true; // set in case we entered because of the right condition
                     }
+                    if half_carry || ((data.0 & 0x0F) > 0x09) {
+                        data += Wrapping(0x06);
+                    }
                 }
 
                 machine
                     .registers_mut()
-                    .write_a(Wrapping(data.0 as u8))
+                    .write_a(data)
                     .write_flag(Flag::Z, data.0 == 0)
-                    .write_flag(Flag::H, half_carry)
+                    .unset_flag(Flag::H)
                     .write_flag(Flag::C, carry);
 
                 (4, 1)
             }
 
             Instruction::DEC_mHL => {
-                let a = machine.read_u8(machine.registers().hl);
+                let a = machine.cpu_read_u8(machine.registers().hl);
                 let res = dec(machine.cpu_mut(), &a);
-                machine.write_u8(machine.registers().hl, res);
+                machine.cpu_write_u8(machine.registers().hl, res);
                 (12, 3)
             }
 
@@ -328,43 +344,40 @@ impl Instruction {
 
             Instruction::DI => {
                 machine.interrupts_mut().interrupt_master_enable = false;
+                // Cancels a preceding EI, so that `EI; DI` never lets an interrupt through
+                machine.cpu_mut().ime_pending = false;
                 (4, 1)
             }
 
-            // NOTE: This sets up IME in one instruction
+            // NOTE: IME only gets set after the next instruction, see `CPU::execute_one_instruction`
             Instruction::EI => {
-                machine.interrupts_mut().interrupt_master_enable_delayed = true;
+                machine.cpu_mut().ime_pending = true;
                 (4, 1)
             }
 
             Instruction::HALT => {
-                if machine.interrupts().interrupt_master_enable {
-                    machine.cpu_mut().low_power_mode = true;
+                if !machine.interrupts().interrupt_master_enable
+                    && machine.interrupts().is_interrupt_pending()
+                {
+                    // HALT bug: the CPU does not halt, and fails to increment PC after reading the
+                    // next opcode, so that the byte after HALT is read twice
+                    machine.cpu_mut().halt_bug = true;
                 } else {
-                    if machine.interrupts().is_interrupt_pending() {
-                        // TODO: emulate HALT bug
-                        machine.cpu_mut().low_power_mode = true;
-                    } else {
-                        machine.cpu_mut().low_power_mode = true;
-                    }
+                    machine.cpu_mut().low_power_mode = true;
                 }
                 (4, 1)
             }
 
+            // `CPU::execute_one_instruction` locks up the CPU instead
             Instruction::Illegal(opcode) => {
-                panic!("Attempted to execute an illegal opcode: 0x{:02X}", opcode)
+                unreachable!("Attempted to execute an illegal opcode: 0x{:02X}", opcode)
             }
 
             Instruction::INC_r8(r8) => {
                 // NOTE: Can't use `add` because we don't want to touch Flag::C
-                let r8val = machine.read_r8(r8);
-                let res = r8val + Wrapping(1);
-                machine
-                    .registers_mut()
-                    .write_r8(r8, res)
-                    .write_flag(Flag::Z, res.0 == 0)
-                    .unset_flag(Flag::N)
-                    .write_flag(Flag::H, add_produces_carry(r8val.0, 1 as u16, false, 4));
+                let a = machine.read_r8(r8);
+                let res = inc(machine.cpu_mut(), &a);
+                machine.registers_mut().write_r8(r8, res);
                 (4, 1)
             }
 
@@ -375,8 +388,9 @@ impl Instruction {
             }
 
             Instruction::INC_mHL => {
-                let res = machine.read_u8(machine.registers().hl) + Wrapping(1);
-                machine.write_u8(machine.registers().hl, res);
+                let a = machine.cpu_read_u8(machine.registers().hl);
+                let res = inc(machine.cpu_mut(), &a);
+                machine.cpu_write_u8(machine.registers().hl, res);
                 (12, 3)
             }
 
@@ -419,14 +433,14 @@ impl Instruction {
 
             Instruction::LD_A_mr16(r16) => {
                 let address = machine.registers().read_r16(r16);
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 machine.registers_mut().write_a(a);
                 (8, 2)
             }
 
             Instruction::LD_A_mHLdec => {
                 let hl = machine.registers().hl;
-                let a = machine.read_u8(hl);
+                let a = machine.cpu_read_u8(hl);
                 machine.registers_mut().write_a(a);
                 machine.registers_mut().hl -= 1;
                 (8, 2)
@@ -434,14 +448,14 @@ impl Instruction {
 
             Instruction::LD_A_mHLinc => {
                 let hl = machine.registers().hl;
-                let a = machine.read_u8(hl);
+                let a = machine.cpu_read_u8(hl);
                 machine.registers_mut().write_a(a);
                 machine.registers_mut().hl += 1;
                 (8, 2)
             }
 
             Instruction::LD_FFu8_A(u8) => {
-                machine.write_u8(
+                machine.cpu_write_u8(
                     Wrapping(0xFF00 + (*u8).0 as u16),
                     machine.registers().read_a(),
                 );
@@ -449,28 +463,21 @@ impl Instruction {
             }
 
             Instruction::LD_HL_SP_i8(i8) => {
-                let sp = machine.registers().sp;
-                let res = Wrapping(sp.0.wrapping_add_signed(i8.0 as i16));
+                let res = sp_plus_i8(machine.cpu_mut(), i8);
                 machine.registers_mut().hl = res;
-                machine.registers_mut().znhc(
-                    false,
-                    false,
-                    add_produces_carry(sp.0, i8.0, false, 4),
-                    add_produces_carry(sp.0, i8.0, false, 8),
-                );
                 (12, 3)
             }
 
             Instruction::LD_mu16_A(imm16) => {
-                machine.write_u8(imm16.as_u16(), machine.registers().read_a());
+                machine.cpu_write_u8(imm16.as_u16(), machine.registers().read_a());
                 (16, 4)
             }
 
             Instruction::LD_mu16_SP(imm16) => {
                 let sp = Immediate16::from_u16(machine.registers().sp);
                 let address = imm16.as_u16();
-                machine.write_u8(address, sp.lower_byte);
-                machine.write_u8(address + Wrapping(1), sp.higher_byte);
+                machine.cpu_write_u8(address, sp.lower_byte);
+                machine.cpu_write_u8(address + Wrapping(1), sp.higher_byte);
                 (20, 5)
             }
 
@@ -479,7 +486,7 @@ impl Instruction {
             Instruction::LD_L_mHL => todo!(),
 
             Instruction::LD_FFC_A => {
-                machine.write_u8(
+                machine.cpu_write_u8(
                     Wrapping(0xFF00) + Wrapping(machine.registers().read_c().0 as u16),
                     machine.registers().read_a(),
                 );
@@ -498,42 +505,42 @@ impl Instruction {
             }
 
             Instruction::LD_mr16_r8(mr16, r8) => {
-                machine.write_u8(machine.registers().read_r16(mr16), machine.read_r8(r8));
+                machine.cpu_write_u8(machine.registers().read_r16(mr16), machine.read_r8(r8));
                 (8, 2)
             }
 
             Instruction::LD_mHL_u8(u8) => {
-                machine.write_u8(machine.registers().hl, *u8);
+                machine.cpu_write_u8(machine.registers().hl, *u8);
                 (12, 3)
             }
 
             Instruction::LD_mHLdec_A => {
-                machine.write_u8(machine.registers().hl, machine.registers().read_a());
+                machine.cpu_write_u8(machine.registers().hl, machine.registers().read_a());
                 machine.registers_mut().hl -= 1;
                 (8, 2)
             }
 
             Instruction::LD_mHLinc_A => {
-                machine.write_u8(machine.registers().hl, machine.registers().read_a());
+                machine.cpu_write_u8(machine.registers().hl, machine.registers().read_a());
                 machine.registers_mut().hl += 1;
                 (8, 2)
             }
 
             Instruction::LD_A_FFC => {
                 let c = machine.registers().read_c();
-                let a = machine.read_u8(Wrapping(0xFF00) + Wrapping(c.0 as u16));
+                let a = machine.cpu_read_u8(Wrapping(0xFF00) + Wrapping(c.0 as u16));
                 machine.registers_mut().write_a(a);
                 (8, 2)
             }
 
             Instruction::LD_A_FFu8(u8) => {
-                let a = machine.read_u8(Wrapping(0xFF00) + Wrapping((*u8).0 as u16));
+                let a = machine.cpu_read_u8(Wrapping(0xFF00) + Wrapping((*u8).0 as u16));
                 machine.registers_mut().write_a(a);
                 (12, 3)
             }
 
             Instruction::LD_A_mu16(imm16) => {
-                let a = machine.read_u8(imm16.as_u16());
+                let a = machine.cpu_read_u8(imm16.as_u16());
                 machine.registers_mut().write_a(a);
                 (16, 4)
             }
@@ -545,7 +552,7 @@ impl Instruction {
 
             Instruction::LD_r8_mr16(r8, r16) => {
                 let address = machine.registers().read_r16(r16);
-                let val = machine.read_u8(address);
+                let val = machine.cpu_read_u8(address);
                 machine.registers_mut().write_r8(r8, val);
                 (8, 2)
             }
@@ -564,7 +571,7 @@ impl Instruction {
 
             Instruction::OR_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 or(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -604,9 +611,9 @@ impl Instruction {
 
             Instruction::RES_u3_mHL(u8) => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = bit_reset(&a, u8);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -631,6 +638,7 @@ impl Instruction {
                 }
             }
 
+            // Unlike EI, RETI enables interrupts immediately
             Instruction::RETI => {
                 machine.interrupts_mut().interrupt_master_enable = true;
                 CPU::pop_r16(machine, &R16::PC);
@@ -639,9 +647,9 @@ impl Instruction {
 
             Instruction::RL_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = rotate_left_through_carry(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -672,9 +680,9 @@ impl Instruction {
 
             Instruction::RLC_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = rotate_left(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -696,9 +704,9 @@ impl Instruction {
 
             Instruction::RR_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = rotate_right_through_carry(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -720,9 +728,9 @@ impl Instruction {
 
             Instruction::RRC_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = rotate_right(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -741,7 +749,7 @@ impl Instruction {
 
             Instruction::SBC_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 let c = machine.registers().read_flag(Flag::C);
                 subc(machine.cpu_mut(), &a, &b, c);
                 (8, 2)
@@ -773,9 +781,9 @@ impl Instruction {
 
             Instruction::SET_u3_mHL(u8) => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = bit_set(&a, u8);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -788,9 +796,9 @@ impl Instruction {
 
             Instruction::SLA_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = rotate_left_with(machine.cpu_mut(), &a, false);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -803,9 +811,9 @@ impl Instruction {
 
             Instruction::SRA_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = shift_right_arithmetically(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -818,9 +826,9 @@ impl Instruction {
 
             Instruction::SRL_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = shift_right_logically(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -832,13 +840,24 @@ impl Instruction {
             }
 
             Instruction::STOP => {
-                // TODO
+                // An armed speed switch (KEY1) happens instead of stopping.  DIV gets reset either
+                // way.
+                let cpu = machine.cpu_mut();
+                if cpu.speed_switch_armed {
+                    cpu.speed_switch_armed = false;
+                    cpu.double_speed = !cpu.double_speed;
+                } else {
+                    cpu.stopped = true;
+                }
+                // Not through `cpu_write_u8`, which would take a bus M-cycle that STOP does not
+                // have: like a write to DIV, the reset lands at the end of this single M-cycle.
+                machine.write_u8(Wrapping(0xFF04), Wrapping(0));
                 (4, 1)
             }
 
             Instruction::SUB_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 sub(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -858,9 +877,9 @@ impl Instruction {
 
             Instruction::SWAP_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cpu_read_u8(address);
                 let res = swap(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cpu_write_u8(address, res);
                 (16, 4)
             }
 
@@ -886,7 +905,7 @@ impl Instruction {
 
             Instruction::XOR_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cpu_read_u8(machine.registers().hl);
                 xor(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -894,6 +913,7 @@ impl Instruction {
     }
 }
 
+// The bit shifted out goes to Flag::C, N and H get cleared.  SLA shifts in a 0.
 pub fn rotate_left_with(cpu: &mut CPU, value: &Wrapping<u8>, new_bit: bool) -> Wrapping<u8> {
     let carry = value.0 >> 7;
     let res = Wrapping((value.0 << 1) | (new_bit as u8));
@@ -930,6 +950,7 @@ pub fn rotate_right(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrapping<u8> {
     rotate_right_with(cpu, value, new_bit)
 }
 
+// Bit 7 is kept, so that the sign is preserved, while bit 0 goes to Flag::C
 pub fn shift_right_arithmetically(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrapping<u8> {
     let carry = value.0 & 1;
     let bit7 = value & Wrapping(0x80);
@@ -939,6 +960,7 @@ pub fn shift_right_arithmetically(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrappi
     res
 }
 
+// Bit 7 becomes 0, while bit 0 goes to Flag::C
 pub fn shift_right_logically(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrapping<u8> {
     let carry = value.0 & 1;
     let res = value >> 1;
@@ -947,6 +969,7 @@ pub fn shift_right_logically(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrapping<u8
     res
 }
 
+// Nothing gets shifted out, so only Flag::Z can end up set
 pub fn swap(cpu: &mut CPU, value: &Wrapping<u8>) -> Wrapping<u8> {
     let new_low = value >> 4;
     let new_high = (value & Wrapping(0x0F)) << 4;
@@ -969,3 +992,322 @@ pub fn bit_reset(value: &Wrapping<u8>, bit_position: &u8) -> Wrapping<u8> {
 pub fn bit_set(value: &Wrapping<u8>, bit_position: &u8) -> Wrapping<u8> {
     Wrapping(value.0 | (1 << bit_position))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        instructions::{assembler::Assembler, type_def::Instruction},
+        machine::Machine,
+        registers::{Flag, R16, R8},
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    const HL_ADDRESS: u16 = 0xC000;
+
+    fn idle() -> Box<Machine> {
+        machine_running(Assembler::new(0x150).halt())
+    }
+
+    fn set_a_and_carry(machine: &mut Machine, a: u8, carry: bool) {
+        machine
+            .registers_mut()
+            .write_a(Wrapping(a))
+            .write_flag(Flag::C, carry);
+    }
+
+    fn flags(machine: &Machine) -> [bool; 4] {
+        [Flag::Z, Flag::N, Flag::H, Flag::C].map(|flag| machine.registers().read_flag(flag))
+    }
+
+    // Runs the r8 form of an instruction on B and its (HL) form, on `value` with the flags set to
+    // `initial_flags`, and returns the resulting value and flags of each
+    fn run_both_forms(
+        machine: &mut Machine,
+        r8_form: Instruction,
+        mhl_form: Instruction,
+        value: u8,
+        initial_flags: [bool; 4],
+    ) -> [(u8, [bool; 4]); 2] {
+        let [z, n, h, c] = initial_flags;
+        machine
+            .registers_mut()
+            .write_r8(&R8::B, Wrapping(value))
+            .znhc(z, n, h, c);
+        r8_form.execute(machine);
+        let r8_result = (machine.registers().read_r8(&R8::B).0, flags(machine));
+
+        machine
+            .registers_mut()
+            .write_r16(&R16::HL, Wrapping(HL_ADDRESS))
+            .znhc(z, n, h, c);
+        machine.write_u8(Wrapping(HL_ADDRESS), Wrapping(value));
+        mhl_form.execute(machine);
+        let mhl_result = (machine.read_u8(Wrapping(HL_ADDRESS)).0, flags(machine));
+
+        [r8_result, mhl_result]
+    }
+
+    fn bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
+    // Every pair of BCD operands, checked against decimal arithmetic
+    #[test]
+    fn daa_adjusts_bcd_arithmetic() {
+        with_large_stack(|| {
+            let mut machine = idle();
+            type Operation = fn(Wrapping<u8>) -> Instruction;
+            let operations: [(Operation, bool, bool); 4] = [
+                (Instruction::ADD_A_u8, false, false),
+                (Instruction::ADC_A_u8, true, false),
+                (Instruction::SUB_A_u8, false, true),
+                (Instruction::SBC_A_u8, true, true),
+            ];
+            for (operation, carry_in, is_subtraction) in operations {
+                for a in 0..100 {
+                    for b in 0..100 {
+                        set_a_and_carry(&mut machine, bcd(a), carry_in);
+                        operation(Wrapping(bcd(b))).execute(&mut machine);
+                        Instruction::DAA.execute(&mut machine);
+                        let (result, carry) = if is_subtraction {
+                            let subtrahend = b + carry_in as u8;
+                            ((100 + a - subtrahend) % 100, a < subtrahend)
+                        } else {
+                            let sum = a + b + carry_in as u8;
+                            (sum % 100, sum >= 100)
+                        };
+                        assert_eq!(
+                            (machine.registers().read_a().0, flags(&machine)),
+                            (bcd(result), [result == 0, is_subtraction, false, carry]),
+                            "0x{:02X}, 0x{:02X}, carry {}, subtraction {}",
+                            bcd(a),
+                            bcd(b),
+                            carry_in,
+                            is_subtraction
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    // Inputs no BCD operation produces, with the results of hardware
+    #[test]
+    fn daa_matches_reference_table() {
+        with_large_stack(|| {
+            let mut machine = idle();
+            // A, N, H, C before DAA, then A and C after it
+            let table = [
+                (0x0A, false, false, false, 0x10, false),
+                (0x9A, false, false, false, 0x00, true),
+                (0xA0, false, false, false, 0x00, true),
+                (0xFF, false, false, false, 0x65, true),
+                (0x00, false, true, false, 0x06, false),
+                (0x00, false, false, true, 0x60, true),
+                (0x0F, true, false, false, 0x0F, false),
+                (0x00, true, true, false, 0xFA, false),
+                (0x00, true, true, true, 0x9A, true),
+                (0x10, true, false, true, 0xB0, true),
+            ];
+            for (a, n, h, c, expected_a, expected_c) in table {
+                machine
+                    .registers_mut()
+                    .write_a(Wrapping(a))
+                    .znhc(false, n, h, c);
+                Instruction::DAA.execute(&mut machine);
+                assert_eq!(
+                    (machine.registers().read_a().0, flags(&machine)),
+                    (expected_a, [expected_a == 0, n, false, expected_c]),
+                    "0x{:02X}, N {}, H {}, C {}",
+                    a,
+                    n,
+                    h,
+                    c
+                );
+            }
+        });
+    }
+
+    // Value before and after, then Z, N, and H after
+    type IncDecCase = (u8, u8, [bool; 3]);
+
+    fn check_inc_dec(r8_form: Instruction, mhl_form: Instruction, cases: [IncDecCase; 2]) {
+        let mut machine = idle();
+        for (value, expected, [z, n, h]) in cases {
+            // The other flags start out opposite to what they should become, the carry either way
+            for carry in [false, true] {
+                let initial_flags = [!z, !n, !h, carry];
+                assert_eq!(
+                    run_both_forms(
+                        &mut machine,
+                        r8_form.clone(),
+                        mhl_form.clone(),
+                        value,
+                        initial_flags
+                    ),
+                    [(expected, [z, n, h, carry]); 2],
+                    "{} on 0x{:02X}, carry {}",
+                    r8_form,
+                    value,
+                    carry
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn inc_sets_z_and_h_and_preserves_c() {
+        with_large_stack(|| {
+            check_inc_dec(
+                Instruction::INC_r8(R8::B),
+                Instruction::INC_mHL,
+                [
+                    (0x0F, 0x10, [false, false, true]),
+                    (0xFF, 0x00, [true, false, true]),
+                ],
+            );
+        });
+    }
+
+    #[test]
+    fn dec_sets_z_and_h_and_preserves_c() {
+        with_large_stack(|| {
+            check_inc_dec(
+                Instruction::DEC_r8(R8::B),
+                Instruction::DEC_mHL,
+                [
+                    (0x10, 0x0F, [false, true, true]),
+                    (0x01, 0x00, [true, true, false]),
+                ],
+            );
+        });
+    }
+
+    // Checks both forms of a shift or swap on 0x01, 0x80, and 0xFF, giving the expected value and
+    // carry for each.  Z is set for a zero result, N and H are always cleared.
+    fn check_shift(r8_form: Instruction, mhl_form: Instruction, expected: [(u8, bool); 3]) {
+        let mut machine = idle();
+        for (value, (expected, carry)) in [0x01, 0x80, 0xFF].into_iter().zip(expected) {
+            // No flag affects the result
+            for initial_flags in [[false; 4], [true; 4]] {
+                assert_eq!(
+                    run_both_forms(
+                        &mut machine,
+                        r8_form.clone(),
+                        mhl_form.clone(),
+                        value,
+                        initial_flags
+                    ),
+                    [(expected, [expected == 0, false, false, carry]); 2],
+                    "{} on 0x{:02X}, flags {:?}",
+                    r8_form,
+                    value,
+                    initial_flags
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sla_shifts_bit_7_into_carry() {
+        with_large_stack(|| {
+            check_shift(
+                Instruction::SLA_r8(R8::B),
+                Instruction::SLA_mHL,
+                [(0x02, false), (0x00, true), (0xFE, true)],
+            );
+        });
+    }
+
+    #[test]
+    fn sra_preserves_bit_7() {
+        with_large_stack(|| {
+            check_shift(
+                Instruction::SRA_r8(R8::B),
+                Instruction::SRA_mHL,
+                [(0x00, true), (0xC0, false), (0xFF, true)],
+            );
+        });
+    }
+
+    #[test]
+    fn srl_shifts_in_0() {
+        with_large_stack(|| {
+            check_shift(
+                Instruction::SRL_r8(R8::B),
+                Instruction::SRL_mHL,
+                [(0x00, true), (0x40, false), (0x7F, true)],
+            );
+        });
+    }
+
+    #[test]
+    fn swap_clears_flags_but_z() {
+        with_large_stack(|| {
+            check_shift(
+                Instruction::SWAP_r8(R8::B),
+                Instruction::SWAP_mHL,
+                [(0x10, false), (0x08, false), (0xFF, false)],
+            );
+            let mut machine = idle();
+            assert_eq!(
+                run_both_forms(
+                    &mut machine,
+                    Instruction::SWAP_r8(R8::B),
+                    Instruction::SWAP_mHL,
+                    0x00,
+                    [false, true, true, true]
+                ),
+                [(0x00, [true, false, false, false]); 2]
+            );
+        });
+    }
+
+    // SP, the offset, the result, then H and C, which come from adding the offset to SP's low
+    // byte as unsigned bytes
+    const SP_PLUS_I8_CASES: [(u16, i8, u16, bool, bool); 7] = [
+        (0xFFF8, 2, 0xFFFA, false, false),
+        (0x000F, 1, 0x0010, true, false),
+        (0x00FF, 1, 0x0100, true, true),
+        (0x0000, -1, 0xFFFF, false, false),
+        (0x0001, -1, 0x0000, true, true),
+        (0xD000, -128, 0xCF80, false, false),
+        (0x1088, -16, 0x1078, false, true),
+    ];
+
+    #[test]
+    fn add_sp_i8_and_ld_hl_sp_i8_flag_the_low_byte_addition() {
+        with_large_stack(|| {
+            let mut machine = idle();
+            for (sp, offset, expected, h, c) in SP_PLUS_I8_CASES {
+                // Z and N always end up cleared, even for a zero result
+                for f in [false, true] {
+                    machine.registers_mut().sp = Wrapping(sp);
+                    machine.registers_mut().znhc(f, f, f, f);
+                    Instruction::ADD_SP_i8(Wrapping(offset)).execute(&mut machine);
+                    assert_eq!(
+                        (machine.registers().sp.0, flags(&machine)),
+                        (expected, [false, false, h, c]),
+                        "ADD SP, {} with SP 0x{:04X}",
+                        offset,
+                        sp
+                    );
+
+                    machine.registers_mut().sp = Wrapping(sp);
+                    machine.registers_mut().znhc(f, f, f, f);
+                    Instruction::LD_HL_SP_i8(Wrapping(offset)).execute(&mut machine);
+                    assert_eq!(
+                        (machine.registers().hl.0, flags(&machine)),
+                        (expected, [false, false, h, c]),
+                        "LD HL, SP{:+} with SP 0x{:04X}",
+                        offset,
+                        sp
+                    );
+                    assert_eq!(machine.registers().sp.0, sp);
+                }
+            }
+        });
+    }
+}
@@ -2,7 +2,7 @@ use std::num::Wrapping;
 
 use crate::{
     conditions::Condition,
-    machine::Machine,
+    memory_bus::MemoryBus,
     registers::{R16, R8},
 };
 
@@ -25,10 +25,10 @@ impl Immediate16 {
     }
 
     // In ROM, immediate 16-bit values are stored lower-byte-first.
-    pub fn from_memory(machine: &Machine, address: Wrapping<u16>) -> Immediate16 {
+    pub fn from_memory(bus: &impl MemoryBus, address: Wrapping<u16>) -> Immediate16 {
         Immediate16 {
-            lower_byte: machine.read_u8(address),
-            higher_byte: machine.read_u8(address + Wrapping(1)),
+            lower_byte: bus.read_u8(address),
+            higher_byte: bus.read_u8(address + Wrapping(1)),
         }
     }
 }
@@ -0,0 +1,30 @@
+pub mod application_state;
+pub mod apu;
+pub mod cartridge;
+pub mod cheats;
+pub mod command_line_arguments;
+pub mod conditions;
+pub mod cpu;
+pub mod dma;
+pub mod hdma;
+pub mod inputs;
+pub mod instructions;
+pub mod machine;
+pub mod memory;
+pub mod memory_bus;
+pub mod message;
+pub mod movie;
+pub mod pixel_fetcher;
+pub mod ppu;
+pub mod registers;
+pub mod rewind;
+pub mod rtc;
+pub mod screenshot;
+pub mod serial;
+pub mod state_diff;
+pub mod symbols;
+pub mod test_utils;
+pub mod trace;
+pub mod utils;
+pub mod view;
+pub mod watchpoints;
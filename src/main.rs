@@ -1,23 +1,9 @@
-pub mod application_state;
-pub mod command_line_arguments;
-pub mod conditions;
-pub mod cpu;
-pub mod inputs;
-pub mod instructions;
-pub mod machine;
-pub mod memory;
-pub mod message;
-pub mod pixel_fetcher;
-pub mod ppu;
-pub mod registers;
-pub mod utils;
-pub mod view;
-
-use application_state::ApplicationState;
 use clap::Parser;
-use command_line_arguments::CommandLineArguments;
 use iced::{self, advanced::graphics::core::font, Settings, Size, Task};
-use message::Message;
+use yokoyboi::{
+    application_state::ApplicationState, command_line_arguments::CommandLineArguments,
+    message::Message,
+};
 
 const BREAKPOINTS: &[u16] = &[
     // 0x00F1, // passed logo check
@@ -1,53 +1,59 @@
 use std::{
     io::{self, Error},
     num::Wrapping,
+    path::Path,
 };
 
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
 use crate::{
-    application_state::{MapperType, RAMSize, ROMInformation},
+    cartridge::Cartridge,
     instructions::decode::{decode_instruction_at_address, DecodedInstruction},
     machine::Machine,
+    memory_bus::MemoryBus,
 };
 
+const BOOT_ROM_SIZE: usize = 0x100;
 const HRAM_SIZE: usize = 0x7F;
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
 pub struct Memory {
+    // ROMs are not part of save states, they are carried over from the running machine instead
+    #[serde(skip)]
     boot_rom: Vec<u8>,
+    #[serde(skip)]
     pub game_rom: Vec<u8>,
     pub game_ram: Vec<u8>,
+    #[serde(with = "BigArray")]
     pub hram: [u8; HRAM_SIZE],
 }
 
 impl Memory {
-    pub fn decode_instruction_at(machine: &Machine, address: Wrapping<u16>) -> DecodedInstruction {
-        decode_instruction_at_address(machine, address)
+    pub fn decode_instruction_at(
+        bus: &impl MemoryBus,
+        address: Wrapping<u16>,
+    ) -> DecodedInstruction {
+        decode_instruction_at_address(bus, address)
     }
 
     pub fn decode_instructions_at(
-        machine: &Machine,
+        bus: &impl MemoryBus,
         address: Wrapping<u16>,
         how_many: u8,
     ) -> Vec<DecodedInstruction> {
         let mut res = Vec::new();
         let mut pc = address;
         for _ in 0..how_many {
-            let instr = decode_instruction_at_address(machine, pc);
+            let instr = decode_instruction_at_address(bus, pc);
             pc = pc + Wrapping(instr.instruction_size as u16);
             res.push(instr);
         }
         res
     }
 
-    pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, rom_information: &ROMInformation) -> Self {
-        let game_ram = match rom_information.ram_size {
-            RAMSize::NoRAM => Vec::new(),
-            RAMSize::Ram2kb => Vec::from([0; 0x800]),
-            RAMSize::Ram8kb => Vec::from([0; 0x2000]),
-            RAMSize::Ram4banks8kb => todo!(),
-            RAMSize::Ram16banks8kb => todo!(),
-            RAMSize::Ram8banks8kb => todo!(),
-        };
+    pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, cartridge: &Cartridge) -> Self {
+        let game_ram = vec![0; cartridge.external_ram_size()];
         Memory {
             boot_rom,
             game_rom,
@@ -56,64 +62,168 @@ impl Memory {
         }
     }
 
+    pub fn take_roms_from(&mut self, other: &mut Memory) {
+        self.boot_rom = std::mem::take(&mut other.boot_rom);
+        self.game_rom = std::mem::take(&mut other.game_rom);
+    }
+
+    pub fn has_boot_rom(&self) -> bool {
+        !self.boot_rom.is_empty()
+    }
+
     pub fn read_boot_rom(&self, address: Wrapping<u16>) -> Wrapping<u8> {
         Wrapping(self.boot_rom[address.0 as usize])
     }
 }
 
-// TODO: move somewhere
-pub fn load_boot_rom(path: &String) -> Result<Vec<u8>, io::Error> {
-    let bytes = std::fs::read(path)?;
-    let byte_length = bytes.len();
-    if byte_length > 0x100 {
-        return Err(Error::other(
-            "Refusing to load a boot ROM larger than 0xFF bytes.",
-        ));
+pub fn boot_rom_from_bytes(bytes: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+    if bytes.len() != BOOT_ROM_SIZE {
+        return Err(Error::other(format!(
+            "Boot ROM is 0x{:X} bytes, expected 0x{:X} bytes.",
+            bytes.len(),
+            BOOT_ROM_SIZE
+        )));
     }
     Ok(bytes)
 }
 
-pub fn load_game_rom(path: &String) -> Result<(Vec<u8>, ROMInformation), io::Error> {
-    let bytes = std::fs::read(path)?;
-    let byte_length = bytes.len();
-    if byte_length > 0x8000 {
-        println!("[WARNING] ROM larger than 0x8000 bytes, errors may occur.");
-    }
-
-    println!("MBC: 0x{:02X}", bytes[0x147]);
-    // Now compute ROM information
-    let mapper_type = match bytes[0x147] {
-        0x00 => MapperType::ROMOnly,
-        0x01..=0x03 => MapperType::MBC1,
-        byte => {
-            println!("Unhandled mapper type: 0x{:02X}", byte);
-            MapperType::Other
+// The header decides the mapper, a size mismatch with the header is only reported
+pub fn game_rom_from_bytes(bytes: Vec<u8>) -> Result<(Vec<u8>, Cartridge), io::Error> {
+    if bytes.is_empty() {
+        return Err(Error::other("Cartridge ROM is empty."));
+    }
+    let cartridge = Cartridge::from_header(&bytes)?;
+    println!("MBC: 0x{:02X}", cartridge.cartridge_type);
+    let expected_length = cartridge.rom_banks as usize * 0x4000;
+    if bytes.len() != expected_length {
+        println!(
+            "[WARNING] ROM is 0x{:X} bytes, but its header announces 0x{:X} bytes.",
+            bytes.len(),
+            expected_length
+        );
+    }
+    Ok((bytes, cartridge))
+}
+
+pub fn load_boot_rom(path: &Path) -> Result<Vec<u8>, io::Error> {
+    boot_rom_from_bytes(std::fs::read(path)?)
+}
+
+pub fn load_game_rom(path: &Path) -> Result<(Vec<u8>, Cartridge), io::Error> {
+    game_rom_from_bytes(std::fs::read(path)?)
+}
+
+impl Machine {
+    // Replaces the boot ROM, which only matters until it gets latched off by a write to 0xFF50
+    pub fn load_boot_rom(&mut self, path: &Path) -> Result<(), io::Error> {
+        self.memory_mut().boot_rom = load_boot_rom(path)?;
+        Ok(())
+    }
+
+    // Inserts a cartridge, meant to be done before running the machine.  Its external RAM starts
+    // out cleared.
+    pub fn load_rom(&mut self, path: &Path) -> Result<(), io::Error> {
+        let (game_rom, cartridge) = load_game_rom(path)?;
+        self.memory_mut().game_rom = game_rom;
+        self.memory_mut().game_ram = vec![0; cartridge.external_ram_size()];
+        self.cartridge = cartridge;
+        Ok(())
+    }
+
+    // Only battery-backed RAM survives power off, so only then is there anything to persist
+    pub fn save_external_ram(&self, path: &Path) -> Result<(), io::Error> {
+        if !self.cartridge.has_battery || self.memory().game_ram.is_empty() {
+            return Ok(());
         }
-    };
-    let rom_banks = match bytes[0x148] {
-        0x00 => 0,
-        0x01 => 4,
-        0x02 => 8,
-        0x03 => 16,
-        0x04 => 32,
-        byte => panic!("Unhandled ROM bank size: 0x{:02X}", byte),
-    };
-    let ram_size = match bytes[0x149] {
-        0x00 => RAMSize::NoRAM,
-        0x01 => RAMSize::Ram2kb,
-        0x02 => RAMSize::Ram8kb,
-        0x03 => RAMSize::Ram4banks8kb,
-        0x04 => RAMSize::Ram16banks8kb,
-        0x05 => RAMSize::Ram8banks8kb,
-        byte => panic!("Unhandled RAM size: 0x{:02X}", byte),
+        std::fs::write(path, &self.memory().game_ram)
+    }
+
+    // A missing or mismatched save file leaves the external RAM untouched
+    pub fn load_external_ram(&mut self, path: &Path) -> Result<(), io::Error> {
+        if !self.cartridge.has_battery || !path.exists() {
+            return Ok(());
+        }
+        let bytes = std::fs::read(path)?;
+        if bytes.len() != self.memory().game_ram.len() {
+            println!(
+                "[WARNING] Ignoring save file of 0x{:X} bytes, expected 0x{:X} bytes.",
+                bytes.len(),
+                self.memory().game_ram.len()
+            );
+            return Ok(());
+        }
+        self.memory_mut().game_ram.copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        instructions::assembler::Assembler,
+        machine::Machine,
+        test_utils::{idle, machine_with_rom, with_large_stack},
     };
 
-    Ok((
-        bytes,
-        ROMInformation {
-            mapper_type,
-            ram_size,
-            rom_banks,
-        },
-    ))
+    use super::{boot_rom_from_bytes, game_rom_from_bytes};
+
+    // MBC1 with 4 banks of battery-backed RAM
+    fn battery_machine() -> Box<Machine> {
+        let mut rom = idle().rom();
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x03;
+        machine_with_rom(rom)
+    }
+
+    #[test]
+    fn external_ram_round_trips_through_the_save_file() {
+        with_large_stack(|| {
+            let path = std::env::temp_dir().join(format!("yokoyboi-{}.sav", std::process::id()));
+            let mut machine = battery_machine();
+            let pattern: Vec<u8> = (0..0x8000)
+                .map(|offset| (offset ^ (offset >> 8)) as u8)
+                .collect();
+            machine.memory_mut().game_ram.copy_from_slice(&pattern);
+            machine.save_external_ram(&path).unwrap();
+            machine.memory_mut().game_ram.fill(0);
+            machine.load_external_ram(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(machine.memory().game_ram, pattern);
+
+            // Missing files leave the RAM as is
+            machine.load_external_ram(&path).unwrap();
+            assert_eq!(machine.memory().game_ram, pattern);
+        });
+    }
+
+    #[test]
+    fn load_rom_maps_the_file_from_address_0() {
+        with_large_stack(|| {
+            let path = std::env::temp_dir().join(format!("yokoyboi-{}.gb", std::process::id()));
+            let mut rom = idle().rom();
+            rom[..4].copy_from_slice(&[0x31, 0xFE, 0xFF, 0xAF]);
+            std::fs::write(&path, &rom).unwrap();
+            let mut machine = machine_with_rom(Assembler::new(0x0150).rom());
+            machine.load_rom(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let first_bytes: Vec<u8> = (0..4)
+                .map(|address| machine.read_u8(Wrapping(address)).0)
+                .collect();
+            assert_eq!(first_bytes, [0x31, 0xFE, 0xFF, 0xAF]);
+        });
+    }
+
+    #[test]
+    fn boot_rom_must_be_256_bytes() {
+        assert!(boot_rom_from_bytes(vec![0; 0x100]).is_ok());
+        assert!(boot_rom_from_bytes(vec![0; 0xFF]).is_err());
+        assert!(boot_rom_from_bytes(vec![0; 0x101]).is_err());
+    }
+
+    #[test]
+    fn game_rom_must_not_be_empty() {
+        assert!(game_rom_from_bytes(Vec::new()).is_err());
+    }
 }
@@ -0,0 +1,25 @@
+use std::num::Wrapping;
+
+use crate::machine::Machine;
+
+/// Memory as seen by the CPU, for components that need nothing else of the machine, so that they
+/// can also run against a bus of their own.
+pub trait MemoryBus {
+    fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8>;
+    fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>);
+    fn request_interrupt(&mut self, interrupt_bit: u8);
+}
+
+impl MemoryBus for Machine {
+    fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        Machine::read_u8(self, address)
+    }
+
+    fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        Machine::write_u8(self, address, value)
+    }
+
+    fn request_interrupt(&mut self, interrupt_bit: u8) {
+        Machine::request_interrupt(self, interrupt_bit)
+    }
+}
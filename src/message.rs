@@ -5,4 +5,5 @@ pub enum Message {
     RunNextInstruction,
     BeginRunUntilBreakpoint,
     ContinueRunUntilBreakpoint,
+    Rewind,
 }
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+
+use crate::machine::Machine;
+
+/// Joypad state of consecutive frames, as `Inputs` button bitmasks.  Each entry holds the buttons
+/// pressed during a frame, as sampled when it ends upon entering VBlank.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct InputMovie {
+    pub frames: Vec<u8>,
+}
+
+impl Default for InputMovie {
+    fn default() -> Self {
+        InputMovie::new()
+    }
+}
+
+impl InputMovie {
+    pub fn new() -> Self {
+        InputMovie { frames: Vec::new() }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Movie {
+    Idle,
+    Recording {
+        movie: InputMovie,
+        /// Host input, which only reaches the joypad at the end of the frame, so that the game sees
+        /// it change at the same point when replaying the movie.
+        latched_buttons: u8,
+    },
+    Playing {
+        movie: InputMovie,
+        next_frame: usize,
+    },
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Movie::new()
+    }
+}
+
+impl Movie {
+    pub fn new() -> Self {
+        Movie::Idle
+    }
+}
+
+impl Machine {
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn movie_mut(&mut self) -> &mut Movie {
+        &mut self.movie
+    }
+
+    /// Starts recording the joypad state, dropping any movie being recorded or played.
+    pub fn start_recording(&mut self) {
+        *self.movie_mut() = Movie::Recording {
+            movie: InputMovie::new(),
+            latched_buttons: self.inputs().pressed_buttons(),
+        };
+    }
+
+    /// Stops recording, and applies the host input latched since the last frame.
+    pub fn stop_recording(&mut self) -> InputMovie {
+        match std::mem::replace(self.movie_mut(), Movie::Idle) {
+            Movie::Recording {
+                movie,
+                latched_buttons,
+            } => {
+                self.set_buttons(latched_buttons);
+                movie
+            }
+            movie => {
+                println!("WARNING: Stopping a recording while not recording");
+                *self.movie_mut() = movie;
+                InputMovie::new()
+            }
+        }
+    }
+
+    /// Replaces the joypad state with the one of `movie` until its last frame.  Starting from the
+    /// state the recording started from, this reproduces the recorded frames exactly.
+    pub fn play_movie(&mut self, movie: InputMovie) {
+        *self.movie_mut() = Movie::Playing {
+            movie,
+            next_frame: 0,
+        };
+        self.update_movie();
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(self.movie(), Movie::Playing { .. })
+    }
+
+    // Called upon entering VBlank, the joypad state of a movie only changes at frame boundaries.
+    // When recording, the frame that ends gets recorded, before the host input latched during it
+    // applies to the next one.
+    pub fn update_movie(&mut self) {
+        let buttons = self.inputs().pressed_buttons();
+        let next_buttons = match self.movie_mut() {
+            Movie::Idle => None,
+            Movie::Recording {
+                movie,
+                latched_buttons,
+            } => {
+                movie.frames.push(buttons);
+                Some(*latched_buttons)
+            }
+            Movie::Playing { movie, next_frame } => {
+                let played_buttons = movie.frames.get(*next_frame).copied();
+                *next_frame += 1;
+                played_buttons
+            }
+        };
+        match next_buttons {
+            Some(buttons) => self
+                .inputs
+                .set_pressed_buttons(buttons, &mut self.interrupts),
+            None if self.is_playing_movie() => *self.movie_mut() = Movie::Idle,
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        instructions::assembler::Assembler,
+        test_utils::{frame_hash, machine_running, with_large_stack},
+    };
+
+    const FRAMES: usize = 30;
+
+    // Shows the direction buttons as BGP, continuously, so that the frame tells when they changed
+    fn joypad_to_palette() -> Assembler {
+        Assembler::new(0x0150)
+            .label("loop")
+            .ld_a_u8(0x20)
+            .ldh_u8_a(0x00)
+            .ldh_a_u8(0x00)
+            .ldh_u8_a(0x47)
+            .jr_label("loop")
+    }
+
+    #[test]
+    fn replay_reproduces_recording() {
+        with_large_stack(|| {
+            let mut rng = StdRng::seed_from_u64(0x57);
+            let mut machine = machine_running(joypad_to_palette());
+            let mut replay = machine_running(joypad_to_palette());
+
+            machine.start_recording();
+            let mut frame_hashes = Vec::new();
+            for _ in 0..FRAMES {
+                // Host input arrives at any point of the frame
+                for _ in 0..rng.gen_range(0..2000) {
+                    machine.step();
+                }
+                machine.set_buttons(rng.gen());
+                machine.run_frames(1);
+                frame_hashes.push(frame_hash(&machine.ppu().frame_buffer));
+            }
+            let movie = machine.stop_recording();
+            assert_eq!(movie.frames.len(), FRAMES);
+
+            replay.play_movie(movie);
+            for (frame, hash) in frame_hashes.iter().enumerate() {
+                replay.run_frames(1);
+                assert_eq!(
+                    frame_hash(&replay.ppu().frame_buffer),
+                    *hash,
+                    "Frame {}",
+                    frame
+                );
+                // Host input is ignored while playing
+                replay.set_buttons(0xFF);
+            }
+            assert!(!replay.is_playing_movie());
+        });
+    }
+}
@@ -1,12 +1,18 @@
 pub mod background_or_window;
 pub mod object;
 
+use serde::{Deserialize, Serialize};
+
 use background_or_window::BackgroundOrWindowFetcher;
 use object::ObjectFetcher;
 
-use crate::ppu::PPU;
+use crate::ppu::{
+    decode_tile_row, HORIZONTAL_PIXELS_PER_TILE, PPU, TILE_COUNT, VERTICAL_PIXELS_PER_TILE,
+};
 
-#[derive(Clone, Debug)]
+const TILE_ROWS_PER_VRAM_BANK: usize = TILE_COUNT * VERTICAL_PIXELS_PER_TILE;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 enum FetcherState {
     GetTileDelay,
     GetTile,
@@ -17,30 +23,36 @@ enum FetcherState {
     PushRow,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FIFOItem {
     pub color: u8,
+    /// CGB background palette (0-7), always 0 outside of CGB mode.
+    pub palette: u8,
+    /// CGB background-to-object priority: when set, colors 1-3 are drawn over objects.
+    pub priority: bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum FetchingFor {
     BackgroundOrWindowFIFO,
     ObjectFIFO,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Fetcher {
     pub fetching_for: FetchingFor,
 }
 
 // Background and Window use one of these based on bit 4 of lcd_control.
 // Sprites always use UnsignedFrom0x8000.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum TileAddressingMode {
     UnsignedFrom0x8000,
     SignedFrom0x9000,
 }
 
+// Tiles are laid out from 0x8000, so in signed mode tile IDs 0x80-0xFF map to 0x8800-0x8FFF
+// (indices 128-255) and 0x00-0x7F to 0x9000-0x97FF (indices 256-383)
 pub fn get_tile_index_in_palette(tile_id: u8, addressing_mode: &TileAddressingMode) -> u16 {
     match addressing_mode {
         TileAddressingMode::UnsignedFrom0x8000 => tile_id as u16,
@@ -48,6 +60,52 @@ pub fn get_tile_index_in_palette(tile_id: u8, addressing_mode: &TileAddressingMo
     }
 }
 
+/// Tile rows decoded into pixel codes, indexed by VRAM bank, tile index in the palette, and row
+/// within the tile, so that tiles that did not change are not decoded again on every fetch.
+/// `PPU::write_vram` invalidates the rows it touches.
+#[derive(Clone, Debug)]
+pub struct TileRowCache {
+    rows: Vec<Option<[u8; HORIZONTAL_PIXELS_PER_TILE]>>,
+}
+
+impl Default for TileRowCache {
+    fn default() -> Self {
+        TileRowCache::new()
+    }
+}
+
+impl TileRowCache {
+    pub fn new() -> Self {
+        TileRowCache {
+            rows: vec![None; 2 * TILE_ROWS_PER_VRAM_BANK],
+        }
+    }
+
+    // Each row takes two bytes, one per bit plane.  Tile maps are past the last row, and ignored.
+    pub fn invalidate(&mut self, vram_bank: u8, vram_address: usize) {
+        let row_index = vram_address / 2;
+        if row_index < TILE_ROWS_PER_VRAM_BANK {
+            self.rows[vram_bank as usize * TILE_ROWS_PER_VRAM_BANK + row_index] = None;
+        }
+    }
+
+    pub fn row(
+        &mut self,
+        vram: &[u8],
+        vram_bank: u8,
+        row_index: usize,
+    ) -> [u8; HORIZONTAL_PIXELS_PER_TILE] {
+        *self.rows[vram_bank as usize * TILE_ROWS_PER_VRAM_BANK + row_index]
+            .get_or_insert_with(|| decode_tile_row(vram[row_index * 2], vram[row_index * 2 + 1]))
+    }
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Fetcher::new()
+    }
+}
+
 impl Fetcher {
     pub fn new() -> Self {
         Fetcher {
@@ -75,31 +133,35 @@ impl Fetcher {
     }
 
     pub fn read_tile_row(
-        vram: &[u8],
+        ppu: &mut PPU,
+        vram_bank: u8,
         addressing_mode: &TileAddressingMode,
         current_line: u8,
         tile_id: u8,
         bit_plane: bool,
         tile_row_data: &mut [u8],
     ) {
-        // WARNING: when handling sprites, will need to update this to ignore addressing mode for
-        // their tiles
-
         // NOTE: rather than going through the MMU again with an absolute address, I'm computing the
         // address relative to VRAM and reading directly from the VRAM slice.  Should be slightly
         // faster as you don't need to perform range checks to realize you're heading into VRAM.
         let tile_index_in_palette = get_tile_index_in_palette(tile_id, addressing_mode);
-        let row_of_pixel_within_tile = (current_line & 255) % 8;
-        let address_in_vram_slice =
-            tile_index_in_palette * 16 + (row_of_pixel_within_tile as u16) * 2;
-        let pixel_data = vram[address_in_vram_slice as usize + bit_plane as usize];
-        // We just finished reading one byte.  Each bit is half of a pixel value, we coalesce them
-        // here Note: This assumes that `tile_row_data` is cleared at each loop.
-        // Note: it's nice to have the row data be sorted by increasing X, but the lowest bit
-        // position is the highest X pixel, so using (7 - bit_position) to reorder.
-        for bit_position in 0..8 {
-            tile_row_data[7 - bit_position] |=
-                ((pixel_data >> bit_position) & 1) << (bit_plane as u8);
+        let row_of_pixel_within_tile = current_line % 8;
+        let row_index = tile_index_in_palette as usize * VERTICAL_PIXELS_PER_TILE
+            + row_of_pixel_within_tile as usize;
+        // Each fetch step only contributes the bit of its own plane, so that VRAM writes between
+        // the low and high fetches are seen.  This assumes that `tile_row_data` is cleared at each
+        // loop.
+        let plane_mask = 1 << (bit_plane as u8);
+        let vram = if vram_bank == 0 {
+            &ppu.vram
+        } else {
+            &ppu.vram_1
+        };
+        for (pixel_code, decoded) in tile_row_data
+            .iter_mut()
+            .zip(ppu.tile_row_cache.row(vram, vram_bank, row_index))
+        {
+            *pixel_code |= decoded & plane_mask;
         }
     }
 
@@ -119,3 +181,55 @@ impl Fetcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        test_utils::{idle, machine_running, with_large_stack},
+    };
+
+    use super::TileRowCache;
+
+    #[test]
+    fn cached_rows_are_kept_until_invalidated() {
+        let mut vram = vec![0; 0x2000];
+        let mut cache = TileRowCache::new();
+        // Row 1 of tile 0, low bit plane
+        vram[2] = 0xFF;
+        assert_eq!(cache.row(&vram, 0, 1), [1; 8]);
+        vram[3] = 0xFF;
+        assert_eq!(cache.row(&vram, 0, 1), [1; 8]);
+        // Either byte of the row invalidates it, in its own VRAM bank only
+        cache.invalidate(1, 3);
+        assert_eq!(cache.row(&vram, 0, 1), [1; 8]);
+        cache.invalidate(0, 3);
+        assert_eq!(cache.row(&vram, 0, 1), [3; 8]);
+    }
+
+    // Rewrites tile 0 of the given tile data area with the LCD off, and renders it
+    fn render_tile_0(machine: &mut Machine, lcdc: u8, tile_data: u16, row: [u8; 2]) -> u8 {
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0x00));
+        for offset in 0..16 {
+            let address = Wrapping(tile_data + offset);
+            machine.write_u8(address, Wrapping(row[offset as usize % 2]));
+        }
+        machine.write_u8(Wrapping(0xFF40), Wrapping(lcdc));
+        machine.run_frames(2);
+        machine.ppu().frame_buffer[0]
+    }
+
+    #[test]
+    fn rendering_sees_tiles_rewritten_in_either_addressing_mode() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            machine.write_u8(Wrapping(0xFF47), Wrapping(0xE4));
+            assert_eq!(render_tile_0(&mut machine, 0x91, 0x8000, [0xFF, 0x00]), 1);
+            assert_eq!(render_tile_0(&mut machine, 0x91, 0x8000, [0x00, 0xFF]), 2);
+            assert_eq!(render_tile_0(&mut machine, 0x81, 0x9000, [0xFF, 0xFF]), 3);
+            assert_eq!(render_tile_0(&mut machine, 0x81, 0x9000, [0xFF, 0x00]), 1);
+        });
+    }
+}
@@ -1,20 +1,70 @@
-use std::{collections::VecDeque, num::Wrapping};
+use std::collections::VecDeque;
 
-use crate::{
-    ppu::{LCDC_BACKGROUND_TILE_MAP_AREA_BIT, PPU, TILE_MAP_HORIZONTAL_TILE_COUNT},
-    utils,
+use serde::{Deserialize, Serialize};
+
+use crate::ppu::{
+    PPU, TILE_MAP0_VRAM_OFFSET, TILE_MAP1_VRAM_OFFSET, TILE_MAP_HORIZONTAL_TILE_COUNT,
 };
 
 use super::{FIFOItem, Fetcher, FetcherState};
 
-#[derive(Clone, Debug)]
+const BACKGROUND_ATTRIBUTE_PRIORITY_BIT: u8 = 7;
+const BACKGROUND_ATTRIBUTE_Y_FLIP_BIT: u8 = 6;
+const BACKGROUND_ATTRIBUTE_X_FLIP_BIT: u8 = 5;
+const BACKGROUND_ATTRIBUTE_VRAM_BANK_BIT: u8 = 3;
+const BACKGROUND_ATTRIBUTE_PALETTE_MASK: u8 = 0b111;
+
+/// CGB attributes of a background or window tile, stored in VRAM bank 1 at the same tile map
+/// address as the tile ID.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct BackgroundAttributes {
+    pub attributes: u8,
+}
+
+impl BackgroundAttributes {
+    pub fn has_priority(&self) -> bool {
+        (self.attributes >> BACKGROUND_ATTRIBUTE_PRIORITY_BIT) & 1 == 1
+    }
+
+    pub fn is_y_flipped(&self) -> bool {
+        (self.attributes >> BACKGROUND_ATTRIBUTE_Y_FLIP_BIT) & 1 == 1
+    }
+
+    pub fn is_x_flipped(&self) -> bool {
+        (self.attributes >> BACKGROUND_ATTRIBUTE_X_FLIP_BIT) & 1 == 1
+    }
+
+    pub fn vram_bank(&self) -> u8 {
+        (self.attributes >> BACKGROUND_ATTRIBUTE_VRAM_BANK_BIT) & 1
+    }
+
+    pub fn palette(&self) -> u8 {
+        self.attributes & BACKGROUND_ATTRIBUTE_PALETTE_MASK
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BackgroundOrWindowFetcher {
     state: FetcherState,
     pub fifo: VecDeque<FIFOItem>,
     pub row_of_pixel_within_tile: u8,
     tile_id: u8,
+    /// Always 0 outside of CGB mode, so that DMG tiles are fetched from bank 0, unflipped.
+    tile_attributes: BackgroundAttributes,
     pub vram_tile_column: u8,
     tile_row_data: [u8; 8],
+    /// Set once the window has been reached on the current row, at which point the background is
+    /// not fetched anymore until the next row.
+    fetching_window: bool,
+    /// The window has its own line counter, which only advances on rows where the window was
+    /// actually drawn.
+    window_line_counter: u8,
+}
+
+impl Default for BackgroundOrWindowFetcher {
+    fn default() -> Self {
+        BackgroundOrWindowFetcher::new()
+    }
 }
 
 impl BackgroundOrWindowFetcher {
@@ -24,8 +74,11 @@ impl BackgroundOrWindowFetcher {
             fifo: VecDeque::new(),
             row_of_pixel_within_tile: 0,
             tile_id: 0,
+            tile_attributes: BackgroundAttributes { attributes: 0 },
             vram_tile_column: 0,
             tile_row_data: [0; 8],
+            fetching_window: false,
+            window_line_counter: 0,
         }
     }
 
@@ -35,6 +88,8 @@ impl BackgroundOrWindowFetcher {
         self.row_of_pixel_within_tile = 0;
         self.vram_tile_column = 0;
         self.tile_row_data = [0; 8];
+        self.fetching_window = false;
+        self.window_line_counter = 0;
     }
 
     pub fn prepare_for_new_row(&mut self) {
@@ -43,6 +98,53 @@ impl BackgroundOrWindowFetcher {
         self.row_of_pixel_within_tile = 0;
         self.vram_tile_column = 0;
         self.tile_row_data = [0; 8];
+        if self.fetching_window {
+            self.window_line_counter += 1;
+        }
+        self.fetching_window = false;
+    }
+
+    pub fn is_fetching_window(&self) -> bool {
+        self.fetching_window
+    }
+
+    // Discards the background pipeline, FIFO included, and starts over from the leftmost window
+    // tile
+    pub fn restart_for_window(&mut self) {
+        self.state = FetcherState::GetTileDelay;
+        self.fifo.clear();
+        self.vram_tile_column = 0;
+        self.tile_row_data = [0; 8];
+        self.fetching_window = true;
+    }
+
+    // The pixel row being fetched, within the 256x256 pixels of the tile map
+    fn tile_map_pixel_row(&self, ppu: &PPU) -> u8 {
+        if self.fetching_window {
+            self.window_line_counter
+        } else {
+            // Wraps around the bottom of the tile map, as LY + SCY is mod 256
+            ppu.ly().0.wrapping_add(ppu.scy.0)
+        }
+    }
+
+    // Flipping vertically mirrors the row within the tile, which is `current_line % 8`
+    fn read_tile_row(&mut self, ppu: &mut PPU, bit_plane: bool) {
+        let addressing_mode = ppu.get_addressing_mode();
+        let current_line = if self.tile_attributes.is_y_flipped() {
+            self.tile_map_pixel_row(ppu) ^ 0b111
+        } else {
+            self.tile_map_pixel_row(ppu)
+        };
+        Fetcher::read_tile_row(
+            ppu,
+            self.tile_attributes.vram_bank(),
+            &addressing_mode,
+            current_line,
+            self.tile_id,
+            bit_plane,
+            &mut self.tile_row_data,
+        );
     }
 
     pub fn tick(&mut self, ppu: &mut PPU) {
@@ -50,32 +152,43 @@ impl BackgroundOrWindowFetcher {
             FetcherState::GetTileDelay => self.state = FetcherState::GetTile,
 
             FetcherState::GetTile => {
-                // NOTE: Because the following operations are done via Wrapping at u8, they
-                // automatically perform the necessary "mod 256"
-                let vram_pixel_row = (ppu.read_ly() + ppu.scy).0;
-                let vram_pixel_col = (Wrapping(self.vram_tile_column) * Wrapping(8) + ppu.scx).0;
-
-                let tile_row = vram_pixel_row / 8;
-                let tile_col = vram_pixel_col / 8;
+                let tile_row = self.tile_map_pixel_row(ppu) / 8;
+                let (tile_col, uses_tile_map1) = if self.fetching_window {
+                    (self.vram_tile_column, ppu.is_window_using_tile_map1())
+                } else {
+                    // (8 * column + SCX) mod 256 is in tile column (column + SCX / 8) mod 32, the
+                    // remaining SCX % 8 pixels get discarded from the FIFO instead
+                    (
+                        (self.vram_tile_column + ppu.scx.0 / 8) % 32,
+                        ppu.is_background_using_tile_map1(),
+                    )
+                };
 
                 let tile_index_in_its_tile_map =
                     tile_row as usize * TILE_MAP_HORIZONTAL_TILE_COUNT + tile_col as usize;
 
-                // FIXME: more complex rules for the row base address
-                let vram_base_address =
-                    if utils::is_bit_set(&ppu.lcd_control, LCDC_BACKGROUND_TILE_MAP_AREA_BIT) {
-                        ppu.tile_map0_last_addressing_modes[tile_index_in_its_tile_map] =
-                            ppu.get_addressing_mode();
-                        0x1C00 // 0x9C00, but VRAM starts at 0x8000
-                    } else {
-                        ppu.tile_map1_last_addressing_modes[tile_index_in_its_tile_map] =
-                            ppu.get_addressing_mode();
-                        0x1800 // 0x9800, but VRAM starts at 0x8000
-                    };
+                // LCDC bit 3 (background) or 6 (window) selects the tile map, while the tile data
+                // the ID refers to depends on LCDC bit 4, see `PPU::get_addressing_mode`
+                let vram_base_address = if uses_tile_map1 {
+                    ppu.tile_map1_last_addressing_modes[tile_index_in_its_tile_map] =
+                        ppu.get_addressing_mode();
+                    TILE_MAP1_VRAM_OFFSET
+                } else {
+                    ppu.tile_map0_last_addressing_modes[tile_index_in_its_tile_map] =
+                        ppu.get_addressing_mode();
+                    TILE_MAP0_VRAM_OFFSET
+                };
 
-                let row_address = vram_base_address + ((tile_row as u16) << 5) + (tile_col as u16);
+                let row_address = vram_base_address + tile_index_in_its_tile_map;
 
-                self.tile_id = ppu.vram[row_address as usize];
+                self.tile_id = ppu.vram[row_address];
+                self.tile_attributes = BackgroundAttributes {
+                    attributes: if ppu.cgb_mode {
+                        ppu.vram_1[row_address]
+                    } else {
+                        0
+                    },
+                };
                 self.state = FetcherState::GetTileDataLowDelay;
             }
 
@@ -84,15 +197,7 @@ impl BackgroundOrWindowFetcher {
             }
 
             FetcherState::GetTileDataLow => {
-                let ly = ppu.read_ly();
-                Fetcher::read_tile_row(
-                    &ppu.vram,
-                    &ppu.get_addressing_mode(),
-                    (ly + ppu.scy).0,
-                    self.tile_id,
-                    false,
-                    &mut self.tile_row_data,
-                );
+                self.read_tile_row(ppu, false);
                 self.state = FetcherState::GetTileDataHighDelay;
             }
 
@@ -101,15 +206,10 @@ impl BackgroundOrWindowFetcher {
             }
 
             FetcherState::GetTileDataHigh => {
-                let ly = ppu.read_ly();
-                Fetcher::read_tile_row(
-                    &ppu.vram,
-                    &ppu.get_addressing_mode(),
-                    (ly + ppu.scy).0,
-                    self.tile_id,
-                    true,
-                    &mut self.tile_row_data,
-                );
+                self.read_tile_row(ppu, true);
+                if self.tile_attributes.is_x_flipped() {
+                    self.tile_row_data.reverse();
+                }
                 self.state = FetcherState::PushRow;
             }
 
@@ -118,7 +218,11 @@ impl BackgroundOrWindowFetcher {
                 if self.fifo.len() == 0 {
                     for i in 0..8 {
                         let color = self.tile_row_data[i];
-                        self.fifo.push_back(FIFOItem { color });
+                        self.fifo.push_back(FIFOItem {
+                            color,
+                            palette: self.tile_attributes.palette(),
+                            priority: self.tile_attributes.has_priority(),
+                        });
                     }
                     self.vram_tile_column += 1;
                     // clean up so that GetTileData can assume 0
@@ -129,3 +233,230 @@ impl BackgroundOrWindowFetcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        test_utils::{frame_hash, idle, machine_running, with_large_stack},
+    };
+
+    fn write(machine: &mut Machine, address: u16, value: u8) {
+        machine.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    // Renders distinct tiles scrolled so that the tile map wraps both ways, with the window over
+    // the bottom right corner
+    fn rendered_frame_hash(lcd_control: u8) -> u64 {
+        let mut machine = machine_running(idle());
+        write(&mut machine, 0xFF40, 0x00);
+        for address in 0x8000..0xA000u16 {
+            let value = (address ^ (address >> 5) ^ (address >> 11)) as u8;
+            write(&mut machine, address, value);
+        }
+        write(&mut machine, 0xFF47, 0xE4);
+        write(&mut machine, 0xFF42, 0xC5);
+        write(&mut machine, 0xFF43, 0x9D);
+        write(&mut machine, 0xFF4A, 0x60);
+        write(&mut machine, 0xFF4B, 0x57);
+        write(&mut machine, 0xFF40, lcd_control);
+        // The first frame after turning the LCD on stays blank
+        machine.run_frames(2);
+        frame_hash(&machine.ppu().frame_buffer)
+    }
+
+    #[test]
+    fn rendered_frames_are_unchanged() {
+        with_large_stack(|| {
+            assert_eq!(rendered_frame_hash(0xF1), 0x948E5F3FB10ABC7D);
+            assert_eq!(rendered_frame_hash(0xE1), 0xB7504FE03D444421);
+        });
+    }
+
+    #[test]
+    fn full_screen_window_samples_the_window_tile_map() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // Tile 0 is all color 0, tile 1 all color 3, filling the background and window maps
+            for address in 0x8000..0x8010 {
+                write(&mut machine, address, 0x00);
+            }
+            for address in 0x8010..0x8020 {
+                write(&mut machine, address, 0xFF);
+            }
+            for address in 0x9800..0x9C00 {
+                write(&mut machine, address, 0x00);
+            }
+            for address in 0x9C00..0xA000 {
+                write(&mut machine, address, 0x01);
+            }
+            write(&mut machine, 0xFF47, 0xE4);
+            write(&mut machine, 0xFF4A, 0);
+            write(&mut machine, 0xFF4B, 7);
+            write(&mut machine, 0xFF40, 0xF1);
+            machine.run_frames(2);
+            assert!(machine.ppu().frame_buffer.iter().all(|shade| *shade == 3));
+
+            // Without the window, the background map shows
+            write(&mut machine, 0xFF40, 0xD1);
+            machine.run_frames(2);
+            assert!(machine.ppu().frame_buffer.iter().all(|shade| *shade == 0));
+        });
+    }
+
+    // Shade of the background made of tile `tile_id` only, with tiles 0x00 at 0x8000 of color 1,
+    // 0x80 at 0x8800 of color 2, and 0x00 at 0x9000 of color 3
+    fn background_shade(lcd_control: u8, tile_id: u8) -> u8 {
+        let mut machine = machine_running(idle());
+        write(&mut machine, 0xFF40, 0x00);
+        for (base, row) in [
+            (0x8000, [0xFF, 0x00]),
+            (0x8800, [0x00, 0xFF]),
+            (0x9000, [0xFF, 0xFF]),
+        ] {
+            for offset in 0..16 {
+                write(&mut machine, base + offset, row[offset as usize % 2]);
+            }
+        }
+        for address in 0x9800..0x9C00 {
+            write(&mut machine, address, tile_id);
+        }
+        write(&mut machine, 0xFF47, 0xE4);
+        write(&mut machine, 0xFF40, lcd_control);
+        machine.run_frames(2);
+        let frame_buffer = &machine.ppu().frame_buffer;
+        assert!(frame_buffer.iter().all(|shade| *shade == frame_buffer[0]));
+        frame_buffer[0]
+    }
+
+    #[test]
+    fn lcdc_bit_4_selects_the_tile_data_addressing() {
+        with_large_stack(|| {
+            assert_eq!(background_shade(0x91, 0x00), 1);
+            assert_eq!(background_shade(0x91, 0x80), 2);
+            // Tile IDs are signed, relative to 0x9000
+            assert_eq!(background_shade(0x81, 0x00), 3);
+            assert_eq!(background_shade(0x81, 0x80), 2);
+        });
+    }
+
+    #[test]
+    fn scx_fine_scroll_discards_leading_pixels() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // Every row of tile 0 shows colors 0, 1, 2, 3, 3, 2, 1, 0
+            for offset in 0..16 {
+                write(
+                    &mut machine,
+                    0x8000 + offset,
+                    [0x5A, 0x3C][offset as usize % 2],
+                );
+            }
+            for address in 0x9800..0x9C00 {
+                write(&mut machine, address, 0x00);
+            }
+            write(&mut machine, 0xFF47, 0xE4);
+            write(&mut machine, 0xFF42, 0);
+            write(&mut machine, 0xFF43, 3);
+            write(&mut machine, 0xFF40, 0x91);
+            machine.run_frames(2);
+            let pattern = [0, 1, 2, 3, 3, 2, 1, 0];
+            for row in machine.ppu().frame_buffer.chunks(160) {
+                for (x, shade) in row.iter().enumerate() {
+                    assert_eq!(*shade, pattern[(x + 3) % 8], "at x = {}", x);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn window_at_wx_50_takes_over_from_x_43() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // Every row of tile 0 shows colors 0, 1, 2, 3, 3, 2, 1, 0, and of tile 1 four pixels of
+            // color 0 then four of color 1
+            for offset in 0..16 {
+                write(
+                    &mut machine,
+                    0x8000 + offset,
+                    [0x5A, 0x3C][offset as usize % 2],
+                );
+                write(
+                    &mut machine,
+                    0x8010 + offset,
+                    [0x0F, 0x00][offset as usize % 2],
+                );
+            }
+            for address in 0x9800..0x9C00 {
+                write(&mut machine, address, 0x00);
+            }
+            for address in 0x9C00..0xA000 {
+                write(&mut machine, address, 0x01);
+            }
+            write(&mut machine, 0xFF47, 0xE4);
+            write(&mut machine, 0xFF42, 0);
+            // The background fine scroll does not shift the window
+            write(&mut machine, 0xFF43, 3);
+            write(&mut machine, 0xFF4A, 0);
+            write(&mut machine, 0xFF4B, 50);
+            write(&mut machine, 0xFF40, 0xF1);
+            machine.run_frames(2);
+            let background = [0, 1, 2, 3, 3, 2, 1, 0];
+            let window = [0, 0, 0, 0, 1, 1, 1, 1];
+            for row in machine.ppu().frame_buffer.chunks(160) {
+                for (x, shade) in row.iter().enumerate() {
+                    let expected = if x < 43 {
+                        background[(x + 3) % 8]
+                    } else {
+                        window[(x - 43) % 8]
+                    };
+                    assert_eq!(*shade, expected, "at x = {}", x);
+                }
+            }
+        });
+    }
+
+    fn tick_until_hblank_of_line(machine: &mut Machine, ly: u8) {
+        while machine.ppu().read_ly().0 != ly || machine.ppu().mode() != 0 {
+            machine.tick(1);
+        }
+    }
+
+    #[test]
+    fn scy_written_each_hblank_shears_the_next_lines() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // Rows 0-1 of tile 0 are of color 0, rows 2-3 of color 1, and so on
+            for row in 0..8 {
+                let color = row / 2;
+                write(&mut machine, 0x8000 + row * 2, 0xFF * (color & 1) as u8);
+                write(&mut machine, 0x8001 + row * 2, 0xFF * (color >> 1) as u8);
+            }
+            for address in 0x9800..0x9C00 {
+                write(&mut machine, address, 0x00);
+            }
+            write(&mut machine, 0xFF47, 0xE4);
+            write(&mut machine, 0xFF43, 0);
+            write(&mut machine, 0xFF40, 0x91);
+            // The first frame after turning the LCD on stays blank
+            machine.run_frames(1);
+            // Line y shows background row 2 * y
+            write(&mut machine, 0xFF42, 0);
+            for ly in 0..143 {
+                tick_until_hblank_of_line(&mut machine, ly);
+                write(&mut machine, 0xFF42, ly + 1);
+            }
+            machine.run_frames(1);
+            for (y, row) in machine.ppu().frame_buffer.chunks(160).enumerate() {
+                let expected = (2 * y % 8) as u8 / 2;
+                assert!(row.iter().all(|shade| *shade == expected), "at y = {}", y);
+            }
+        });
+    }
+}
@@ -1,24 +1,28 @@
-use std::{
-    cmp::{max, min},
-    collections::VecDeque,
-};
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
 
 use crate::ppu::PPU;
 
 use super::{Fetcher, TileAddressingMode};
 
-#[derive(Clone, Debug)]
+const OBJECT_ATTRIBUTE_BACKGROUND_PRIORITY_BIT: u8 = 7;
+const OBJECT_ATTRIBUTE_Y_FLIP_BIT: u8 = 6;
+const OBJECT_ATTRIBUTE_X_FLIP_BIT: u8 = 5;
+const OBJECT_ATTRIBUTE_PALETTE_BIT: u8 = 4;
+
+// Unlike the background fetcher, the object fetcher pushes its row as soon as it has the data, so
+// that a fetch takes 6 dots, the first one being spent switching to it
+#[derive(Clone, Debug, Deserialize, Serialize)]
 enum FetcherState {
-    GetTileDelay,
-    GetTile,
-    GetTileDataLowDelay,
-    GetTileDataLow,
-    GetTileDataHighDelay,
-    GetTileDataHigh,
-    PushRow,
+    TileIndex,
+    DataLowDelay,
+    DataLow,
+    DataHighDelay,
+    DataHigh,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Sprite {
     pub attributes: u8,
     pub tile_index: u8,
@@ -26,156 +30,439 @@ pub struct Sprite {
     pub y_screen_plus_16: u8,
 }
 
-#[derive(Clone, Debug)]
+/// An OAM entry, with its attributes decoded, as displayed by debuggers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpriteEntry {
+    /// Horizontal position plus 8, as stored in OAM.
+    pub x: u8,
+    /// Vertical position plus 16, as stored in OAM.
+    pub y: u8,
+    pub tile: u8,
+    pub background_priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub palette: ObjectPalette,
+}
+
+impl Sprite {
+    // OAM entries are 4 bytes: Y, X, tile index, and attributes
+    pub fn from_oam_entry(entry: &[u8]) -> Self {
+        Sprite {
+            y_screen_plus_16: entry[0],
+            x_screen_plus_8: entry[1],
+            tile_index: entry[2],
+            attributes: entry[3],
+        }
+    }
+
+    pub fn entry(&self) -> SpriteEntry {
+        SpriteEntry {
+            x: self.x_screen_plus_8,
+            y: self.y_screen_plus_16,
+            tile: self.tile_index,
+            background_priority: self.has_background_priority(),
+            y_flip: self.is_y_flipped(),
+            x_flip: self.is_x_flipped(),
+            palette: self.palette(),
+        }
+    }
+
+    pub fn has_background_priority(&self) -> bool {
+        (self.attributes >> OBJECT_ATTRIBUTE_BACKGROUND_PRIORITY_BIT) & 1 == 1
+    }
+
+    pub fn is_y_flipped(&self) -> bool {
+        (self.attributes >> OBJECT_ATTRIBUTE_Y_FLIP_BIT) & 1 == 1
+    }
+
+    pub fn is_x_flipped(&self) -> bool {
+        (self.attributes >> OBJECT_ATTRIBUTE_X_FLIP_BIT) & 1 == 1
+    }
+
+    pub fn palette(&self) -> ObjectPalette {
+        match (self.attributes >> OBJECT_ATTRIBUTE_PALETTE_BIT) & 1 {
+            0b0 => ObjectPalette::ObjectPalette0,
+            0b1 => ObjectPalette::ObjectPalette1,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum ObjectPalette {
     ObjectPalette0,
     ObjectPalette1,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ObjectFIFOItem {
     pub color: u8,
     pub palette: ObjectPalette,
+    /// When set, background and window colors 1-3 are drawn over this pixel.
+    pub background_priority: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ObjectFetcher {
     state: FetcherState,
     pub fifo: VecDeque<ObjectFIFOItem>,
+    /// The object currently being fetched, if any.
     sprite: Option<Sprite>,
+    /// The LCD column at which the current object fetch was started.
     pub pixel_index_in_row: u8,
+    /// The background tile (counted from the left of the tile map row) for which an object fetch
+    /// last waited on the background fetcher, on the current row.
+    pub waited_background_tile: Option<u8>,
+    tile_id: u8,
     tile_row_data: [u8; 8],
+    /// Objects selected during OAM scan that have not been fetched yet, in OAM order.
     pub selected_objects: VecDeque<Sprite>,
 }
 
-pub fn inclusive_ranges_overlap((s1, e1): (i16, i16), (s2, e2): (i16, i16)) -> bool {
-    max(s1, s2) <= min(e1, e2)
+impl Default for ObjectFetcher {
+    fn default() -> Self {
+        ObjectFetcher::new()
+    }
 }
 
 impl ObjectFetcher {
     pub fn new() -> Self {
         ObjectFetcher {
-            state: FetcherState::GetTileDelay,
+            state: FetcherState::TileIndex,
             fifo: VecDeque::new(),
             sprite: None,
             pixel_index_in_row: 0,
+            waited_background_tile: None,
+            tile_id: 0,
             tile_row_data: [0; 8],
             selected_objects: VecDeque::new(),
         }
     }
 
     pub fn prepare_for_new_row(&mut self) {
-        self.state = FetcherState::GetTileDelay;
+        self.state = FetcherState::TileIndex;
         self.fifo.clear();
+        self.sprite = None;
         self.tile_row_data = [0; 8];
         self.pixel_index_in_row = 0;
+        self.waited_background_tile = None;
     }
 
     pub fn prepare_for_new_frame(&mut self) {
-        self.state = FetcherState::GetTileDelay;
+        self.state = FetcherState::TileIndex;
         self.fifo.clear();
+        self.sprite = None;
         self.pixel_index_in_row = 0;
+        self.waited_background_tile = None;
+    }
+
+    pub fn is_fetching(&self) -> bool {
+        self.sprite.is_some()
+    }
+
+    /// Removes and returns the first selected object (in OAM order) whose left edge has been
+    /// reached by the LCD column `pixel_x`.  Because objects get fetched in order of X, and then
+    /// of OAM index, and because already pushed pixels win over later ones, this implements the
+    /// DMG object-to-object priority.
+    pub fn take_object_reached_at(&mut self, pixel_x: u8) -> Option<Sprite> {
+        let index = self
+            .selected_objects
+            .iter()
+            .position(|sprite| sprite.x_screen_plus_8 as u16 <= pixel_x as u16 + 8)?;
+        self.selected_objects.remove(index)
+    }
+
+    pub fn start_fetching(&mut self, sprite: Sprite, pixel_x: u8) {
+        self.state = FetcherState::TileIndex;
+        self.sprite = Some(sprite);
+        self.pixel_index_in_row = pixel_x;
+        self.tile_row_data = [0; 8];
+    }
+
+    // The row of the object being fetched that intersects the current scanline.  With Y-flip, the
+    // whole object is mirrored, so in 8x16 mode this also swaps which of the two tiles is used.
+    // The OAM scan selected the object for the height at the time, which LCDC may have changed
+    // since: like hardware, only as many low bits of the row as the current height has are used.
+    fn row_within_object(&self, ppu: &PPU, sprite: &Sprite) -> u8 {
+        let object_height = ppu.object_height();
+        let object_top = sprite.y_screen_plus_16 as i16 - 16;
+        let row = (ppu.ly().0 as i16 - object_top) as u8 & (object_height - 1);
+        if sprite.is_y_flipped() {
+            object_height - 1 - row
+        } else {
+            row
+        }
     }
 
     pub fn tick(&mut self, ppu: &mut PPU) {
+        let Some(sprite) = self.sprite.clone() else {
+            return;
+        };
+
         match self.state {
-            FetcherState::GetTileDelay => self.state = FetcherState::GetTile,
-
-            FetcherState::GetTile => {
-                let current_x = self.pixel_index_in_row as i16;
-                let x_range = (current_x, current_x + 7);
-                let selected = &self.selected_objects;
-
-                // Technically we should only tick this when there is going to be a match
-                self.sprite = selected
-                    .iter()
-                    .find(|item| {
-                        let item_x_screen = item.x_screen_plus_8 as u16 as i16 - 8;
-                        inclusive_ranges_overlap(x_range, (item_x_screen, item_x_screen + 7))
-                    })
-                    .map(|i| i.clone());
-
-                self.state = FetcherState::GetTileDataLowDelay
+            FetcherState::TileIndex => {
+                // In 8x16 mode, the top tile is at the even index and the bottom one follows it
+                self.tile_id = if ppu.object_height() == 16 {
+                    let row = self.row_within_object(ppu, &sprite);
+                    (sprite.tile_index & 0xFE) | (row / 8)
+                } else {
+                    sprite.tile_index
+                };
+                self.state = FetcherState::DataLowDelay
             }
 
-            FetcherState::GetTileDataLowDelay => self.state = FetcherState::GetTileDataLow,
-
-            FetcherState::GetTileDataLow => {
-                let ly = ppu.read_ly();
-                match self.sprite.clone() {
-                    Some(sprite) => Fetcher::read_tile_row(
-                        &ppu.vram,
-                        &TileAddressingMode::UnsignedFrom0x8000,
-                        (ly + ppu.scy).0,
-                        sprite.tile_index,
-                        false,
-                        &mut self.tile_row_data,
-                    ),
-                    None => {
-                        self.tile_row_data = [0; 8];
-                    }
-                }
-                self.state = FetcherState::GetTileDataHighDelay
+            FetcherState::DataLowDelay => self.state = FetcherState::DataLow,
+
+            FetcherState::DataLow => {
+                let row_within_object = self.row_within_object(ppu, &sprite);
+                Fetcher::read_tile_row(
+                    ppu,
+                    0,
+                    &TileAddressingMode::UnsignedFrom0x8000,
+                    row_within_object,
+                    self.tile_id,
+                    false,
+                    &mut self.tile_row_data,
+                );
+                self.state = FetcherState::DataHighDelay
             }
 
-            FetcherState::GetTileDataHighDelay => self.state = FetcherState::GetTileDataHigh,
-
-            FetcherState::GetTileDataHigh => {
-                let ly = ppu.read_ly();
-                match self.sprite.clone() {
-                    Some(sprite) => Fetcher::read_tile_row(
-                        &ppu.vram,
-                        &TileAddressingMode::UnsignedFrom0x8000,
-                        (ly + ppu.scy).0,
-                        sprite.tile_index,
-                        true,
-                        &mut self.tile_row_data,
-                    ),
-                    None => {
-                        self.tile_row_data = [0; 8];
-                    }
+            FetcherState::DataHighDelay => self.state = FetcherState::DataHigh,
+
+            FetcherState::DataHigh => {
+                let row_within_object = self.row_within_object(ppu, &sprite);
+                Fetcher::read_tile_row(
+                    ppu,
+                    0,
+                    &TileAddressingMode::UnsignedFrom0x8000,
+                    row_within_object,
+                    self.tile_id,
+                    true,
+                    &mut self.tile_row_data,
+                );
+                if sprite.is_x_flipped() {
+                    self.tile_row_data.reverse();
                 }
-                self.state = FetcherState::PushRow
-            }
 
-            FetcherState::PushRow => {
-                let obj_fifo_len = self.fifo.len();
+                // Objects hanging off the left edge of the screen only push their visible columns
+                let hidden_columns = (self.pixel_index_in_row as usize + 8)
+                    .saturating_sub(sprite.x_screen_plus_8 as usize)
+                    .min(8);
                 // Object FIFO pixels are merged with existing object FIFO pixels:
                 // Those with ID 0 are overwritten by latter ones, otherwise the existing one wins
-                for i in 0..8 {
-                    if i < obj_fifo_len {
+                for (i, color) in self.tile_row_data[hidden_columns..].iter().enumerate() {
+                    let item = ObjectFIFOItem {
+                        color: *color,
+                        palette: sprite.palette(),
+                        background_priority: sprite.has_background_priority(),
+                    };
+                    match self.fifo.get_mut(i) {
                         // Pixel merging following OBJ-to-OBJ priority
-                        let old_item = self.fifo[i].clone();
-                        if old_item.color == 0 {
-                            self.fifo[i] = ObjectFIFOItem {
-                                color: self.tile_row_data[i],
-                                palette: palette_for_sprite(self.sprite.as_ref()),
-                            };
+                        Some(old_item) => {
+                            if old_item.color == 0 {
+                                *old_item = item;
+                            }
                         }
-                    } else {
                         // No pixel to merge with, just push
-                        let color = self.tile_row_data[i];
-                        self.fifo.push_back(ObjectFIFOItem {
-                            color,
-                            palette: palette_for_sprite(self.sprite.as_ref()),
-                        });
+                        None => self.fifo.push_back(item),
                     }
                 }
                 // clean up so that GetTileData can assume 0
                 self.tile_row_data = [0; 8];
-                self.state = FetcherState::GetTileDelay
+                self.sprite = None;
+                self.state = FetcherState::TileIndex
             }
         }
     }
 }
 
-fn palette_for_sprite(sprite: Option<&Sprite>) -> ObjectPalette {
-    match sprite {
-        Some(sprite) => match (sprite.attributes >> 4) & 1 {
-            0b0 => ObjectPalette::ObjectPalette0,
-            0b1 => ObjectPalette::ObjectPalette1,
-            _ => unreachable!(),
-        },
-        None => ObjectPalette::ObjectPalette0, // does not matter
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        ppu::PPU,
+        test_utils::{idle, machine_running, with_large_stack},
+    };
+
+    use super::{ObjectFetcher, ObjectPalette, Sprite, SpriteEntry};
+
+    // Y-flipped, so that the row gets subtracted from the height
+    fn sprite_at(y_screen_plus_16: u8) -> Sprite {
+        Sprite {
+            attributes: 1 << super::OBJECT_ATTRIBUTE_Y_FLIP_BIT,
+            tile_index: 0,
+            x_screen_plus_8: 8,
+            y_screen_plus_16,
+        }
+    }
+
+    #[test]
+    fn row_within_object_follows_object_height() {
+        with_large_stack(|| {
+            // LY reads 144 with the Gameboy Doctor fix
+            let mut ppu = Box::new(PPU::new(true));
+            let fetcher = ObjectFetcher::new();
+            ppu.lcd_control = Wrapping(0b100);
+            assert_eq!(fetcher.row_within_object(&ppu, &sprite_at(145)), 0);
+            assert_eq!(fetcher.row_within_object(&ppu, &sprite_at(160)), 15);
+            // An 8x16 object 15 rows down, selected before LCDC switched back to 8x8 objects
+            ppu.lcd_control = Wrapping(0);
+            assert_eq!(fetcher.row_within_object(&ppu, &sprite_at(145)), 0);
+            assert_eq!(fetcher.row_within_object(&ppu, &sprite_at(160)), 7);
+        });
+    }
+
+    fn write(machine: &mut Machine, address: u16, value: u8) {
+        machine.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    // Renders a single object showing `tile` with `attributes` at the top-left of a background of
+    // color 1, with identity BGP and OBP0
+    fn render_object(tile: [u8; 16], attributes: u8, obp1: u8) -> Box<Machine> {
+        render_objects(tile, &[[16, 8, 1, attributes]], obp1)
+    }
+
+    // Same, with `objects` being the OAM entries of objects showing tile 1
+    fn render_objects(tile: [u8; 16], objects: &[[u8; 4]], obp1: u8) -> Box<Machine> {
+        let mut machine = machine_running(idle());
+        write(&mut machine, 0xFF40, 0x00);
+        for (offset, value) in [0xFF, 0x00].repeat(8).into_iter().chain(tile).enumerate() {
+            write(&mut machine, 0x8000 + offset as u16, value);
+        }
+        for address in 0x9800..0x9C00 {
+            write(&mut machine, address, 0x00);
+        }
+        for address in 0xFE00..0xFEA0 {
+            write(&mut machine, address, 0x00);
+        }
+        for (offset, value) in objects.iter().flatten().enumerate() {
+            write(&mut machine, 0xFE00 + offset as u16, *value);
+        }
+        write(&mut machine, 0xFF47, 0xE4);
+        write(&mut machine, 0xFF48, 0xE4);
+        write(&mut machine, 0xFF49, obp1);
+        write(&mut machine, 0xFF40, 0x93);
+        // The first frame after turning the LCD on stays blank
+        machine.run_frames(2);
+        machine
+    }
+
+    fn shade_at(machine: &Machine, x: usize, y: usize) -> u8 {
+        machine.ppu().frame_buffer[y * 160 + x]
+    }
+
+    #[test]
+    fn object_at_8_16_appears_at_the_top_left() {
+        with_large_stack(|| {
+            let machine = render_object([0xFF; 16], 0x00, 0xE4);
+            for y in 0..10 {
+                for x in 0..10 {
+                    let expected = if x < 8 && y < 8 { 3 } else { 1 };
+                    assert_eq!(shade_at(&machine, x, y), expected, "at ({}, {})", x, y);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn obp1_shades_objects_and_color_0_stays_transparent() {
+        with_large_stack(|| {
+            // Each row shows colors 0 to 3 twice, with OBP1 mapping them to shades 3, 0, 3, 2
+            let attributes = 1 << super::OBJECT_ATTRIBUTE_PALETTE_BIT;
+            let machine =
+                render_object([0x55, 0x33].repeat(8).try_into().unwrap(), attributes, 0xB3);
+            for y in 0..8 {
+                for x in 0..8 {
+                    assert_eq!(
+                        shade_at(&machine, x, y),
+                        [1, 0, 3, 2][x % 4],
+                        "at ({}, {})",
+                        x,
+                        y
+                    );
+                }
+            }
+        });
+    }
+
+    // Where the only color 3 pixel of an object ends up, its tile having it at the top-left
+    fn flipped_corner(attributes: u8) -> Vec<(usize, usize)> {
+        let mut tile = [0x00; 16];
+        tile[0] = 0x80;
+        tile[1] = 0x80;
+        let machine = render_object(tile, attributes, 0xE4);
+        let mut corners = Vec::new();
+        for y in 0..8 {
+            for x in 0..8 {
+                if shade_at(&machine, x, y) == 3 {
+                    corners.push((x, y));
+                }
+            }
+        }
+        corners
+    }
+
+    #[test]
+    fn flips_mirror_the_object_tile() {
+        with_large_stack(|| {
+            let x_flip = 1 << super::OBJECT_ATTRIBUTE_X_FLIP_BIT;
+            let y_flip = 1 << super::OBJECT_ATTRIBUTE_Y_FLIP_BIT;
+            assert_eq!(flipped_corner(0), vec![(0, 0)]);
+            assert_eq!(flipped_corner(x_flip), vec![(7, 0)]);
+            assert_eq!(flipped_corner(y_flip), vec![(0, 7)]);
+            assert_eq!(flipped_corner(x_flip | y_flip), vec![(7, 7)]);
+        });
+    }
+
+    #[test]
+    fn only_the_first_10_objects_on_a_line_are_drawn() {
+        with_large_stack(|| {
+            // Right to left, so that OAM order differs from X order: the 2 leftmost get dropped
+            let objects: Vec<[u8; 4]> = (0..12)
+                .map(|index| [16, 8 + 12 * (11 - index), 1, 0])
+                .collect();
+            let machine = render_objects([0xFF; 16], &objects, 0xE4);
+            for x in 0..160 {
+                let expected = if (24..144).contains(&x) && x % 12 < 8 {
+                    3
+                } else {
+                    1
+                };
+                assert_eq!(shade_at(&machine, x, 0), expected, "at x = {}", x);
+            }
+        });
+    }
+
+    #[test]
+    fn dump_oam_decodes_entries_while_oam_is_blocked() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            machine.write_u8(Wrapping(0xFF40), Wrapping(0x00));
+            // Behind the background, flipped vertically, with OBP1
+            for (offset, byte) in [0x50, 0x28, 0x12, 0xD0].into_iter().enumerate() {
+                machine.write_u8(Wrapping(0xFE14 + offset as u16), Wrapping(byte));
+            }
+            machine.write_u8(Wrapping(0xFF40), Wrapping(0x93));
+            while machine.read_u8(Wrapping(0xFF41)).0 & 0x03 != 3 {
+                machine.step();
+            }
+            assert_eq!(machine.read_u8(Wrapping(0xFE14)), Wrapping(0xFF));
+            let oam = machine.ppu().dump_oam();
+            assert_eq!(oam.len(), 40);
+            assert_eq!(
+                oam[5],
+                SpriteEntry {
+                    x: 0x28,
+                    y: 0x50,
+                    tile: 0x12,
+                    background_priority: true,
+                    y_flip: true,
+                    x_flip: false,
+                    palette: ObjectPalette::ObjectPalette1,
+                }
+            );
+        });
     }
 }
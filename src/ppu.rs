@@ -1,30 +1,49 @@
-use std::{collections::VecDeque, num::Wrapping};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    num::Wrapping,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
 
 use crate::{
     cpu::interrupts::{Interrupts, STAT_INTERRUPT_BIT, VBLANK_INTERRUPT_BIT},
     pixel_fetcher::{
         background_or_window::BackgroundOrWindowFetcher,
         get_tile_index_in_palette,
-        object::{ObjectFetcher, ObjectPalette, Sprite},
-        Fetcher, FetchingFor, TileAddressingMode,
+        object::{ObjectFIFOItem, ObjectFetcher, ObjectPalette, Sprite, SpriteEntry},
+        FIFOItem, Fetcher, FetchingFor, TileAddressingMode, TileRowCache,
     },
     utils::{self},
 };
 
-const TILE_MAP0_VRAM_OFFSET: usize = 0x1800;
-const TILE_MAP1_VRAM_OFFSET: usize = 0x1C00;
+// LY switches to 0 early during line 153, still in VBlank
+const LINE_153_DOTS_AS_LY_153: u16 = 4;
+
+pub const TILE_MAP0_VRAM_OFFSET: usize = 0x1800;
+pub const TILE_MAP1_VRAM_OFFSET: usize = 0x1C00;
 
 const OAM_SIZE: usize = 0xA0;
+const OAM_ENTRY_SIZE: usize = 4;
+// OAM scan keeps the first objects by OAM index, later ones on the same scanline are not drawn
+const MAX_OBJECTS_PER_SCANLINE: usize = 10;
 const VRAM_SIZE: usize = 0x2000;
-const WRAM_SIZE: usize = 0x1000;
+pub const WRAM_SIZE: usize = 0x1000;
+// CGB has 8 WRAM banks, DMG only uses the first two
+const WRAM_BANK_COUNT: usize = 8;
+const WRAM_BANK_MASK: u8 = 0x07;
 
-const LCD_HORIZONTAL_PIXEL_COUNT: usize = 160;
-const LCD_VERTICAL_PIXEL_COUNT: usize = 144;
+pub const LCD_HORIZONTAL_PIXEL_COUNT: usize = 160;
+pub const LCD_VERTICAL_PIXEL_COUNT: usize = 144;
 
 pub const HORIZONTAL_PIXELS_PER_TILE: usize = 8;
 pub const VERTICAL_PIXELS_PER_TILE: usize = 8;
 pub const PIXELS_PER_TILE: usize = HORIZONTAL_PIXELS_PER_TILE * VERTICAL_PIXELS_PER_TILE;
 
+// Tile data spans 0x8000-0x97FF, 16 bytes per tile
+const TILE_DATA_SIZE: usize = 16;
+pub const TILE_COUNT: usize = 384;
+
 pub const TILE_PALETTE_HORIZONTAL_TILE_COUNT: usize = 16;
 pub const TILE_PALETTE_VERTICAL_TILE_COUNT: usize = 24;
 pub const TILE_PALETTE_HORIZONTAL_PIXELS: usize =
@@ -45,23 +64,37 @@ const TILE_MAP_PIXELS_TOTAL: usize = TILE_MAP_HORIZONTAL_PIXELS * TILE_MAP_VERTI
 const PIXEL_DATA_SIZE: usize = 4; // 4-bytes for R, G, B, A
 
 // LCD control single bits of interest
-const _LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT: u8 = 0;
-const _LCDC_OBJECT_ENABLE_BIT: u8 = 1;
-const _LCDC_OBJECT_SIZE_BIT: u8 = 2;
-pub const LCDC_BACKGROUND_TILE_MAP_AREA_BIT: u8 = 3;
+const LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT: u8 = 0;
+const LCDC_OBJECT_ENABLE_BIT: u8 = 1;
+const LCDC_OBJECT_SIZE_BIT: u8 = 2;
+const LCDC_BACKGROUND_TILE_MAP_AREA_BIT: u8 = 3;
 const LCDC_BACKGROUND_AND_WINDOW_TILE_AREA_BIT: u8 = 4;
-const _LCDC_WINDOW_ENABLE_BIT: u8 = 5;
-const _LCDC_WINDOW_TILE_MAP_AREA_BIT: u8 = 6;
+const LCDC_WINDOW_ENABLE_BIT: u8 = 5;
+const LCDC_WINDOW_TILE_MAP_AREA_BIT: u8 = 6;
 const LCDC_LCD_ENABLE_BIT: u8 = 7;
 
 // LCD status single bits of interest
+const LCD_STATUS_MODE_MASK: u8 = 0b0000_0011;
 const LYC_EQUALS_LY_BIT: u8 = 2;
 const MODE_0_INTERRUPT_SELECT_BIT: u8 = 3;
 const MODE_1_INTERRUPT_SELECT_BIT: u8 = 4;
 const MODE_2_INTERRUPT_SELECT_BIT: u8 = 5;
 const LYC_EQUALS_LY_INTERRUPT_SELECT_BIT: u8 = 6;
+// Only the interrupt selects are writable, bits 0-2 are read-only
+const LCD_STATUS_WRITABLE_MASK: u8 = 0b0111_1000;
+// Bit 7 is unused and always reads as 1
+const LCD_STATUS_UNUSED_BITS: u8 = 0b1000_0000;
+
+const CGB_WHITE: u16 = 0x7FFF;
 
-#[derive(Clone, Debug)]
+// CGB palette RAM: 8 palettes of 4 colors, each color 2 bytes
+const COLOR_PALETTE_RAM_SIZE: usize = 0x40;
+const COLOR_PALETTE_INDEX_MASK: u8 = 0x3F;
+const COLOR_PALETTE_AUTO_INCREMENT_BIT: u8 = 0x80;
+// Bit 6 of BCPS/OCPS is unused and always reads as 1
+const COLOR_PALETTE_SPEC_UNUSED_BITS: u8 = 0x40;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum PPUState {
     OAMScan,
     DrawingPixels(u8),
@@ -69,120 +102,321 @@ pub enum PPUState {
     VerticalBlank,
 }
 
-#[derive(Clone, Debug)]
+impl PPUState {
+    /// The mode number reported in bits 0-1 of the STAT register.
+    pub fn mode(&self) -> u8 {
+        match self {
+            PPUState::HorizontalBlank => 0,
+            PPUState::VerticalBlank => 1,
+            PPUState::OAMScan => 2,
+            PPUState::DrawingPixels(_) => 3,
+        }
+    }
+}
+
+/// One of the two background tile maps, selected by LCDC for the background and the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileMap {
+    At9800,
+    At9C00,
+}
+
+impl TileMap {
+    fn vram_offset(&self) -> usize {
+        match self {
+            TileMap::At9800 => TILE_MAP0_VRAM_OFFSET,
+            TileMap::At9C00 => TILE_MAP1_VRAM_OFFSET,
+        }
+    }
+}
+
+/// CGB palette RAM, for either the background or the objects, along with the specification
+/// register (BCPS/OCPS) selecting which byte the data register (BCPD/OCPD) accesses.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColorPalettes {
+    /// Byte index in `ram` (bits 0-5), auto-incremented on data writes when bit 7 is set.
+    pub specification: Wrapping<u8>,
+    /// Colors in little-endian RGB555, i.e. `0bxBBBBBGG_GGGRRRRR` once both bytes are combined.
+    #[serde(with = "BigArray")]
+    pub ram: [u8; COLOR_PALETTE_RAM_SIZE],
+}
+
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        ColorPalettes::new()
+    }
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        ColorPalettes {
+            specification: Wrapping(0),
+            ram: [0; COLOR_PALETTE_RAM_SIZE],
+        }
+    }
+
+    fn index(&self) -> usize {
+        (self.specification.0 & COLOR_PALETTE_INDEX_MASK) as usize
+    }
+
+    pub fn read_specification(&self) -> Wrapping<u8> {
+        Wrapping(COLOR_PALETTE_SPEC_UNUSED_BITS | self.specification.0)
+    }
+
+    pub fn write_specification(&mut self, value: Wrapping<u8>) {
+        self.specification = Wrapping(value.0 & !COLOR_PALETTE_SPEC_UNUSED_BITS);
+    }
+
+    pub fn read_data(&self) -> Wrapping<u8> {
+        Wrapping(self.ram[self.index()])
+    }
+
+    pub fn write_data(&mut self, value: Wrapping<u8>) {
+        self.ram[self.index()] = value.0;
+        if self.specification.0 & COLOR_PALETTE_AUTO_INCREMENT_BIT != 0 {
+            let index = (self.specification.0 + 1) & COLOR_PALETTE_INDEX_MASK;
+            self.specification = Wrapping(COLOR_PALETTE_AUTO_INCREMENT_BIT | index);
+        }
+    }
+
+    /// The RGB555 value of color `color` (0-3) of palette `palette` (0-7).
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let index = (palette as usize * 4 + color as usize) * 2;
+        u16::from_le_bytes([self.ram[index], self.ram[index + 1]])
+    }
+}
+
+// Scales each 5-bit component to 8 bits, without any of the color correction of a real LCD
+pub fn rgb555_to_rgba(color: u16) -> [u8; PIXEL_DATA_SIZE] {
+    let component = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    [component(0), component(5), component(10), 255]
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PPU {
     /** PPU state **/
+    /// CGB mode: background map attributes get read from VRAM bank 1, and colors from the CGB
+    /// palettes into `cgb_frame_buffer`.  Set by `Machine::new` for games whose header flags CGB
+    /// support, others render as on DMG.
+    pub cgb_mode: bool,
     drawn_pixels_on_current_row: u8,
+    /// Set when the LCD gets turned on.  The first scanline after that skips its OAM scan: the PPU
+    /// stays in mode 0 instead, and starts drawing without any object.
+    first_line_after_enable: bool,
+    /// SCX % 8, latched when mode 3 starts: the pixels discarded for fine scrolling do not change
+    /// with SCX writes later in the scanline.
+    fine_scroll_x: u8,
     fix_ly_for_gb_doctor: bool,
     /// Because the STAT interrupt is triggered on a rising edge of the STAT line, we need to
     /// remember its previous value.
     last_stat_line: u8,
+    /// Mode 3 dots spent on fetches that the pixel fetchers do not simulate, during which no
+    /// pixel gets pushed to the LCD.
+    mode_3_stall_dots: u8,
     scanline_dots: u16,
     state: PPUState,
 
     // Hardware registers
-    pub background_palette_data: u8,
-    pub cgb_background_palette_data: Wrapping<u8>,
-    pub cgb_background_palette_spec: Wrapping<u8>,
+    pub background_palette_data: Wrapping<u8>,
+    /// CGB background palettes, accessed through BCPS (0xFF68) and BCPD (0xFF69).
+    pub cgb_background_palettes: ColorPalettes,
+    /// CGB object palettes, accessed through OCPS (0xFF6A) and OCPD (0xFF6B).
+    pub cgb_object_palettes: ColorPalettes,
     pub lcd_control: Wrapping<u8>,
-    pub lcd_status: Wrapping<u8>,
-    pub lcd_y_compare: Wrapping<u8>,
+    /// LCD status.  Only holds the LYC==LY bit and the interrupt selects: the mode bits are derived
+    /// from `state` when read via `read_stat()`.
+    lcd_status: Wrapping<u8>,
+    /// LY compare.  Made private to enforce the use of `write_lyc()`, which re-evaluates the LYC==LY
+    /// coincidence.
+    lcd_y_compare: Wrapping<u8>,
     /// LCD Y-coordinate.  Made private to enforce the use of `read_ly()` which allows forcing LY's
-    /// value when using GB Doctor.
+    /// value when using GB Doctor, while rendering goes through `ly()`.
     lcd_y_coord: Wrapping<u8>,
-    pub object_palette_data: Wrapping<u8>,
-    pub object_palette_spec: Wrapping<u8>,
-    pub object_palette_0: u8,
-    pub object_palette_1: u8,
+    /// OBP0 (0xFF48), selected by objects whose attribute palette bit is clear.
+    pub object_palette_0: Wrapping<u8>,
+    /// OBP1 (0xFF49), selected by objects whose attribute palette bit is set.
+    pub object_palette_1: Wrapping<u8>,
+    /// SCX (0xFF43).  Its upper 5 bits are read by each background tile fetch, so that a write
+    /// mid-scanline shifts the tiles fetched afterwards, while its lower 3 bits only matter when
+    /// mode 3 starts, see `fine_scroll_x`.
     pub scx: Wrapping<u8>,
+    /// SCY (0xFF42), read by each background tile fetch, for both the tile ID and its data.
     pub scy: Wrapping<u8>,
+    /// VBK (0xFF4F): CGB VRAM bank mapped at 0x8000-0x9FFF (bit 0).
     pub vram_bank: Wrapping<u8>,
     pub window_x7: Wrapping<u8>,
     pub window_y: Wrapping<u8>,
+    /// SVBK (0xFF70): CGB WRAM bank mapped at 0xD000-0xDFFF (bits 0-2, 0 selecting bank 1).
+    pub wram_bank: Wrapping<u8>,
 
     // Hardware banks
+    #[serde(with = "BigArray")]
     pub object_attribute_memory: [u8; OAM_SIZE], // TODO: make private?
+    /// VRAM bank 0, the only one on DMG.
+    #[serde(with = "BigArray")]
     pub vram: [u8; VRAM_SIZE],
-    wram_0: [u8; WRAM_SIZE],
-    wram_1: [u8; WRAM_SIZE],
+    /// CGB VRAM bank 1, holding additional tiles and the background map attributes.
+    #[serde(with = "BigArray")]
+    pub vram_1: [u8; VRAM_SIZE],
+    /// All WRAM banks, one after the other.  Bank 0 is always mapped at 0xC000-0xCFFF.
+    #[serde(with = "BigArray")]
+    wram: [u8; WRAM_SIZE * WRAM_BANK_COUNT],
 
     // Rendered pixel surfaces
-    pub lcd_pixels: [u8; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT * PIXEL_DATA_SIZE],
+    /// Shade (0-3) of each LCD pixel, with BGP/OBP0/OBP1 already applied.
+    #[serde(with = "BigArray")]
+    pub frame_buffer: FrameBuffer,
+    /// RGB555 color of each LCD pixel, drawn instead of `frame_buffer` in CGB mode.
+    #[serde(with = "BigArray")]
+    pub cgb_frame_buffer: [u16; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT],
+    /// Set when entering VBlank, i.e. when `frame_buffer` holds a complete frame.  The host is
+    /// responsible for clearing it once it has consumed the frame.
+    pub frame_ready: bool,
+    /// Frames completed since power on, counted upon entering VBlank.
+    pub frame_count: u64,
+    /// Set to leave `frame_buffer` untouched, for frames that will not be displayed.  Only
+    /// rendering is skipped, timing is not affected.  Not part of save states.
+    #[serde(skip)]
+    pub skip_rendering: bool,
+    /// Tile rows already decoded by the fetchers.  Refilled lazily, so not part of save states.
+    #[serde(skip, default = "TileRowCache::new")]
+    pub tile_row_cache: TileRowCache,
+    /// Tiles written since the last `take_dirty_tiles()`, those of VRAM bank 1 numbered after the
+    /// `TILE_COUNT` of bank 0.  Only meant for debug views, so not part of save states.
+    #[serde(skip)]
+    dirty_tiles: BTreeSet<u16>,
+    /// Tile map cells written since the last `take_dirty_tile_map_cells()`, numbered from the
+    /// start of tile map 0, so that those of tile map 1 follow.  Attribute writes in VRAM bank 1
+    /// count as well.
+    #[serde(skip)]
+    dirty_tile_map_cells: BTreeSet<u16>,
+    /// Colors used by `to_rgba()` to display each of the four shades.
+    pub screen_palette: ScreenPalette,
+    // Debug surfaces are left out of save states, they get re-rendered by `render()` anyway
+    #[serde(skip, default = "blank_pixels")]
     pub tile_map0_pixels: [u8; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
+    #[serde(skip, default = "blank_pixels")]
     pub tile_map1_pixels: [u8; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
+    #[serde(skip, default = "blank_pixels")]
     pub tile_palette_pixels: [u8; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE],
 
     // Transient state saved for debug view purposes
+    #[serde(with = "BigArray")]
     frame_scxs: [u8; LCD_VERTICAL_PIXEL_COUNT],
+    #[serde(with = "BigArray")]
     frame_scxs_valid: [bool; LCD_VERTICAL_PIXEL_COUNT],
+    #[serde(with = "BigArray")]
     frame_scys_at_scanline_0: [u8; LCD_HORIZONTAL_PIXEL_COUNT],
+    #[serde(with = "BigArray")]
     frame_scys_first_scanline_valid: [bool; LCD_HORIZONTAL_PIXEL_COUNT],
     // TODO: make this private? move it to pixel fetcher?
+    #[serde(with = "BigArray")]
     pub tile_map0_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
+    #[serde(with = "BigArray")]
     pub tile_map1_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
 }
 
+/// Shade (0-3) of each LCD pixel, row by row.
+pub type FrameBuffer = [u8; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT];
+
+/// RGBA colors for shades 0 (lightest) to 3 (darkest).
+pub type ScreenPalette = [[u8; PIXEL_DATA_SIZE]; 4];
+
+pub const CLASSIC_GREEN_PALETTE: ScreenPalette = [
+    [0x9B, 0xBC, 0x0F, 255],
+    [0x8B, 0xAC, 0x0F, 255],
+    [0x30, 0x62, 0x30, 255],
+    [0x0F, 0x38, 0x0F, 255],
+];
+pub const GRAYSCALE_PALETTE: ScreenPalette = [WHITE, LIGHT_GRAY, DARK_GRAY, BLACK];
+
 const BLACK: [u8; 4] = [0, 0, 0, 255];
 const DARK_GRAY: [u8; 4] = [0x55, 0x55, 0x55, 255];
 const LIGHT_GRAY: [u8; 4] = [0xAA, 0xAA, 0xAA, 255];
 const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 255];
 
-pub fn pixel_code_to_rgba(pixel_code: u8, palette: u8) -> [u8; PIXEL_DATA_SIZE] {
-    let pixel_shade = match pixel_code {
+// Palettes (BGP, OBP0, OBP1) hold two bits of shade for each of the four pixel codes, with the
+// shade for pixel code 0 in the lowest bits.
+pub fn pixel_code_to_shade(pixel_code: u8, palette: u8) -> u8 {
+    match pixel_code {
         0b00 => palette & 0b11,
         0b01 => (palette >> 2) & 0b11,
         0b10 => (palette >> 4) & 0b11,
         0b11 => (palette >> 6) & 0b11,
         _ => panic!("Invalid pixel code: 0x{:08b}", pixel_code),
-    };
-    match pixel_shade {
+    }
+}
+
+pub fn shade_to_rgba(shade: u8) -> [u8; PIXEL_DATA_SIZE] {
+    match shade {
         0b00 => WHITE,
         0b01 => LIGHT_GRAY,
         0b10 => DARK_GRAY,
         0b11 => BLACK,
-        _ => unreachable!(),
+        _ => panic!("Invalid shade: 0x{:08b}", shade),
     }
 }
 
-// Each pixel takes 4 bytes (R, G, B, A).  Each y results in 160 pixels.
-pub fn pixel_coordinates_in_rgba_slice(x: u8, y: u8) -> usize {
-    (y as usize * LCD_HORIZONTAL_PIXEL_COUNT + x as usize) * PIXEL_DATA_SIZE
+pub fn pixel_code_to_rgba(pixel_code: u8, palette: u8) -> [u8; PIXEL_DATA_SIZE] {
+    shade_to_rgba(pixel_code_to_shade(pixel_code, palette))
+}
+
+fn blank_pixels<const N: usize>() -> [u8; N] {
+    [0; N]
+}
+
+// Each y results in 160 pixels.
+pub fn pixel_coordinates_in_frame_buffer(x: u8, y: u8) -> usize {
+    y as usize * LCD_HORIZONTAL_PIXEL_COUNT + x as usize
 }
 
 impl PPU {
     pub fn new(fix_ly: bool) -> Self {
         PPU {
+            cgb_mode: false,
             drawn_pixels_on_current_row: 0,
+            first_line_after_enable: false,
+            fine_scroll_x: 0,
             fix_ly_for_gb_doctor: fix_ly,
             last_stat_line: 0,
+            mode_3_stall_dots: 0,
             scanline_dots: 0,
-            state: PPUState::OAMScan,
+            // The LCD starts off, which the PPU reports as mode 0
+            state: PPUState::HorizontalBlank,
 
-            background_palette_data: 0,
-            cgb_background_palette_spec: Wrapping(0),
-            cgb_background_palette_data: Wrapping(0),
+            background_palette_data: Wrapping(0),
+            cgb_background_palettes: ColorPalettes::new(),
+            cgb_object_palettes: ColorPalettes::new(),
             lcd_control: Wrapping(0),
-            lcd_status: Wrapping(2), // initially set Mode 2
+            lcd_status: Wrapping(0),
             lcd_y_compare: Wrapping(0),
             lcd_y_coord: Wrapping(0),
-            object_palette_data: Wrapping(0),
-            object_palette_0: 0,
-            object_palette_1: 0,
-            object_palette_spec: Wrapping(0),
+            object_palette_0: Wrapping(0),
+            object_palette_1: Wrapping(0),
             scx: Wrapping(0),
             scy: Wrapping(0),
             vram_bank: Wrapping(0),
             window_x7: Wrapping(0),
             window_y: Wrapping(0),
+            wram_bank: Wrapping(0),
 
             object_attribute_memory: [0; OAM_SIZE],
             vram: [0; VRAM_SIZE],
-            wram_0: [0; WRAM_SIZE],
-            wram_1: [0; WRAM_SIZE],
-
-            lcd_pixels: [0; LCD_HORIZONTAL_PIXEL_COUNT
-                * LCD_VERTICAL_PIXEL_COUNT
-                * PIXEL_DATA_SIZE],
+            vram_1: [0; VRAM_SIZE],
+            wram: [0; WRAM_SIZE * WRAM_BANK_COUNT],
+
+            frame_buffer: [0; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT],
+            cgb_frame_buffer: [CGB_WHITE; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT],
+            frame_ready: false,
+            frame_count: 0,
+            skip_rendering: false,
+            tile_row_cache: TileRowCache::new(),
+            dirty_tiles: BTreeSet::new(),
+            dirty_tile_map_cells: BTreeSet::new(),
+            screen_palette: CLASSIC_GREEN_PALETTE,
             tile_map0_pixels: [0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
             tile_map1_pixels: [0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
             tile_palette_pixels: [0; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE],
@@ -206,45 +440,156 @@ impl PPU {
         }
     }
 
+    // On DMG, clearing this blanks both the background and the window, objects are unaffected
+    pub fn is_background_and_window_enabled(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT)
+    }
+
+    pub fn are_objects_enabled(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_OBJECT_ENABLE_BIT)
+    }
+
+    pub fn object_height(&self) -> u8 {
+        if utils::is_bit_set(&self.lcd_control, LCDC_OBJECT_SIZE_BIT) {
+            16
+        } else {
+            8
+        }
+    }
+
     pub fn is_lcd_ppu_on(&self) -> bool {
         utils::is_bit_set(&self.lcd_control, LCDC_LCD_ENABLE_BIT)
     }
 
-    pub fn increment_ly(&mut self, interrupts: &mut Interrupts) {
-        self.lcd_y_coord = self.lcd_y_coord + Wrapping(1);
-        if self.lcd_y_coord == self.lcd_y_compare {
-            utils::set_bit(&mut self.lcd_status, LYC_EQUALS_LY_BIT);
-            if utils::is_bit_set(&self.lcd_status, LYC_EQUALS_LY_INTERRUPT_SELECT_BIT) {
-                interrupts.request(STAT_INTERRUPT_BIT);
-            }
+    // Only takes effect along with `is_background_and_window_enabled`
+    pub fn is_window_enabled(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_WINDOW_ENABLE_BIT)
+    }
+
+    // Tile map at 0x9C00 rather than 0x9800
+    pub fn is_background_using_tile_map1(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_BACKGROUND_TILE_MAP_AREA_BIT)
+    }
+
+    // Tile map at 0x9C00 rather than 0x9800
+    pub fn is_window_using_tile_map1(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_WINDOW_TILE_MAP_AREA_BIT)
+    }
+
+    pub fn increment_ly(&mut self) {
+        self.write_ly(self.lcd_y_coord + Wrapping(1));
+    }
+
+    // All changes to LY must go through here so that the LYC==LY coincidence stays up to date
+    fn write_ly(&mut self, value: Wrapping<u8>) {
+        self.lcd_y_coord = value;
+        self.update_lyc_equals_ly();
+    }
+
+    // NOTE: This only updates the STAT bit, the interrupt is requested on the rising edge of the
+    // STAT line, at the end of `tick()`.
+    fn update_lyc_equals_ly(&mut self) {
+        self.lcd_status = utils::write_bit(
+            &self.lcd_status,
+            LYC_EQUALS_LY_BIT,
+            self.lcd_y_coord == self.lcd_y_compare,
+        );
+    }
+
+    pub fn mode(&self) -> u8 {
+        // While the LCD is off, the PPU reports mode 0
+        if self.is_lcd_ppu_on() {
+            self.state.mode()
         } else {
-            utils::unset_bit(&mut self.lcd_status, LYC_EQUALS_LY_BIT);
+            0
         }
     }
 
+    // The PPU locks the CPU out of VRAM while drawing, and out of OAM while scanning or drawing.
+    // Mode 0 is reported while the LCD is off, so everything is accessible then.
+    pub fn is_accessible_by_cpu(&self, address: Wrapping<u16>) -> bool {
+        match address.0 {
+            0x8000..=0x9FFF => self.mode() != 3,
+            // Including the unusable area past OAM, which reads 0xFF rather than 0x00 meanwhile
+            0xFE00..=0xFEFF => self.mode() < 2,
+            _ => true,
+        }
+    }
+
+    // The window starts being drawn once both its top edge and its left edge have been reached
+    fn is_window_reached(&self) -> bool {
+        self.is_window_enabled()
+            && self.ly() >= self.window_y
+            && self.drawn_pixels_on_current_row as u16 + 7 >= self.window_x7.0 as u16
+    }
+
+    pub fn is_ly_fixed_for_gb_doctor(&self) -> bool {
+        self.fix_ly_for_gb_doctor
+    }
+
+    /// The scanline being drawn, which is what LY reads unless it is stubbed for GB Doctor.
+    pub fn ly(&self) -> Wrapping<u8> {
+        self.lcd_y_coord
+    }
+
     pub fn read_ly(&self) -> Wrapping<u8> {
+        // Gameboy Doctor logs are produced with LY stubbed to 0x90, i.e. the first VBlank line
         if self.fix_ly_for_gb_doctor {
-            Wrapping(144)
+            Wrapping(0x90)
         } else {
             self.lcd_y_coord
         }
     }
 
+    /// Pixel codes (0-3) of every tile, in VRAM order, each row by row.  Tile maps refer to them
+    /// through `tile_index`.
+    pub fn dump_tiles(&self) -> Vec<[u8; PIXELS_PER_TILE]> {
+        self.vram[..TILE_COUNT * TILE_DATA_SIZE]
+            .chunks_exact(TILE_DATA_SIZE)
+            .map(decode_tile)
+            .collect()
+    }
+
+    /// Tile IDs of `tile_map`, row by row.
+    pub fn dump_tile_map(
+        &self,
+        tile_map: TileMap,
+    ) -> [[u8; TILE_MAP_HORIZONTAL_TILE_COUNT]; TILE_MAP_VERTICAL_TILE_COUNT] {
+        let offset = tile_map.vram_offset();
+        let mut tile_map = [[0; TILE_MAP_HORIZONTAL_TILE_COUNT]; TILE_MAP_VERTICAL_TILE_COUNT];
+        for (y, row) in tile_map.iter_mut().enumerate() {
+            let row_from = offset + y * TILE_MAP_HORIZONTAL_TILE_COUNT;
+            row.copy_from_slice(&self.vram[row_from..row_from + TILE_MAP_HORIZONTAL_TILE_COUNT]);
+        }
+        tile_map
+    }
+
+    /// Index in `dump_tiles()` of the tile that a background or window tile ID currently refers
+    /// to, which depends on the addressing mode selected by LCDC.
+    pub fn tile_index(&self, tile_id: u8) -> usize {
+        get_tile_index_in_palette(tile_id, &self.get_addressing_mode()) as usize
+    }
+
+    /// Every OAM entry, in OAM order.  OAM is read directly, so this works whatever the PPU mode.
+    pub fn dump_oam(&self) -> Vec<SpriteEntry> {
+        self.object_attribute_memory
+            .chunks_exact(OAM_ENTRY_SIZE)
+            .map(|entry| Sprite::from_oam_entry(entry).entry())
+            .collect()
+    }
+
     // TODO: Eventually we could update on the fly on writes
     pub fn render_tile_palette(&mut self) {
         for tile_palette_y in 0..TILE_PALETTE_VERTICAL_TILE_COUNT {
             for tile_palette_x in 0..TILE_PALETTE_HORIZONTAL_TILE_COUNT {
-                let tile_data_from = (tile_palette_y * 16 + tile_palette_x) * 16;
-                let tile_data = &self.vram[tile_data_from..tile_data_from + 16];
+                let tile_data_from = (tile_palette_y * 16 + tile_palette_x) * TILE_DATA_SIZE;
+                let tile = decode_tile(&self.vram[tile_data_from..tile_data_from + TILE_DATA_SIZE]);
                 for tile_pixel_y in 0..VERTICAL_PIXELS_PER_TILE {
-                    let row_data_from = tile_pixel_y * 2;
-                    let low_bits = tile_data[row_data_from];
-                    let high_bits = tile_data[row_data_from + 1];
                     for tile_pixel_x in 0..HORIZONTAL_PIXELS_PER_TILE {
-                        let pixel_code = (((high_bits >> (7 - tile_pixel_x)) & 1) << 1)
-                            | ((low_bits >> (7 - tile_pixel_x)) & 1);
+                        let pixel_code =
+                            tile[tile_pixel_y * HORIZONTAL_PIXELS_PER_TILE + tile_pixel_x];
                         let pixel_rgba =
-                            pixel_code_to_rgba(pixel_code, self.background_palette_data);
+                            pixel_code_to_rgba(pixel_code, self.background_palette_data.0);
                         let vram_pixel_x = tile_palette_x * 8 + tile_pixel_x;
                         let vram_pixel_y = tile_palette_y * 8 + tile_pixel_y;
                         let vram_pixels_from =
@@ -315,6 +660,20 @@ impl PPU {
         )
     }
 
+    pub fn to_rgba(&self) -> Vec<u8> {
+        if self.cgb_mode {
+            return self
+                .cgb_frame_buffer
+                .iter()
+                .flat_map(|color| rgb555_to_rgba(*color))
+                .collect();
+        }
+        self.frame_buffer
+            .iter()
+            .flat_map(|shade| self.screen_palette[*shade as usize])
+            .collect()
+    }
+
     // TODO: Eventually we could update on the fly on writes
     pub fn render(&mut self) {
         self.render_tile_palette();
@@ -327,7 +686,7 @@ impl PPU {
         bgw_fetcher: &mut BackgroundOrWindowFetcher,
         obj_fetcher: &mut ObjectFetcher,
     ) {
-        self.lcd_y_coord = Wrapping(0);
+        self.write_ly(Wrapping(0));
 
         bgw_fetcher.prepare_for_new_frame();
         obj_fetcher.prepare_for_new_frame();
@@ -372,7 +731,7 @@ impl PPU {
             // mode 2
             PPUState::OAMScan => {
                 if self.scanline_dots == 80 {
-                    let ly = self.read_ly().0 as usize;
+                    let ly = self.ly().0 as usize;
 
                     // At the start of each scanline, remember SCX
                     if ly < LCD_VERTICAL_PIXEL_COUNT {
@@ -380,22 +739,17 @@ impl PPU {
                     }
 
                     let mut selected_objects = VecDeque::new();
-                    let object_size = 8; // TODO: this is either 8 or 16 depending on something
+                    let object_size = self.object_height() as i16;
                     let ly = ly as i16; // from now on it's convenient as a signed (yet >= 0)
-                    for object_offset in (0x00..0x9F).step_by(4) {
-                        if selected_objects.len() == 10 {
+                    for entry in self.object_attribute_memory.chunks_exact(OAM_ENTRY_SIZE) {
+                        if selected_objects.len() == MAX_OBJECTS_PER_SCANLINE {
                             break;
                         }
-                        let y_screen_plus_16 = self.object_attribute_memory[object_offset];
-                        let object_min_y_on_screen = (y_screen_plus_16 as u16 as i16) - 16;
+                        let object = Sprite::from_oam_entry(entry);
+                        let object_min_y_on_screen = (object.y_screen_plus_16 as u16 as i16) - 16;
                         let object_max_y_on_screen = object_min_y_on_screen + object_size - 1;
                         if object_min_y_on_screen <= ly && ly <= object_max_y_on_screen {
-                            selected_objects.push_back(Sprite {
-                                x_screen_plus_8: self.object_attribute_memory[object_offset + 1],
-                                y_screen_plus_16,
-                                tile_index: self.object_attribute_memory[object_offset + 2],
-                                attributes: self.object_attribute_memory[object_offset + 3],
-                            });
+                            selected_objects.push_back(object);
                         }
                     }
                     obj_fetcher.selected_objects = selected_objects;
@@ -405,78 +759,23 @@ impl PPU {
 
             // mode 3
             PPUState::DrawingPixels(dropped_pixels) => {
-                if self.drawn_pixels_on_current_row as usize == LCD_HORIZONTAL_PIXEL_COUNT {
-                    return;
-                }
-
-                obj_fetcher.pixel_index_in_row = self.drawn_pixels_on_current_row;
-
-                let bgw_fifo_len = bgw_fetcher.fifo.len();
-                let obj_fifo_len = obj_fetcher.fifo.len();
-
-                let fetcher_state = &pixel_fetcher.fetching_for;
-                if obj_fifo_len == 0 && bgw_fifo_len != 0 {
-                    if *fetcher_state == FetchingFor::BackgroundOrWindowFIFO {
-                        pixel_fetcher.switch_to_object_fifo();
-                    }
-                } else {
-                    if *fetcher_state == FetchingFor::ObjectFIFO {
-                        pixel_fetcher.switch_to_background_or_window_fifo();
-                    }
-                }
-                pixel_fetcher.tick(bgw_fetcher, obj_fetcher, self);
-
-                if !bgw_fetcher.fifo.is_empty() && !obj_fetcher.fifo.is_empty() {
-                    // To support fine scrolling, the first (scx % 8) pixels are dropped from FIFOs
-                    if dropped_pixels < self.scx.0 % 8 {
-                        bgw_fetcher.fifo.pop_front();
-                        obj_fetcher.fifo.pop_front();
-                        self.state = PPUState::DrawingPixels(dropped_pixels + 1);
-                        return;
-                    }
-
-                    // During scanline 0, remember SCY for every pixel pushed
-                    let ly = self.read_ly().0 as usize;
-                    if ly == 0 {
-                        self.frame_scys_at_scanline_0[self.drawn_pixels_on_current_row as usize] =
-                            self.scy.0;
-                    }
-
-                    let bgw_pixel = bgw_fetcher.fifo.pop_front().unwrap();
-                    let obj_pixel = obj_fetcher.fifo.pop_front().unwrap();
-                    let pixel_x = self.drawn_pixels_on_current_row;
-                    let pixel_y = self.read_ly().0;
-
-                    let from = pixel_coordinates_in_rgba_slice(pixel_x, pixel_y);
-                    // Simulate pixel mixing
-                    let (selected_pixel, palette) = if obj_pixel.color == 0 {
-                        (bgw_pixel.color, self.background_palette_data)
-                    } else {
-                        // FIXME: need to choose between OBJ palettes based on attribute
-                        (
-                            obj_pixel.color,
-                            match obj_pixel.palette {
-                                ObjectPalette::ObjectPalette0 => self.object_palette_0,
-                                ObjectPalette::ObjectPalette1 => self.object_palette_1,
-                            },
-                        )
-                    };
-                    let rgba = pixel_code_to_rgba(selected_pixel, palette);
-                    self.lcd_pixels[from..from + 4].copy_from_slice(&rgba);
-                    self.drawn_pixels_on_current_row += 1;
-
-                    if self.drawn_pixels_on_current_row as usize == LCD_HORIZONTAL_PIXEL_COUNT {
-                        self.switch_to_horizontal_blank()
-                    }
-                }
+                self.tick_drawing_pixels(dropped_pixels, bgw_fetcher, obj_fetcher, pixel_fetcher)
             }
 
             // mode 0
             PPUState::HorizontalBlank => {
-                if self.scanline_dots == 456 {
+                if self.first_line_after_enable {
+                    if self.scanline_dots == 80 {
+                        self.first_line_after_enable = false;
+                        self.prepare_for_new_frame(bgw_fetcher, obj_fetcher);
+                        self.drawn_pixels_on_current_row = 0;
+                        obj_fetcher.selected_objects.clear();
+                        self.switch_to_drawing_pixels(pixel_fetcher);
+                    }
+                } else if self.scanline_dots == 456 {
                     self.scanline_dots = 0;
-                    self.increment_ly(interrupts);
-                    if self.read_ly().0 as usize == LCD_VERTICAL_PIXEL_COUNT {
+                    self.increment_ly();
+                    if self.ly().0 as usize == LCD_VERTICAL_PIXEL_COUNT {
                         self.switch_to_vertical_blank(interrupts)
                     } else {
                         self.switch_to_oam_scan(bgw_fetcher, obj_fetcher)
@@ -486,55 +785,330 @@ impl PPU {
 
             // mode 1
             PPUState::VerticalBlank => {
+                // Line 153 only reads as such for its first dots, LY reads 0 for the rest of it
+                if self.lcd_y_coord.0 == 153 && self.scanline_dots == LINE_153_DOTS_AS_LY_153 {
+                    self.write_ly(Wrapping(0));
+                }
+                // Line 153 is the last of VBlank, making for 154 lines per frame
                 if self.scanline_dots == 456 {
                     self.scanline_dots = 0;
-                    self.increment_ly(interrupts);
-                    if self.read_ly().0 == 153 {
+                    if self.lcd_y_coord.0 == 0 {
                         self.prepare_for_new_frame(bgw_fetcher, obj_fetcher);
                         self.switch_to_oam_scan(bgw_fetcher, obj_fetcher)
+                    } else {
+                        self.increment_ly();
                     }
                 }
             }
         }
 
         // STAT interrupt check
-        let stat_line = (self.lcd_status.0 >> 3) & 0xF;
+        let stat_line = self.stat_line();
         if self.last_stat_line == 0 && stat_line != 0 {
             interrupts.request(STAT_INTERRUPT_BIT);
         }
         self.last_stat_line = stat_line;
     }
 
+    // mode 3
+    fn tick_drawing_pixels(
+        &mut self,
+        dropped_pixels: u8,
+        bgw_fetcher: &mut BackgroundOrWindowFetcher,
+        obj_fetcher: &mut ObjectFetcher,
+        pixel_fetcher: &mut Fetcher,
+    ) {
+        if self.drawn_pixels_on_current_row as usize == LCD_HORIZONTAL_PIXEL_COUNT {
+            return;
+        }
+
+        if self.mode_3_stall_dots > 0 {
+            self.mode_3_stall_dots -= 1;
+            return;
+        }
+
+        pixel_fetcher.tick(bgw_fetcher, obj_fetcher, self);
+
+        // While an object is being fetched, both the background fetcher and the LCD are paused
+        if pixel_fetcher.fetching_for == FetchingFor::ObjectFIFO {
+            if !obj_fetcher.is_fetching() {
+                pixel_fetcher.switch_to_background_or_window_fifo();
+            }
+            return;
+        }
+
+        if bgw_fetcher.fifo.is_empty() {
+            return;
+        }
+
+        // To support fine scrolling, the first (scx % 8) pixels are dropped from the FIFO
+        if dropped_pixels < self.fine_scroll_x {
+            bgw_fetcher.fifo.pop_front();
+            self.state = PPUState::DrawingPixels(dropped_pixels + 1);
+            return;
+        }
+
+        // The window is triggered as the pixel at its left edge is about to be shifted out, so even
+        // at WX=7 the first background tile has been fetched, and fine scrolling applied, by then.
+        // Restarting for the window throws that tile away: the window fetch starts on this very
+        // dot, and takes 6 dots to push its first tile.
+        if !bgw_fetcher.is_fetching_window() && self.is_window_reached() {
+            bgw_fetcher.restart_for_window();
+            bgw_fetcher.tick(self);
+            // Fine scrolling only applies to the background, the window never drops pixels
+            self.state = PPUState::DrawingPixels(self.fine_scroll_x);
+            return;
+        }
+
+        let pixel_x = self.drawn_pixels_on_current_row;
+
+        if self.are_objects_enabled() {
+            if let Some(sprite) = obj_fetcher.take_object_reached_at(pixel_x) {
+                // The object fetch waits for the background fetcher to be done with its current
+                // tile, which takes longer the closer the object is to the left of that tile.
+                // Later objects within the same tile do not wait again.
+                let background_x = pixel_x as u16 + self.scx.0 as u16;
+                let background_tile = (background_x / 8) as u8;
+                if obj_fetcher.waited_background_tile != Some(background_tile) {
+                    obj_fetcher.waited_background_tile = Some(background_tile);
+                    self.mode_3_stall_dots = 5 - (background_x % 8).min(5) as u8;
+                }
+                obj_fetcher.start_fetching(sprite, pixel_x);
+                pixel_fetcher.switch_to_object_fifo();
+                return;
+            }
+        }
+
+        // During scanline 0, remember SCY for every pixel pushed
+        let ly = self.ly().0 as usize;
+        if ly == 0 {
+            self.frame_scys_at_scanline_0[pixel_x as usize] = self.scy.0;
+        }
+
+        let bgw_pixel = bgw_fetcher.fifo.pop_front().unwrap();
+        let bgw_color = if self.is_background_and_window_enabled() {
+            bgw_pixel.color
+        } else {
+            0
+        };
+        let obj_pixel = obj_fetcher.fifo.pop_front();
+        let pixel_y = self.ly().0;
+
+        if !self.skip_rendering {
+            let index = pixel_coordinates_in_frame_buffer(pixel_x, pixel_y);
+            if self.cgb_mode {
+                self.cgb_frame_buffer[index] = self.mix_cgb_pixels(&bgw_pixel, obj_pixel);
+            } else {
+                self.frame_buffer[index] = self.mix_pixels(bgw_color, obj_pixel);
+            }
+        }
+        self.drawn_pixels_on_current_row += 1;
+
+        if self.drawn_pixels_on_current_row as usize == LCD_HORIZONTAL_PIXEL_COUNT {
+            self.switch_to_horizontal_blank()
+        }
+    }
+
+    // Simulate pixel mixing.  FIFOs only hold pixel codes, palettes are applied here as pixels get
+    // shifted out, so that mid-scanline palette writes take effect on the next pixel.  Object
+    // color 0 is always transparent, whatever the object palette maps it to.
+    fn mix_pixels(&self, bgw_color: u8, obj_pixel: Option<ObjectFIFOItem>) -> u8 {
+        let (selected_pixel, palette) = match obj_pixel {
+            Some(obj_pixel)
+                if obj_pixel.color != 0 && !(obj_pixel.background_priority && bgw_color != 0) =>
+            {
+                (
+                    obj_pixel.color,
+                    match obj_pixel.palette {
+                        ObjectPalette::ObjectPalette0 => self.object_palette_0.0,
+                        ObjectPalette::ObjectPalette1 => self.object_palette_1.0,
+                    },
+                )
+            }
+            _ => (bgw_color, self.background_palette_data.0),
+        };
+        pixel_code_to_shade(selected_pixel, palette)
+    }
+
+    // In CGB mode, clearing LCDC bit 0 does not blank the background and window, but puts objects
+    // over them regardless of priorities.  CGB object attributes are not supported yet, so objects
+    // use object palette 0 or 1 as selected by their DMG palette bit.
+    fn mix_cgb_pixels(&self, bgw_pixel: &FIFOItem, obj_pixel: Option<ObjectFIFOItem>) -> u16 {
+        // Either priority bit puts background and window colors 1-3 over objects
+        let can_have_priority = self.is_background_and_window_enabled() && bgw_pixel.color != 0;
+        match obj_pixel {
+            Some(obj_pixel)
+                if obj_pixel.color != 0
+                    && !(can_have_priority
+                        && (bgw_pixel.priority || obj_pixel.background_priority)) =>
+            {
+                let palette = match obj_pixel.palette {
+                    ObjectPalette::ObjectPalette0 => 0,
+                    ObjectPalette::ObjectPalette1 => 1,
+                };
+                self.cgb_object_palettes.color(palette, obj_pixel.color)
+            }
+            _ => self
+                .cgb_background_palettes
+                .color(bgw_pixel.palette, bgw_pixel.color),
+        }
+    }
+
+    // Each interrupt select contributes to the STAT line while its condition holds
+    fn stat_line(&self) -> u8 {
+        let lyc_equals_ly = utils::is_bit_set(&self.lcd_status, LYC_EQUALS_LY_BIT)
+            && utils::is_bit_set(&self.lcd_status, LYC_EQUALS_LY_INTERRUPT_SELECT_BIT);
+        let mode_interrupt_select_bit = match self.state {
+            PPUState::HorizontalBlank => Some(MODE_0_INTERRUPT_SELECT_BIT),
+            PPUState::VerticalBlank => Some(MODE_1_INTERRUPT_SELECT_BIT),
+            PPUState::OAMScan => Some(MODE_2_INTERRUPT_SELECT_BIT),
+            PPUState::DrawingPixels(_) => None,
+        };
+        let mode =
+            mode_interrupt_select_bit.is_some_and(|bit| utils::is_bit_set(&self.lcd_status, bit));
+        // Quirk: line 144 starts like any other line, so entering VBlank also raises the line when
+        // the OAM scan interrupt is selected
+        let vblank_oam_scan = matches!(self.state, PPUState::VerticalBlank)
+            && self.lcd_y_coord.0 as usize == LCD_VERTICAL_PIXEL_COUNT
+            && self.scanline_dots == 0
+            && utils::is_bit_set(&self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
+        (lyc_equals_ly || mode || vblank_oam_scan) as u8
+    }
+
+    fn selected_vram_bank(&self) -> &[u8; VRAM_SIZE] {
+        if self.vram_bank.0 & 1 == 0 {
+            &self.vram
+        } else {
+            &self.vram_1
+        }
+    }
+
+    fn selected_vram_bank_mut(&mut self) -> &mut [u8; VRAM_SIZE] {
+        if self.vram_bank.0 & 1 == 0 {
+            &mut self.vram
+        } else {
+            &mut self.vram_1
+        }
+    }
+
     pub fn read_vram(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.vram[address.0 as usize])
+        Wrapping(self.selected_vram_bank()[address.0 as usize])
+    }
+
+    // Offset in `wram` of the bank mapped at 0xD000-0xDFFF
+    fn selected_wram_bank_offset(&self) -> usize {
+        let bank = (self.wram_bank.0 & WRAM_BANK_MASK).max(1);
+        bank as usize * WRAM_SIZE
+    }
+
+    /// All WRAM banks, one after the other, `WRAM_SIZE` bytes each.
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
     }
 
     pub fn read_wram_0(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.wram_0[address.0 as usize])
+        Wrapping(self.wram[address.0 as usize])
     }
 
     pub fn read_wram_1(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.wram_1[address.0 as usize])
+        Wrapping(self.wram[self.selected_wram_bank_offset() + address.0 as usize])
     }
 
     pub fn read_lcdc(&self) -> Wrapping<u8> {
         self.lcd_control
     }
 
+    pub fn read_lyc(&self) -> Wrapping<u8> {
+        self.lcd_y_compare
+    }
+
+    pub fn read_stat(&self) -> Wrapping<u8> {
+        Wrapping(LCD_STATUS_UNUSED_BITS | (self.lcd_status.0 & !LCD_STATUS_MODE_MASK) | self.mode())
+    }
+
     pub fn write_vram(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.vram[address.0 as usize] = value.0;
+        let address = address.0 as usize;
+        let vram_bank = self.vram_bank.0 & 1;
+        self.selected_vram_bank_mut()[address] = value.0;
+        self.tile_row_cache.invalidate(vram_bank, address);
+        if address < TILE_MAP0_VRAM_OFFSET {
+            let tile = vram_bank as usize * TILE_COUNT + address / TILE_DATA_SIZE;
+            self.dirty_tiles.insert(tile as u16);
+        } else {
+            let cell = address - TILE_MAP0_VRAM_OFFSET;
+            self.dirty_tile_map_cells.insert(cell as u16);
+        }
+    }
+
+    /// Tiles written since the last call, in increasing order.
+    pub fn take_dirty_tiles(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.dirty_tiles).into_iter().collect()
+    }
+
+    /// Tile map cells written since the last call, in increasing order.
+    pub fn take_dirty_tile_map_cells(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.dirty_tile_map_cells)
+            .into_iter()
+            .collect()
+    }
+
+    // Only bit 0 is used, the others read as 1
+    pub fn read_vbk(&self) -> Wrapping<u8> {
+        Wrapping(0xFE | self.vram_bank.0)
+    }
+
+    pub fn write_vbk(&mut self, value: Wrapping<u8>) {
+        self.vram_bank = Wrapping(value.0 & 1);
     }
 
     pub fn write_wram_0(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.wram_0[address.0 as usize] = value.0;
+        self.wram[address.0 as usize] = value.0;
     }
 
     pub fn write_wram_1(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.wram_1[address.0 as usize] = value.0;
+        let offset = self.selected_wram_bank_offset();
+        self.wram[offset + address.0 as usize] = value.0;
+    }
+
+    // Only bits 0-2 are used, the others read as 1
+    pub fn read_svbk(&self) -> Wrapping<u8> {
+        Wrapping(!WRAM_BANK_MASK | self.wram_bank.0)
+    }
+
+    pub fn write_svbk(&mut self, value: Wrapping<u8>) {
+        self.wram_bank = Wrapping(value.0 & WRAM_BANK_MASK);
     }
 
     pub fn write_lcdc(&mut self, value: Wrapping<u8>) {
+        let was_on = self.is_lcd_ppu_on();
         self.lcd_control = value;
+        match (was_on, self.is_lcd_ppu_on()) {
+            (true, false) => self.turn_lcd_off(),
+            (false, true) => self.first_line_after_enable = true,
+            _ => {}
+        }
+    }
+
+    // While off, LY stays at 0, the PPU reports mode 0, and the screen is blank
+    fn turn_lcd_off(&mut self) {
+        self.write_ly(Wrapping(0));
+        self.scanline_dots = 0;
+        self.drawn_pixels_on_current_row = 0;
+        self.mode_3_stall_dots = 0;
+        self.state = PPUState::HorizontalBlank;
+        self.frame_buffer.fill(0);
+        self.cgb_frame_buffer.fill(CGB_WHITE);
+    }
+
+    pub fn write_lyc(&mut self, value: Wrapping<u8>) {
+        self.lcd_y_compare = value;
+        self.update_lyc_equals_ly();
+    }
+
+    pub fn write_stat(&mut self, value: Wrapping<u8>) {
+        self.lcd_status = Wrapping(
+            (self.lcd_status.0 & !LCD_STATUS_WRITABLE_MASK) | (value.0 & LCD_STATUS_WRITABLE_MASK),
+        );
     }
 
     fn switch_to_oam_scan(
@@ -545,41 +1119,56 @@ impl PPU {
         self.drawn_pixels_on_current_row = 0;
         bgw_fetcher.prepare_for_new_row();
         obj_fetcher.prepare_for_new_row();
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 2);
-        utils::unset_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::set_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
         self.state = PPUState::OAMScan;
     }
 
     fn switch_to_drawing_pixels(&mut self, pixel_fetcher: &mut Fetcher) {
         pixel_fetcher.switch_to_background_or_window_fifo();
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 3);
+        // The first tile fetched on each scanline gets discarded
+        self.mode_3_stall_dots = 6;
+        self.fine_scroll_x = self.scx.0 % 8;
         self.state = PPUState::DrawingPixels(0);
     }
 
     fn switch_to_horizontal_blank(&mut self) {
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping(machine.ppu().lcd_status.0 & 0xFC);
-        utils::set_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
         self.state = PPUState::HorizontalBlank;
     }
 
+    // Only reached on the LY 143 -> 144 transition, so VBlank gets requested exactly once per frame
     fn switch_to_vertical_blank(&mut self, interrupts: &mut Interrupts) {
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 1);
-        utils::unset_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::set_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
         interrupts.request(VBLANK_INTERRUPT_BIT);
+        self.frame_ready = true;
+        self.frame_count += 1;
         self.state = PPUState::VerticalBlank
     }
 }
 
+// Each row takes two bytes, holding the low then high bits of its pixel codes, leftmost pixel in
+// bit 7
+fn decode_tile(tile_data: &[u8]) -> [u8; PIXELS_PER_TILE] {
+    let mut tile = [0; PIXELS_PER_TILE];
+    for (tile_pixel_y, row) in tile
+        .chunks_exact_mut(HORIZONTAL_PIXELS_PER_TILE)
+        .enumerate()
+    {
+        row.copy_from_slice(&decode_tile_row(
+            tile_data[tile_pixel_y * 2],
+            tile_data[tile_pixel_y * 2 + 1],
+        ));
+    }
+    tile
+}
+
+// Pixel codes of one tile row, from its two bit planes, by increasing X
+pub fn decode_tile_row(low_bits: u8, high_bits: u8) -> [u8; HORIZONTAL_PIXELS_PER_TILE] {
+    let mut row = [0; HORIZONTAL_PIXELS_PER_TILE];
+    for (tile_pixel_x, pixel_code) in row.iter_mut().enumerate() {
+        *pixel_code =
+            (((high_bits >> (7 - tile_pixel_x)) & 1) << 1) | ((low_bits >> (7 - tile_pixel_x)) & 1);
+    }
+    row
+}
+
 fn render_tile_map(
     vram: &[u8],
     tile_palette_pixels: &[u8],
@@ -633,3 +1222,522 @@ fn render_tile_map(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        machine::Machine,
+        test_utils::{cgb_machine_running, idle, machine_running, with_large_stack},
+    };
+
+    use super::{
+        rgb555_to_rgba, TileAddressingMode, TileMap, CLASSIC_GREEN_PALETTE, GRAYSCALE_PALETTE,
+    };
+
+    fn write(machine: &mut Machine, address: u16, value: u8) {
+        machine.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    fn write_bytes(machine: &mut Machine, address: u16, values: &[u8]) {
+        for (offset, value) in values.iter().enumerate() {
+            write(machine, address + offset as u16, *value);
+        }
+    }
+
+    #[test]
+    fn cgb_mode_follows_the_cartridge_header() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            assert!(!machine.ppu().cgb_mode);
+            machine.reset(false);
+            assert!(!machine.ppu().cgb_mode);
+
+            let mut machine = cgb_machine_running(idle());
+            assert!(machine.ppu().cgb_mode);
+            machine.reset(false);
+            assert!(machine.ppu().cgb_mode);
+        });
+    }
+
+    #[test]
+    fn cgb_tile_attributes_select_the_background_palette() {
+        with_large_stack(|| {
+            let mut machine = cgb_machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // Tile 0 is all color 1, and used everywhere, with palette 3 in the top-left cell
+            write_bytes(&mut machine, 0x8000, &[0xFF, 0x00].repeat(8));
+            write(&mut machine, 0xFF4F, 0x01);
+            write(&mut machine, 0x9800, 0x03);
+            write(&mut machine, 0xFF4F, 0x00);
+            // Color 1 is red in palette 0, blue in palette 3
+            write(&mut machine, 0xFF68, 0x80 | 0x02);
+            write(&mut machine, 0xFF69, 0x1F);
+            write(&mut machine, 0xFF69, 0x00);
+            write(&mut machine, 0xFF68, 0x80 | (3 * 8 + 2));
+            write(&mut machine, 0xFF69, 0x00);
+            write(&mut machine, 0xFF69, 0x7C);
+            write(&mut machine, 0xFF40, 0x91);
+            machine.run_frames(2);
+            let frame_buffer = &machine.ppu().cgb_frame_buffer;
+            assert_eq!(frame_buffer[0], 0x7C00);
+            assert_eq!(frame_buffer[7], 0x7C00);
+            assert_eq!(frame_buffer[8], 0x001F);
+        });
+    }
+
+    #[test]
+    fn palette_data_writes_auto_increment_the_index() {
+        with_large_stack(|| {
+            let mut machine = cgb_machine_running(idle());
+            // Palette 1: white, red, green, and blue
+            let colors = [0xFF, 0x7F, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C];
+            write(&mut machine, 0xFF68, 0x80 | 0x08);
+            for byte in colors {
+                write(&mut machine, 0xFF69, byte);
+            }
+            assert_eq!(machine.read_u8(Wrapping(0xFF68)), Wrapping(0xC0 | 0x10));
+            // Reads do not increment
+            for (offset, byte) in colors.into_iter().enumerate() {
+                write(&mut machine, 0xFF68, 0x08 + offset as u8);
+                assert_eq!(machine.read_u8(Wrapping(0xFF69)), Wrapping(byte));
+                assert_eq!(machine.read_u8(Wrapping(0xFF69)), Wrapping(byte));
+            }
+            let palettes = &machine.ppu().cgb_background_palettes;
+            let decoded = (0..4).map(|color| rgb555_to_rgba(palettes.color(1, color)));
+            assert_eq!(
+                decoded.collect::<Vec<_>>(),
+                [
+                    [0xFF, 0xFF, 0xFF, 0xFF],
+                    [0xFF, 0x00, 0x00, 0xFF],
+                    [0x00, 0xFF, 0x00, 0xFF],
+                    [0x00, 0x00, 0xFF, 0xFF],
+                ]
+            );
+
+            // Object palettes work the same, and the index wraps around
+            write(&mut machine, 0xFF6A, 0x80 | 0x3F);
+            write(&mut machine, 0xFF6B, 0x12);
+            write(&mut machine, 0xFF6B, 0x34);
+            assert_eq!(machine.read_u8(Wrapping(0xFF6A)), Wrapping(0xC1));
+            assert_eq!(machine.ppu().cgb_object_palettes.ram[0x3F], 0x12);
+            assert_eq!(machine.ppu().cgb_object_palettes.ram[0x00], 0x34);
+            // Without auto-increment, writes keep going to the same byte
+            write(&mut machine, 0xFF6A, 0x05);
+            write(&mut machine, 0xFF6B, 0x56);
+            write(&mut machine, 0xFF6B, 0x78);
+            assert_eq!(machine.read_u8(Wrapping(0xFF6A)), Wrapping(0x45));
+            assert_eq!(
+                machine.ppu().cgb_object_palettes.ram[0x05..0x07],
+                [0x78, 0x00]
+            );
+        });
+    }
+
+    #[test]
+    fn vram_banks_are_independent() {
+        with_large_stack(|| {
+            let mut machine = cgb_machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            write(&mut machine, 0x8000, 0x11);
+            write(&mut machine, 0xFF4F, 0x01);
+            write(&mut machine, 0x8000, 0x22);
+            write(&mut machine, 0xFF4F, 0x00);
+            assert_eq!(machine.read_u8(Wrapping(0x8000)), Wrapping(0x11));
+            write(&mut machine, 0xFF4F, 0x01);
+            assert_eq!(machine.read_u8(Wrapping(0x8000)), Wrapping(0x22));
+        });
+    }
+
+    fn stat_mode(machine: &Machine) -> u8 {
+        machine.read_u8(Wrapping(0xFF41)).0 & 0x03
+    }
+
+    #[test]
+    fn stat_mode_cycles_through_the_scanline_then_vblank() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            // Start at the beginning of a visible scanline
+            while stat_mode(&machine) != 0 {
+                machine.tick(1);
+            }
+            while stat_mode(&machine) != 2 {
+                machine.tick(1);
+            }
+            let mut modes = vec![];
+            for _ in 0..456 {
+                let mode = stat_mode(&machine);
+                if modes.last() != Some(&mode) {
+                    modes.push(mode);
+                }
+                machine.tick(1);
+            }
+            assert_eq!(modes, vec![2, 3, 0]);
+            assert_eq!(stat_mode(&machine), 2);
+
+            while machine.ppu().read_ly().0 != 144 {
+                machine.tick(1);
+            }
+            for _ in 0..10 * 456 {
+                assert_eq!(stat_mode(&machine), 1);
+                machine.tick(1);
+            }
+            assert_eq!(stat_mode(&machine), 2);
+            assert_eq!(machine.ppu().read_ly(), Wrapping(0));
+        });
+    }
+
+    #[test]
+    fn lyc_match_requests_the_stat_interrupt() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF41, 0x40);
+            write(&mut machine, 0xFF45, 40);
+            while machine.ppu().read_ly().0 != 0 {
+                machine.tick(4);
+            }
+            machine.interrupts_mut().interrupt_flag = Wrapping(0);
+            while machine.ppu().read_ly().0 != 40 {
+                assert_eq!(machine.interrupts().interrupt_flag.0 & 0x02, 0);
+                machine.tick(4);
+            }
+            assert_eq!(machine.interrupts().interrupt_flag.0 & 0x02, 0x02);
+            assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0x04, 0x04);
+        });
+    }
+
+    // Line and mode at which the STAT interrupt gets requested with only the `stat` interrupt
+    // selects set, starting from mode 3 of line `ly`
+    fn first_stat_interrupt(machine: &mut Machine, ly: u8, stat: u8) -> (u8, u8) {
+        write(machine, 0xFF41, 0x00);
+        while machine.ppu().read_ly().0 != ly || stat_mode(machine) != 3 {
+            machine.tick(1);
+        }
+        write(machine, 0xFF41, stat);
+        machine.interrupts_mut().interrupt_flag = Wrapping(0);
+        while machine.interrupts().interrupt_flag.0 & 0x02 == 0 {
+            machine.tick(1);
+        }
+        (machine.ppu().read_ly().0, stat_mode(machine))
+    }
+
+    #[test]
+    fn mode_interrupt_selects_request_the_stat_interrupt_on_entering_their_mode() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            assert_eq!(first_stat_interrupt(&mut machine, 10, 0x08), (10, 0));
+            assert_eq!(first_stat_interrupt(&mut machine, 10, 0x10), (144, 1));
+            assert_eq!(first_stat_interrupt(&mut machine, 10, 0x20), (11, 2));
+            // Entering VBlank also counts as an OAM scan
+            assert_eq!(first_stat_interrupt(&mut machine, 143, 0x20), (144, 1));
+        });
+    }
+
+    #[test]
+    fn ly_reads_0_for_most_of_line_153() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF45, 0);
+            while machine.ppu().read_ly().0 != 153 {
+                machine.tick(1);
+            }
+            let mut lines = vec![];
+            for _ in 0..456 {
+                assert_eq!(stat_mode(&machine), 1);
+                let ly = machine.read_u8(Wrapping(0xFF44)).0;
+                let coincidence = machine.read_u8(Wrapping(0xFF41)).0 & 0x04 != 0;
+                assert_eq!(coincidence, ly == 0);
+                match lines.last_mut() {
+                    Some((last_ly, dots)) if *last_ly == ly => *dots += 1,
+                    _ => lines.push((ly, 1)),
+                }
+                machine.tick(1);
+            }
+            assert_eq!(lines, vec![(153, 4), (0, 452)]);
+            // Line 0 then starts as usual
+            assert_eq!(stat_mode(&machine), 2);
+            assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0));
+        });
+    }
+
+    // Each row of tile 0 shows colors 0 to 3 twice, and the tile fills the background
+    fn render_color_ramp(machine: &mut Machine, background_palette: u8) {
+        write(machine, 0xFF40, 0x00);
+        write_bytes(machine, 0x8000, &[0x55, 0x33].repeat(8));
+        write_bytes(machine, 0x9800, &[0x00; 0x400]);
+        write(machine, 0xFF42, 0);
+        write(machine, 0xFF43, 0);
+        write(machine, 0xFF47, background_palette);
+        write(machine, 0xFF40, 0x91);
+        // The first frame after turning the LCD on stays blank
+        machine.run_frames(2);
+    }
+
+    #[test]
+    fn bgp_maps_color_indices_to_shades() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            render_color_ramp(&mut machine, 0b11_10_01_00);
+            assert_eq!(machine.ppu().frame_buffer[..8], [0, 1, 2, 3, 0, 1, 2, 3]);
+            render_color_ramp(&mut machine, 0b00_01_10_11);
+            for row in machine.ppu().frame_buffer.chunks(160) {
+                for (x, shade) in row.iter().enumerate() {
+                    assert_eq!(*shade, 3 - x as u8 % 4);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn clearing_lcdc_bit_0_blanks_the_background_to_bgp_color_0() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            render_color_ramp(&mut machine, 0b00_01_10_11);
+            write(&mut machine, 0xFF40, 0x90);
+            machine.run_frames(2);
+            assert!(machine.ppu().frame_buffer.iter().all(|shade| *shade == 3));
+        });
+    }
+
+    #[test]
+    fn lcd_off_holds_ly_at_0_and_enabling_restarts_from_the_top() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            render_color_ramp(&mut machine, 0b11_10_01_00);
+            while machine.ppu().read_ly().0 != 50 {
+                machine.tick(4);
+            }
+            write(&mut machine, 0xFF40, 0x11);
+            assert!(machine.ppu().frame_buffer.iter().all(|shade| *shade == 0));
+            for _ in 0..70224 / 4 {
+                assert_eq!(machine.ppu().read_ly(), Wrapping(0));
+                assert_eq!(stat_mode(&machine), 0);
+                machine.tick(4);
+            }
+
+            write(&mut machine, 0xFF40, 0x91);
+            let mut lines = vec![0];
+            while lines.last() != Some(&153) {
+                machine.tick(4);
+                let ly = machine.ppu().read_ly().0;
+                if lines.last() != Some(&ly) {
+                    lines.push(ly);
+                }
+            }
+            assert_eq!(lines, (0..154).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn to_rgba_maps_shades_through_the_screen_palette() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            render_color_ramp(&mut machine, 0b11_10_01_00);
+            let rgba = machine.ppu().to_rgba();
+            assert_eq!(rgba.len(), 160 * 144 * 4);
+            assert_eq!(rgba[..16], CLASSIC_GREEN_PALETTE.concat());
+            assert_eq!(rgba[16..32], CLASSIC_GREEN_PALETTE.concat());
+
+            machine.ppu_mut().screen_palette = GRAYSCALE_PALETTE;
+            let rgba = machine.ppu().to_rgba();
+            assert_eq!(rgba[160 * 4..160 * 4 + 16], GRAYSCALE_PALETTE.concat());
+        });
+    }
+
+    #[test]
+    fn frame_ready_is_set_on_vblank() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            while machine.ppu().read_ly().0 != 143 {
+                machine.tick(4);
+            }
+            machine.ppu_mut().frame_ready = false;
+            while machine.ppu().read_ly().0 != 144 {
+                assert!(!machine.ppu().frame_ready);
+                machine.tick(4);
+            }
+            assert!(machine.ppu().frame_ready);
+        });
+    }
+
+    #[test]
+    fn vblank_interrupt_is_requested_once_at_ly_144() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            while machine.ppu().read_ly().0 != 0 {
+                machine.tick(1);
+            }
+            machine.interrupts_mut().interrupt_flag = Wrapping(0);
+            let mut requests = Vec::new();
+            for _ in 0..70224 {
+                machine.tick(1);
+                if machine.interrupts().interrupt_flag.0 & 0x01 != 0 {
+                    requests.push(machine.ppu().read_ly().0);
+                    machine.interrupts_mut().interrupt_flag = Wrapping(0);
+                }
+            }
+            assert_eq!(requests, vec![144]);
+        });
+    }
+
+    // Mode 3 length of the next visible scanline
+    fn mode_3_dots(machine: &mut Machine) -> usize {
+        while machine.ppu().read_ly().0 != 0 || stat_mode(machine) != 2 {
+            machine.tick(1);
+        }
+        while stat_mode(machine) != 3 {
+            machine.tick(1);
+        }
+        let mut dots = 0;
+        while stat_mode(machine) == 3 {
+            machine.tick(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn objects_on_a_line_lengthen_mode_3() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            write_bytes(&mut machine, 0xFE00, &[0x00; 0xA0]);
+            write(&mut machine, 0xFF43, 0);
+            write(&mut machine, 0xFF40, 0x93);
+            let without_objects = mode_3_dots(&mut machine);
+            assert_eq!(without_objects, 172);
+
+            write(&mut machine, 0xFF40, 0x00);
+            write_bytes(&mut machine, 0xFE00, &[16, 8, 0, 0, 16, 48, 0, 0]);
+            write(&mut machine, 0xFF40, 0x93);
+            let with_objects = mode_3_dots(&mut machine);
+            assert!(with_objects > without_objects);
+
+            // Objects are not fetched while disabled
+            write(&mut machine, 0xFF40, 0x91);
+            assert_eq!(mode_3_dots(&mut machine), without_objects);
+        });
+    }
+
+    fn tick_until_mode(machine: &mut Machine, mode: u8) {
+        while stat_mode(machine) != mode {
+            machine.tick(1);
+        }
+    }
+
+    #[test]
+    fn vram_and_oam_are_blocked_while_the_ppu_uses_them() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            write(&mut machine, 0x8000, 0x42);
+            write(&mut machine, 0xFE00, 0x24);
+            write(&mut machine, 0xFF40, 0x91);
+            tick_until_mode(&mut machine, 0);
+            tick_until_mode(&mut machine, 2);
+            assert_eq!(machine.read_u8(Wrapping(0x8000)), Wrapping(0x42));
+            assert_eq!(machine.read_u8(Wrapping(0xFE00)), Wrapping(0xFF));
+            tick_until_mode(&mut machine, 3);
+            assert_eq!(machine.read_u8(Wrapping(0x8000)), Wrapping(0xFF));
+            assert_eq!(machine.read_u8(Wrapping(0xFE00)), Wrapping(0xFF));
+            // Blocked writes are dropped
+            write(&mut machine, 0x8000, 0x00);
+            tick_until_mode(&mut machine, 0);
+            assert_eq!(machine.read_u8(Wrapping(0x8000)), Wrapping(0x42));
+            assert_eq!(machine.read_u8(Wrapping(0xFE00)), Wrapping(0x24));
+        });
+    }
+
+    #[test]
+    fn dump_tiles_decodes_the_tiles_written_to_vram() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            // The example tile of Pan Docs
+            let tile = [
+                0x3C, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x5E, 0x7E, 0x0A, 0x7C, 0x56,
+                0x38, 0x7C,
+            ];
+            write_bytes(&mut machine, 0x8010, &tile);
+            write(&mut machine, 0x9801, 0x01);
+            let decoded = [
+                [0, 2, 3, 3, 3, 3, 2, 0],
+                [0, 3, 0, 0, 0, 0, 3, 0],
+                [0, 3, 0, 0, 0, 0, 3, 0],
+                [0, 3, 0, 0, 0, 0, 3, 0],
+                [0, 3, 1, 3, 3, 3, 3, 0],
+                [0, 1, 1, 1, 3, 1, 3, 0],
+                [0, 3, 1, 3, 1, 3, 2, 0],
+                [0, 2, 3, 3, 3, 2, 0, 0],
+            ];
+            let tiles = machine.ppu().dump_tiles();
+            assert_eq!(tiles.len(), 384);
+            assert_eq!(tiles[1], decoded.concat()[..]);
+            assert_eq!(tiles[0], [0; 64]);
+            assert_eq!(
+                machine.ppu().dump_tile_map(TileMap::At9800)[0][..2],
+                [0x00, 0x01]
+            );
+
+            // Tile IDs refer to tiles depending on the addressing mode
+            write(&mut machine, 0xFF40, 0x10);
+            assert_eq!(machine.ppu().tile_index(0x01), 1);
+            assert_eq!(machine.ppu().tile_index(0x81), 129);
+            write(&mut machine, 0xFF40, 0x00);
+            assert_eq!(machine.ppu().tile_index(0x01), 257);
+            assert_eq!(machine.ppu().tile_index(0x81), 129);
+        });
+    }
+
+    #[test]
+    fn lcdc_accessors_decode_their_bits() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            machine.ppu_mut().lcd_control = Wrapping(0b1010_0101);
+            let ppu = machine.ppu();
+            assert!(ppu.is_background_and_window_enabled());
+            assert!(!ppu.are_objects_enabled());
+            assert_eq!(ppu.object_height(), 16);
+            assert!(!ppu.is_background_using_tile_map1());
+            assert!(matches!(
+                ppu.get_addressing_mode(),
+                TileAddressingMode::SignedFrom0x9000
+            ));
+            assert!(ppu.is_window_enabled());
+            assert!(!ppu.is_window_using_tile_map1());
+            assert!(ppu.is_lcd_ppu_on());
+
+            machine.ppu_mut().lcd_control = Wrapping(0b0101_1010);
+            let ppu = machine.ppu();
+            assert!(!ppu.is_background_and_window_enabled());
+            assert!(ppu.are_objects_enabled());
+            assert_eq!(ppu.object_height(), 8);
+            assert!(ppu.is_background_using_tile_map1());
+            assert!(matches!(
+                ppu.get_addressing_mode(),
+                TileAddressingMode::UnsignedFrom0x8000
+            ));
+            assert!(!ppu.is_window_enabled());
+            assert!(ppu.is_window_using_tile_map1());
+            assert!(!ppu.is_lcd_ppu_on());
+        });
+    }
+
+    #[test]
+    fn take_dirty_tiles_returns_the_tiles_written_since_the_last_call() {
+        with_large_stack(|| {
+            let mut machine = machine_running(idle());
+            write(&mut machine, 0xFF40, 0x00);
+            machine.ppu_mut().take_dirty_tiles();
+            // Two writes into tile 3 and one into tile 200
+            write(&mut machine, 0x8030, 0x12);
+            write(&mut machine, 0x803F, 0x34);
+            write(&mut machine, 0x8C80, 0x56);
+            // Tile map writes are tracked separately
+            write(&mut machine, 0x9800, 0x01);
+            assert_eq!(machine.ppu_mut().take_dirty_tiles(), [3, 200]);
+            assert!(machine.ppu_mut().take_dirty_tiles().is_empty());
+        });
+    }
+}
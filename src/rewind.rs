@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use crate::machine::Machine;
+
+pub const DEFAULT_REWIND_CAPACITY: usize = 600;
+pub const DEFAULT_FRAMES_PER_SNAPSHOT: u32 = 1;
+
+/// Bounded history of save states, recorded every few frames.
+#[derive(Debug)]
+pub struct Rewind {
+    capacity: usize,
+    frames_per_snapshot: u32,
+    frames_since_snapshot: u32,
+    snapshots: VecDeque<Vec<u8>>,
+    /// Buffers of discarded snapshots, kept around so that recording does not allocate.
+    spare_buffers: Vec<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize, frames_per_snapshot: u32) -> Self {
+        Rewind {
+            capacity,
+            frames_per_snapshot,
+            frames_since_snapshot: 0,
+            snapshots: VecDeque::with_capacity(capacity),
+            spare_buffers: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    // To be called once per frame, only every `frames_per_snapshot` frames get recorded
+    pub fn record_frame(&mut self, machine: &Machine) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.frames_per_snapshot {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+        self.record(machine);
+    }
+
+    pub fn record(&mut self, machine: &Machine) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut buffer = if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front().unwrap()
+        } else {
+            self.spare_buffers.pop().unwrap_or_default()
+        };
+        buffer.clear();
+        bincode::serialize_into(&mut buffer, machine)
+            .expect("Machine state should always be serializable");
+        self.snapshots.push_back(buffer);
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        self.frames_since_snapshot = 0;
+        self.snapshots.pop_back()
+    }
+}
+
+impl Machine {
+    // Returns whether there was a snapshot to go back to
+    pub fn rewind_one(&mut self, rewind: &mut Rewind) -> bool {
+        let Some(snapshot) = rewind.pop() else {
+            return false;
+        };
+        self.load_state(&snapshot)
+            .expect("Rewind snapshots should always be loadable");
+        rewind.spare_buffers.push(snapshot);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instructions::assembler::Assembler,
+        registers::R8,
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    use super::Rewind;
+
+    #[test]
+    fn rewinding_restores_recorded_frames() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .label("loop")
+                .inc_r8(R8::A)
+                .ld_mu16_a(0xC000)
+                .jr_label("loop");
+            let mut machine = machine_running(code);
+            let mut rewind = Rewind::new(2, 2);
+            let mut states = Vec::new();
+            for _ in 0..6 {
+                machine.run_frames(1);
+                rewind.record_frame(&machine);
+                states.push(machine.save_state());
+            }
+            // Only the last two of the three snapshots, of frames 4 and 6, were kept
+            assert_eq!(rewind.len(), 2);
+            assert!(machine.rewind_one(&mut rewind));
+            assert_eq!(machine.save_state(), states[5]);
+            assert!(machine.rewind_one(&mut rewind));
+            assert_eq!(machine.save_state(), states[3]);
+            assert!(!machine.rewind_one(&mut rewind));
+            assert_eq!(machine.save_state(), states[3]);
+        });
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::machine::Machine;
+
+// RTC registers get mapped at 0xA000-0xBFFF when one of these is selected as the RAM bank
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0A;
+pub const RTC_DAY_LOW: u8 = 0x0B;
+pub const RTC_DAY_HIGH: u8 = 0x0C;
+
+const DAY_HIGH_DAY_BIT_8: u8 = 0;
+const DAY_HIGH_HALT_BIT: u8 = 6;
+const DAY_HIGH_DAY_CARRY_BIT: u8 = 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// The day counter is 9 bits wide
+const DAY_COUNTER_RANGE: u64 = 512;
+
+pub fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// MBC3 real-time clock.  Rather than ticking, the clock is derived from the wall-clock time, so
+/// that persisting `base_timestamp` is enough for it to keep running while the emulator is closed.
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct RTC {
+    /// Unix time (in seconds) at which the clock counter read zero.
+    pub base_timestamp: u64,
+    /// Unix time at which the clock was halted, if it currently is.
+    pub halted_at: Option<u64>,
+    /// Set when the day counter overflows, until cleared by a write.
+    pub day_carry: bool,
+    /// Registers as last latched, which is what reads return.
+    latched: [u8; 5],
+    /// Last value written to 0x6000-0x7FFF, latching happens on a 0x00 then 0x01 sequence.
+    last_latch_write: u8,
+}
+
+impl RTC {
+    // Not a Default, as the clock starts counting from the current wall-clock time
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        RTC {
+            base_timestamp: now_seconds(),
+            halted_at: None,
+            day_carry: false,
+            latched: [0; 5],
+            last_latch_write: 0xFF,
+        }
+    }
+
+    fn elapsed_seconds(&self, now: u64) -> u64 {
+        self.halted_at
+            .unwrap_or(now)
+            .saturating_sub(self.base_timestamp)
+    }
+
+    fn current_registers(&mut self, now: u64) -> [u8; 5] {
+        let elapsed = self.elapsed_seconds(now);
+        let mut days = elapsed / SECONDS_PER_DAY;
+        if days >= DAY_COUNTER_RANGE {
+            // Only keep the 9-bit counter, the overflow is remembered in the carry bit
+            self.day_carry = true;
+            days %= DAY_COUNTER_RANGE;
+            self.base_timestamp += (elapsed / SECONDS_PER_DAY - days) * SECONDS_PER_DAY;
+        }
+        let day_high = (((days >> 8) as u8 & 1) << DAY_HIGH_DAY_BIT_8)
+            | ((self.halted_at.is_some() as u8) << DAY_HIGH_HALT_BIT)
+            | ((self.day_carry as u8) << DAY_HIGH_DAY_CARRY_BIT);
+        [
+            (elapsed % 60) as u8,
+            (elapsed / 60 % 60) as u8,
+            (elapsed / 3600 % 24) as u8,
+            days as u8,
+            day_high,
+        ]
+    }
+
+    pub fn write_latch(&mut self, value: u8, now: u64) {
+        if self.last_latch_write == 0x00 && value == 0x01 {
+            self.latched = self.current_registers(now);
+        }
+        self.last_latch_write = value;
+    }
+
+    pub fn read_register(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    // Writes go to the live counter: the clock is rebased so that it reads the written value now
+    pub fn write_register(&mut self, register: u8, value: u8, now: u64) {
+        let mut registers = self.current_registers(now);
+        registers[(register - RTC_SECONDS) as usize] = value;
+        let [seconds, minutes, hours, day_low, day_high] = registers;
+
+        let days = ((((day_high >> DAY_HIGH_DAY_BIT_8) & 1) as u64) << 8) | day_low as u64;
+        let elapsed =
+            seconds as u64 + minutes as u64 * 60 + hours as u64 * 3600 + days * SECONDS_PER_DAY;
+        self.base_timestamp = now.saturating_sub(elapsed);
+        self.day_carry = (day_high >> DAY_HIGH_DAY_CARRY_BIT) & 1 == 1;
+        self.halted_at = if (day_high >> DAY_HIGH_HALT_BIT) & 1 == 1 {
+            Some(now)
+        } else {
+            None
+        };
+    }
+}
+
+pub fn is_rtc_register(bank: u8) -> bool {
+    (RTC_SECONDS..=RTC_DAY_HIGH).contains(&bank)
+}
+
+impl Machine {
+    pub fn rtc(&self) -> &RTC {
+        &self.rtc
+    }
+
+    pub fn rtc_mut(&mut self) -> &mut RTC {
+        &mut self.rtc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RTC, RTC_HOURS, RTC_MINUTES};
+
+    fn latch(rtc: &mut RTC, now: u64) {
+        rtc.write_latch(0x00, now);
+        rtc.write_latch(0x01, now);
+    }
+
+    #[test]
+    fn relatching_an_hour_later_increments_the_hours() {
+        let mut rtc = RTC::new();
+        let now = rtc.base_timestamp + 5 * 3600 + 42 * 60;
+        latch(&mut rtc, now);
+        assert_eq!(rtc.read_register(RTC_HOURS), 5);
+        assert_eq!(rtc.read_register(RTC_MINUTES), 42);
+
+        latch(&mut rtc, now + 3600);
+        assert_eq!(rtc.read_register(RTC_HOURS), 6);
+        assert_eq!(rtc.read_register(RTC_MINUTES), 42);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fs::File, io, io::BufWriter, path::Path};
+
+use crate::{
+    machine::Machine,
+    ppu::{LCD_HORIZONTAL_PIXEL_COUNT, LCD_VERTICAL_PIXEL_COUNT},
+};
+
+impl Machine {
+    /// Saves the last frame as a PNG, in the colors of the screen palette.
+    pub fn save_screenshot(&self, path: &Path) -> Result<(), io::Error> {
+        if self.ppu().frame_count == 0 {
+            return Err(io::Error::other("No frame has been rendered yet"));
+        }
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            LCD_HORIZONTAL_PIXEL_COUNT as u32,
+            LCD_VERTICAL_PIXEL_COUNT as u32,
+        );
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer
+            .write_image_data(&self.ppu().to_rgba())
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, num::Wrapping};
+
+    use crate::test_utils::{idle, machine_running, with_large_stack};
+
+    #[test]
+    fn saved_screenshot_holds_the_frame_in_screen_colors() {
+        with_large_stack(|| {
+            let path = std::env::temp_dir().join(format!("yokoyboi-{}.png", std::process::id()));
+            let mut machine = machine_running(idle());
+            assert!(machine.save_screenshot(&path).is_err());
+
+            // Every background pixel row reads color indices 0, 1, 2, 3, 0, ...
+            machine.write_u8(Wrapping(0xFF40), Wrapping(0x00));
+            for (offset, row) in [0x55u8, 0x33].repeat(8).into_iter().enumerate() {
+                machine.write_u8(Wrapping(0x8000 + offset as u16), Wrapping(row));
+            }
+            machine.write_u8(Wrapping(0xFF47), Wrapping(0xE4));
+            machine.write_u8(Wrapping(0xFF40), Wrapping(0x91));
+            machine.run_frames(2);
+            machine.save_screenshot(&path).unwrap();
+
+            let decoder = png::Decoder::new(File::open(&path).unwrap());
+            let mut reader = decoder.read_info().unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut pixels).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!((info.width, info.height), (160, 144));
+            assert_eq!(info.color_type, png::ColorType::Rgba);
+            assert_eq!(pixels, machine.ppu().to_rgba());
+            let palette = machine.ppu().screen_palette;
+            for (x, pixel) in pixels.chunks(4).take(8).enumerate() {
+                assert_eq!(pixel, palette[x % 4]);
+            }
+        });
+    }
+}
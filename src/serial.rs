@@ -0,0 +1,251 @@
+pub mod tcp;
+
+use std::{
+    fmt,
+    num::Wrapping,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cpu::interrupts::{Interrupts, SERIAL_INTERRUPT_BIT},
+    machine::Machine,
+    utils,
+};
+
+const SERIAL_CONTROL_TRANSFER_ENABLE_BIT: u8 = 7;
+const SERIAL_CONTROL_CLOCK_SELECT_BIT: u8 = 0;
+// Bits 1-6 are unused on DMG and read as 1
+const SERIAL_CONTROL_UNUSED_BITS: u8 = 0x7E;
+// The internal clock runs at 8192 Hz, i.e. one bit every 512 dots
+const DOTS_PER_SHIFTED_BIT: u16 = 512;
+
+/// The other end of the link cable.  Each transfer exchanges one byte with it: the Game Boy
+/// providing the clock (the master) sends its byte and receives the other's at the same time.
+pub trait SerialLink: Send {
+    /// Sends `byte` as the master, and returns the byte received in exchange.  This may block until
+    /// the other side replies, e.g. for up to 500 ms with `TcpSerialLink`.
+    fn exchange_as_master(&mut self, byte: u8) -> u8;
+    /// Waiting for an external clock: returns the master's byte if a transfer just happened, in
+    /// which case `byte` was sent in exchange.
+    fn poll_as_slave(&mut self, byte: u8) -> Option<u8>;
+}
+
+impl fmt::Debug for dyn SerialLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SerialLink")
+    }
+}
+
+/// Nothing plugged in: the line stays high, so 1s get shifted in, and no external clock ever
+/// comes.
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn exchange_as_master(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll_as_slave(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Shared by all clones of a machine, e.g. those kept for rewinding, since there is only one cable.
+pub type SharedSerialLink = Arc<Mutex<Box<dyn SerialLink>>>;
+
+pub fn disconnected_serial_link() -> SharedSerialLink {
+    Arc::new(Mutex::new(Box::new(Disconnected)))
+}
+
+#[derive(Clone, Debug, Deserialize, Hash, Serialize)]
+pub struct Serial {
+    /// SB (0xFF01): the byte being shifted out, while the received bits get shifted in.
+    pub serial_data: Wrapping<u8>,
+    /// SC (0xFF02).
+    serial_control: Wrapping<u8>,
+    /// The byte being received from the link, shifted into SB one bit at a time.
+    received_data: u8,
+    shifted_bits: u8,
+    shift_dots: u16,
+    /// Every byte sent over serial, which is how test ROMs (e.g. blargg's) report their results.
+    /// Games can send any amount of data, so this is left out of save states.
+    #[serde(skip)]
+    output: String,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            serial_data: Wrapping(0),
+            serial_control: Wrapping(0),
+            received_data: 0xFF,
+            shifted_bits: 0,
+            shift_dots: 0,
+            output: String::new(),
+        }
+    }
+
+    fn is_transferring(&self) -> bool {
+        utils::is_bit_set(&self.serial_control, SERIAL_CONTROL_TRANSFER_ENABLE_BIT)
+    }
+
+    fn is_internal_clock(&self) -> bool {
+        utils::is_bit_set(&self.serial_control, SERIAL_CONTROL_CLOCK_SELECT_BIT)
+    }
+
+    fn finish_transfer(&mut self, interrupts: &mut Interrupts) {
+        self.serial_control = utils::write_bit(
+            &self.serial_control,
+            SERIAL_CONTROL_TRANSFER_ENABLE_BIT,
+            false,
+        );
+        interrupts.request(SERIAL_INTERRUPT_BIT);
+    }
+
+    pub fn read_sc(&self) -> Wrapping<u8> {
+        Wrapping(SERIAL_CONTROL_UNUSED_BITS | self.serial_control.0)
+    }
+
+    pub fn write_sc(&mut self, value: Wrapping<u8>, link: &SharedSerialLink) {
+        self.serial_control = Wrapping(value.0 & !SERIAL_CONTROL_UNUSED_BITS);
+        if self.is_transferring() {
+            self.shifted_bits = 0;
+            self.shift_dots = 0;
+            if self.is_internal_clock() {
+                // Captured now, since the byte gets shifted out of SB as the transfer progresses
+                self.output.push(self.serial_data.0 as char);
+                // The link exchanges whole bytes, which then get shifted in at the clock's pace.
+                // This blocks emulation, within the SC write, until the other side replies: the
+                // received bits are needed from the first shift on, and the other side only replies
+                // once its own emulation polls the link, every 512 dots of its time.
+                self.received_data = link.lock().unwrap().exchange_as_master(self.serial_data.0);
+            }
+        }
+    }
+
+    pub fn tick(&mut self, interrupts: &mut Interrupts, link: &SharedSerialLink) {
+        if !self.is_transferring() {
+            return;
+        }
+        self.shift_dots += 1;
+        if self.shift_dots < DOTS_PER_SHIFTED_BIT {
+            return;
+        }
+        self.shift_dots = 0;
+
+        if !self.is_internal_clock() {
+            // The master clocks all 8 bits in one go, as far as we can tell
+            if let Some(byte) = link.lock().unwrap().poll_as_slave(self.serial_data.0) {
+                self.output.push(self.serial_data.0 as char);
+                self.serial_data = Wrapping(byte);
+                self.finish_transfer(interrupts);
+            }
+            return;
+        }
+        self.serial_data = Wrapping((self.serial_data.0 << 1) | (self.received_data >> 7));
+        self.received_data <<= 1;
+        self.shifted_bits += 1;
+        if self.shifted_bits == 8 {
+            self.finish_transfer(interrupts);
+        }
+    }
+
+    pub fn ticks(&mut self, interrupts: &mut Interrupts, link: &SharedSerialLink, dots: u8) {
+        for _ in 0..dots {
+            self.tick(interrupts, link);
+        }
+    }
+}
+
+impl Machine {
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn serial_output(&self) -> &str {
+        &self.serial().output
+    }
+
+    /// Plugs `link` in, in place of the current one.  Clones made from now on share it.
+    pub fn connect_serial_link(&mut self, link: impl SerialLink + 'static) {
+        self.serial_link = Arc::new(Mutex::new(Box::new(link)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        cpu::interrupts::Interrupts,
+        test_utils::{machine_with_rom, with_large_stack},
+    };
+
+    use super::{disconnected_serial_link, Serial, DOTS_PER_SHIFTED_BIT};
+
+    #[test]
+    fn internal_clock_transfer_completes_with_an_interrupt() {
+        let link = disconnected_serial_link();
+        let mut interrupts = Interrupts::new();
+        let mut serial = Serial::new();
+        serial.serial_data = Wrapping(0x42);
+        serial.write_sc(Wrapping(0x81), &link);
+        for _ in 0..8 * DOTS_PER_SHIFTED_BIT - 1 {
+            serial.tick(&mut interrupts, &link);
+        }
+        assert_eq!(serial.read_sc().0 & 0x80, 0x80);
+        assert_eq!(interrupts.interrupt_flag.0 & 0x08, 0);
+        serial.tick(&mut interrupts, &link);
+        assert_eq!(serial.read_sc().0 & 0x80, 0);
+        assert_eq!(interrupts.interrupt_flag.0 & 0x08, 0x08);
+        // Nothing is connected, so 1s got shifted in
+        assert_eq!(serial.serial_data, Wrapping(0xFF));
+    }
+
+    #[test]
+    fn bytes_sent_through_sb_and_sc_are_captured() {
+        with_large_stack(|| {
+            let mut machine = machine_with_rom(vec![0; 0x8000]);
+            for byte in b"Passed" {
+                machine.write_u8(Wrapping(0xFF01), Wrapping(*byte));
+                machine.write_u8(Wrapping(0xFF02), Wrapping(0x81));
+                for _ in 0..8 * DOTS_PER_SHIFTED_BIT {
+                    machine.tick(1);
+                }
+            }
+            assert_eq!(machine.serial_output(), "Passed");
+        });
+    }
+
+    #[test]
+    fn output_is_not_saved() {
+        let link = disconnected_serial_link();
+        let mut interrupts = Interrupts::new();
+        let mut serial = Serial::new();
+        let empty_state = bincode::serialize(&serial).unwrap();
+        for byte in b"Passed" {
+            serial.serial_data = Wrapping(*byte);
+            serial.write_sc(Wrapping(0x81), &link);
+            for _ in 0..8 * DOTS_PER_SHIFTED_BIT {
+                serial.tick(&mut interrupts, &link);
+            }
+        }
+        assert_eq!(serial.output, "Passed");
+        let state = bincode::serialize(&serial).unwrap();
+        assert_eq!(state.len(), empty_state.len());
+        let restored: Serial = bincode::deserialize(&state).unwrap();
+        assert_eq!(restored.output, "");
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::SerialLink;
+
+// How long the master waits for the other side's byte before giving up on it
+const EXCHANGE_TIMEOUT: Duration = Duration::from_millis(500);
+const EXCHANGE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+// Every message is a tag, the sequence number of the transfer, and the byte exchanged
+const MESSAGE_SIZE: usize = 3;
+const MASTER_MESSAGE: u8 = 0x01;
+const REPLY_MESSAGE: u8 = 0x02;
+
+/// A link cable to another emulator over TCP.  Each side sends its byte when a transfer happens:
+/// the master when it starts one, the slave in reply to the master's byte.  Replies carry the
+/// sequence number of the master's transfer, so that a reply arriving after the master gave up on
+/// it does not get mistaken for the reply to a later transfer.
+pub struct TcpSerialLink {
+    // Non-blocking, so that the slave can poll it
+    stream: TcpStream,
+    // Why the link got disconnected, after which transfers complete as if nothing was connected
+    error: Option<io::Error>,
+    // Bytes received that do not make up a whole message yet
+    received: Vec<u8>,
+    sequence: u8,
+}
+
+impl TcpSerialLink {
+    pub fn from_stream(stream: TcpStream) -> Result<Self, io::Error> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpSerialLink {
+            stream,
+            error: None,
+            received: Vec::new(),
+            sequence: 0,
+        })
+    }
+
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self, io::Error> {
+        TcpSerialLink::from_stream(TcpStream::connect(address)?)
+    }
+
+    // Blocks until the other side connects
+    pub fn accept(listener: &TcpListener) -> Result<Self, io::Error> {
+        let (stream, _) = listener.accept()?;
+        TcpSerialLink::from_stream(stream)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The error that disconnected the link, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    fn disconnect(&mut self, error: io::Error) {
+        self.error = Some(error);
+    }
+
+    fn send(&mut self, message: [u8; MESSAGE_SIZE]) {
+        // The stream is non-blocking, but a few bytes always fit in its buffer
+        if let Err(error) = self.stream.write_all(&message) {
+            self.disconnect(error);
+        }
+    }
+
+    fn try_receive(&mut self) -> Option<[u8; MESSAGE_SIZE]> {
+        while self.is_connected() && self.received.len() < MESSAGE_SIZE {
+            let mut bytes = [0; MESSAGE_SIZE];
+            match self.stream.read(&mut bytes) {
+                Ok(0) => self.disconnect(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(count) => self.received.extend_from_slice(&bytes[..count]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return None,
+                Err(error) => self.disconnect(error),
+            }
+        }
+        if !self.is_connected() {
+            return None;
+        }
+        let message: [u8; MESSAGE_SIZE] = self.received[..MESSAGE_SIZE].try_into().unwrap();
+        self.received.drain(..MESSAGE_SIZE);
+        match message[0] {
+            MASTER_MESSAGE | REPLY_MESSAGE => Some(message),
+            tag => {
+                self.disconnect(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown message tag 0x{:02X}", tag),
+                ));
+                None
+            }
+        }
+    }
+}
+
+impl SerialLink for TcpSerialLink {
+    // Without a reply in time, the transfer completes as if nothing was connected.  That includes
+    // the other side starting a transfer as master too: neither provides a clock to the other, so
+    // both ignore the other's byte and time out.
+    fn exchange_as_master(&mut self, byte: u8) -> u8 {
+        if !self.is_connected() {
+            return 0xFF;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.send([MASTER_MESSAGE, self.sequence, byte]);
+        let start = Instant::now();
+        while self.is_connected() && start.elapsed() < EXCHANGE_TIMEOUT {
+            match self.try_receive() {
+                Some([REPLY_MESSAGE, sequence, received]) if sequence == self.sequence => {
+                    return received
+                }
+                // Late replies to earlier transfers, or the other side acting as master
+                Some(_) => {}
+                None => thread::sleep(EXCHANGE_POLL_INTERVAL),
+            }
+        }
+        0xFF
+    }
+
+    fn poll_as_slave(&mut self, byte: u8) -> Option<u8> {
+        while self.is_connected() {
+            // Replies to our own transfers as master that came too late get dropped
+            if let [MASTER_MESSAGE, sequence, received] = self.try_receive()? {
+                self.send([REPLY_MESSAGE, sequence, byte]);
+                return Some(received);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        instructions::assembler::Assembler,
+        serial::SerialLink,
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    use super::{TcpSerialLink, MASTER_MESSAGE, MESSAGE_SIZE, REPLY_MESSAGE};
+
+    const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // A link, and the raw stream at the other end of it
+    fn link_and_peer() -> (TcpSerialLink, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let link = TcpSerialLink::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (link, peer)
+    }
+
+    // Runs a machine writing `byte` to SB and `control` to SC, until the transfer is over, and
+    // returns what SB then holds
+    fn transfer(link: TcpSerialLink, byte: u8, control: u8) -> u8 {
+        let code = Assembler::new(0x150)
+            .ld_a_u8(byte)
+            .ldh_u8_a(0x01)
+            .ld_a_u8(control)
+            .ldh_u8_a(0x02)
+            .label("wait")
+            .jr_label("wait");
+        let wait_address = code.origin() + 8;
+        let mut machine = machine_running(code);
+        machine.connect_serial_link(link);
+        let start = Instant::now();
+        while machine.registers().pc.0 != wait_address || machine.serial().read_sc().0 & 0x80 != 0 {
+            assert!(start.elapsed() < TRANSFER_TIMEOUT, "Transfer did not end");
+            machine.step();
+        }
+        machine.serial().serial_data.0
+    }
+
+    #[test]
+    fn machines_exchange_bytes_over_localhost() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let slave = thread::spawn(move || {
+            with_large_stack(move || {
+                let link = TcpSerialLink::accept(&listener).unwrap();
+                assert_eq!(transfer(link, 0x99, 0x80), 0x42);
+            })
+        });
+        with_large_stack(move || {
+            let link = TcpSerialLink::connect(address).unwrap();
+            assert_eq!(transfer(link, 0x42, 0x81), 0x99);
+        });
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn late_reply_is_not_taken_for_the_next_one() {
+        let (mut link, mut peer) = link_and_peer();
+        assert_eq!(link.exchange_as_master(0x42), 0xFF);
+        let mut message = [0; MESSAGE_SIZE];
+        peer.read_exact(&mut message).unwrap();
+        assert_eq!(message, [MASTER_MESSAGE, 1, 0x42]);
+        peer.write_all(&[REPLY_MESSAGE, 1, 0x11]).unwrap();
+        let replier = thread::spawn(move || {
+            peer.read_exact(&mut message).unwrap();
+            assert_eq!(message, [MASTER_MESSAGE, 2, 0x43]);
+            peer.write_all(&[REPLY_MESSAGE, 2, 0x22]).unwrap();
+            peer
+        });
+        assert_eq!(link.exchange_as_master(0x43), 0x22);
+        replier.join().unwrap();
+    }
+
+    #[test]
+    fn two_masters_both_time_out() {
+        let (mut link, mut peer) = link_and_peer();
+        peer.write_all(&[MASTER_MESSAGE, 1, 0x99]).unwrap();
+        assert_eq!(link.exchange_as_master(0x42), 0xFF);
+        // The other master's byte is not left around for a later transfer as slave either
+        assert_eq!(link.poll_as_slave(0x42), None);
+    }
+
+    #[test]
+    fn closed_peer_disconnects_the_link() {
+        let (mut link, peer) = link_and_peer();
+        drop(peer);
+        assert_eq!(link.exchange_as_master(0x42), 0xFF);
+        assert!(!link.is_connected());
+        assert!(link.error().is_some());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{fmt::Debug, num::Wrapping};
+
+use serde::Serialize;
+
+use crate::{machine::Machine, ppu::WRAM_SIZE};
+
+const PPU_REGISTERS: [(&str, u16); 11] = [
+    ("LCDC", 0xFF40),
+    ("STAT", 0xFF41),
+    ("SCY", 0xFF42),
+    ("SCX", 0xFF43),
+    ("LY", 0xFF44),
+    ("LYC", 0xFF45),
+    ("BGP", 0xFF47),
+    ("OBP0", 0xFF48),
+    ("OBP1", 0xFF49),
+    ("WY", 0xFF4A),
+    ("WX", 0xFF4B),
+];
+
+fn diff_value<T: Debug + PartialEq>(diffs: &mut Vec<String>, name: &str, a: T, b: T) {
+    if a != b {
+        diffs.push(format!("{}: {:?} != {:?}", name, a, b));
+    }
+}
+
+// Only the first difference gets reported, along with how many bytes differ overall
+fn diff_bytes(diffs: &mut Vec<String>, name: &str, base_address: usize, a: &[u8], b: &[u8]) {
+    if a.len() != b.len() {
+        diffs.push(format!("{}: {} bytes != {} bytes", name, a.len(), b.len()));
+        return;
+    }
+    let mut differing = a.iter().zip(b).enumerate().filter(|(_, (a, b))| a != b);
+    if let Some((offset, (a, b))) = differing.next() {
+        diffs.push(format!(
+            "{} at 0x{:04X}: 0x{:02X} != 0x{:02X}, {} differing in total",
+            name,
+            base_address + offset,
+            a,
+            b,
+            1 + differing.count()
+        ));
+    }
+}
+
+// For subsystems not worth comparing field by field, compares them as saved in save states
+fn diff_state<T: Serialize>(diffs: &mut Vec<String>, name: &str, a: &T, b: &T) {
+    let serialize = |state| bincode::serialize(state).expect("State should be serializable");
+    if serialize(a) != serialize(b) {
+        diffs.push(format!("{}: internal state differs", name));
+    }
+}
+
+impl Machine {
+    /// Describes every difference between this machine and `other`, e.g. to find where two runs
+    /// that should be identical diverge.  Memory regions only report their first differing
+    /// address.  Host-side state, like breakpoints or cheats, is not compared.
+    pub fn diff(&self, other: &Machine) -> Vec<String> {
+        let mut diffs = Vec::new();
+
+        diff_value(&mut diffs, "Dots", self.dot_count, other.dot_count);
+
+        // Whatever the detailed comparisons of a subsystem miss is only reported when they found
+        // nothing, since its state includes what they compare
+        let diff_count = diffs.len();
+        let (a, b) = (self.registers(), other.registers());
+        diff_value(&mut diffs, "AF", a.af, b.af);
+        diff_value(&mut diffs, "BC", a.bc, b.bc);
+        diff_value(&mut diffs, "DE", a.de, b.de);
+        diff_value(&mut diffs, "HL", a.hl, b.hl);
+        diff_value(&mut diffs, "SP", a.sp, b.sp);
+        diff_value(&mut diffs, "PC", a.pc, b.pc);
+        let (a, b) = (self.cpu(), other.cpu());
+        diff_value(&mut diffs, "CPU halted", a.low_power_mode, b.low_power_mode);
+        diff_value(&mut diffs, "CPU stopped", a.stopped, b.stopped);
+        diff_value(&mut diffs, "CPU locked up", a.locked_up, b.locked_up);
+        diff_value(
+            &mut diffs,
+            "CPU double speed",
+            a.double_speed,
+            b.double_speed,
+        );
+        let (a, b) = (self.memory(), other.memory());
+        diff_bytes(&mut diffs, "HRAM", 0xFF80, &a.hram, &b.hram);
+        // Reported as offsets within the whole external RAM, rather than addresses in a bank
+        diff_bytes(&mut diffs, "External RAM", 0, &a.game_ram, &b.game_ram);
+        if diffs.len() == diff_count {
+            diff_state(&mut diffs, "CPU", self.cpu(), other.cpu());
+        }
+
+        let (a, b) = (self.interrupts(), other.interrupts());
+        diff_value(
+            &mut diffs,
+            "IME",
+            a.interrupt_master_enable,
+            b.interrupt_master_enable,
+        );
+        diff_value(&mut diffs, "IE", a.interrupt_enable, b.interrupt_enable);
+        diff_value(&mut diffs, "IF", a.interrupt_flag, b.interrupt_flag);
+
+        let diff_count = diffs.len();
+        let (a, b) = (self.timers(), other.timers());
+        diff_value(
+            &mut diffs,
+            "System counter",
+            a.system_counter,
+            b.system_counter,
+        );
+        diff_value(&mut diffs, "TIMA", a.timer_counter, b.timer_counter);
+        diff_value(&mut diffs, "TMA", a.timer_modulo, b.timer_modulo);
+        diff_value(&mut diffs, "TAC", a.timer_control, b.timer_control);
+        if diffs.len() == diff_count {
+            diff_state(&mut diffs, "Timers", a, b);
+        }
+
+        // The banks actually mapped account for the banking mode
+        diff_value(
+            &mut diffs,
+            "RAM enabled",
+            self.is_ram_enabled,
+            other.is_ram_enabled,
+        );
+        diff_value(
+            &mut diffs,
+            "ROM bank",
+            self.rom_bank_at(0x4000),
+            other.rom_bank_at(0x4000),
+        );
+        diff_value(
+            &mut diffs,
+            "RAM bank",
+            self.ram_or_hiram_bank,
+            other.ram_or_hiram_bank,
+        );
+
+        let diff_count = diffs.len();
+        // Reading these has no side effect, unlike writing them
+        for (name, address) in PPU_REGISTERS {
+            diff_value(
+                &mut diffs,
+                name,
+                self.read_u8_unrestricted(Wrapping(address)),
+                other.read_u8_unrestricted(Wrapping(address)),
+            );
+        }
+        let (a, b) = (self.ppu(), other.ppu());
+        diff_bytes(&mut diffs, "VRAM bank 0", 0x8000, &a.vram, &b.vram);
+        diff_bytes(&mut diffs, "VRAM bank 1", 0x8000, &a.vram_1, &b.vram_1);
+        diff_bytes(
+            &mut diffs,
+            "OAM",
+            0xFE00,
+            &a.object_attribute_memory,
+            &b.object_attribute_memory,
+        );
+        for (bank, (a, b)) in a
+            .wram()
+            .chunks(WRAM_SIZE)
+            .zip(b.wram().chunks(WRAM_SIZE))
+            .enumerate()
+        {
+            let base_address = if bank == 0 { 0xC000 } else { 0xD000 };
+            diff_bytes(
+                &mut diffs,
+                &format!("WRAM bank {}", bank),
+                base_address,
+                a,
+                b,
+            );
+        }
+        if diffs.len() == diff_count {
+            diff_state(&mut diffs, "PPU", a, b);
+        }
+
+        diff_state(
+            &mut diffs,
+            "Background/window fetcher",
+            &self.background_window_fetcher,
+            &other.background_window_fetcher,
+        );
+        diff_state(
+            &mut diffs,
+            "Object fetcher",
+            &self.object_fetcher,
+            &other.object_fetcher,
+        );
+        diff_state(&mut diffs, "APU", self.apu(), other.apu());
+        diff_state(&mut diffs, "OAM DMA", self.dma(), other.dma());
+        diff_state(&mut diffs, "HDMA", self.hdma(), other.hdma());
+        diff_state(&mut diffs, "Serial", self.serial(), other.serial());
+        diff_state(&mut diffs, "RTC", self.rtc(), other.rtc());
+        diff_state(&mut diffs, "Inputs", self.inputs(), other.inputs());
+
+        diffs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::test_utils::{idle, machine_running, with_large_stack};
+
+    #[test]
+    fn diff_names_the_differing_wram_address() {
+        with_large_stack(|| {
+            let machine = machine_running(idle());
+            let mut other = Box::new((*machine).clone());
+            assert_eq!(machine.diff(&other), Vec::<String>::new());
+
+            let value = machine.read_u8(Wrapping(0xD123)).0;
+            other.write_u8(Wrapping(0xD123), Wrapping(value ^ 0xFF));
+            assert_eq!(
+                machine.diff(&other),
+                vec![format!(
+                    "WRAM bank 1 at 0xD123: 0x{:02X} != 0x{:02X}, 1 differing in total",
+                    value,
+                    value ^ 0xFF
+                )]
+            );
+        });
+    }
+}
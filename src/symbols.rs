@@ -0,0 +1,144 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use crate::machine::Machine;
+
+/// Labels of a `.sym` file, as produced by RGBDS or WLA-DX: one `BB:AAAA Label` per line, where
+/// BB is the bank and AAAA the address, both in hexadecimal.  `;` starts a comment.
+#[derive(Clone, Debug)]
+pub struct Symbols {
+    labels: BTreeMap<(u16, u16), String>,
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Symbols::new()
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            labels: BTreeMap::new(),
+        }
+    }
+
+    // Malformed lines get skipped rather than failing the whole file, and are returned alongside
+    // the symbols for the caller to report
+    pub fn parse(text: &str) -> (Self, Vec<&str>) {
+        let mut symbols = Symbols::new();
+        let mut skipped_lines = Vec::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_symbol(line) {
+                Some((bank, address, label)) => symbols.add(bank, address, label),
+                None => skipped_lines.push(line),
+            }
+        }
+        (symbols, skipped_lines)
+    }
+
+    pub fn add(&mut self, bank: u16, address: u16, label: &str) {
+        self.labels.insert((bank, address), label.to_string());
+    }
+
+    pub fn clear(&mut self) {
+        self.labels.clear();
+    }
+
+    /// The label at `address` in `bank`, or in any bank when `bank` is `None`.
+    pub fn label(&self, bank: Option<u16>, address: u16) -> Option<&str> {
+        match bank {
+            Some(bank) => self.labels.get(&(bank, address)),
+            None => self
+                .labels
+                .iter()
+                .find(|((_, labelled_address), _)| *labelled_address == address)
+                .map(|(_, label)| label),
+        }
+        .map(|label| label.as_str())
+    }
+}
+
+fn parse_symbol(line: &str) -> Option<(u16, u16, &str)> {
+    let (location, label) = line.split_once(char::is_whitespace)?;
+    let (bank, address) = location.split_once(':')?;
+    Some((
+        u16::from_str_radix(bank, 16).ok()?,
+        u16::from_str_radix(address, 16).ok()?,
+        label.trim(),
+    ))
+}
+
+impl Machine {
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut Symbols {
+        &mut self.symbols
+    }
+
+    // Replaces the symbols, typically those of the game being run.  Returns the malformed lines
+    // that got skipped.
+    pub fn load_symbols(&mut self, path: &Path) -> Result<Vec<String>, io::Error> {
+        let text = std::fs::read_to_string(path)?;
+        let (symbols, skipped_lines) = Symbols::parse(&text);
+        *self.symbols_mut() = symbols;
+        Ok(skipped_lines.into_iter().map(String::from).collect())
+    }
+
+    /// The label at `address`.  In ROM, only labels of the bank currently mapped there match.
+    pub fn label(&self, address: u16) -> Option<&str> {
+        let bank = self.rom_bank_at(address).map(|bank| bank as u16);
+        self.symbols().label(bank, address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        instructions::assembler::Assembler,
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    use super::Symbols;
+
+    #[test]
+    fn parse_returns_skipped_lines() {
+        let (symbols, skipped_lines) = Symbols::parse(
+            "; RGBDS symbols\n00:0150 Start\n01:4000 Bank1Data ; comment\nnot a symbol\n\n00:XYZ Bad\n",
+        );
+        assert_eq!(symbols.label(Some(0), 0x0150), Some("Start"));
+        assert_eq!(symbols.label(Some(1), 0x4000), Some("Bank1Data"));
+        assert_eq!(symbols.label(Some(0), 0x4000), None);
+        assert_eq!(symbols.label(None, 0x4000), Some("Bank1Data"));
+        assert_eq!(skipped_lines, vec!["not a symbol", "00:XYZ Bad"]);
+    }
+
+    #[test]
+    fn call_targets_render_with_their_label() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x150)
+                .call_label("init_video")
+                .call_label("unlabelled")
+                .halt()
+                .label("init_video")
+                .ret()
+                .label("unlabelled")
+                .ret();
+            let mut machine = machine_running(code);
+            let (symbols, _) = Symbols::parse("00:0157 InitVideo");
+            *machine.symbols_mut() = symbols;
+            let (_, size, text) = machine.disassemble(Wrapping(0x150));
+            assert_eq!(size, 3);
+            assert_eq!(text, "CALL InitVideo");
+            let (_, _, text) = machine.disassemble(Wrapping(0x153));
+            assert_eq!(text, "CALL $0158");
+        });
+    }
+}
@@ -0,0 +1,73 @@
+//! Helpers shared by the unit tests of the various modules, and by the benchmarks.
+
+use crate::{cartridge::Cartridge, instructions::assembler::Assembler, machine::Machine};
+
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+
+// A machine is large enough that the few copies a debug build keeps on the stack overflow the
+// default stack of test threads
+const STACK_SIZE: usize = 64 << 20;
+
+/// Runs `test` on a thread with enough stack for machines, propagating its panics.
+pub fn with_large_stack(test: impl FnOnce() + Send + 'static) {
+    let result = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(test)
+        .expect("Test thread should spawn")
+        .join();
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+/// Code jumping to itself forever, for tests driving the other components.
+pub fn idle() -> Assembler {
+    Assembler::new(0x0150).label("idle").jr_label("idle")
+}
+
+/// A machine past the boot ROM, running `rom` with the cartridge its header describes.
+pub fn machine_with_rom(rom: Vec<u8>) -> Box<Machine> {
+    let cartridge = Cartridge::from_header(&rom).expect("Test ROM should have a header");
+    Box::new(Machine::new_post_boot(rom, cartridge, false))
+}
+
+/// A machine past the boot ROM, about to execute the first instruction of `code`.
+pub fn machine_running(code: Assembler) -> Box<Machine> {
+    let origin = code.origin();
+    machine_running_rom(code.rom(), origin)
+}
+
+/// Like `machine_running`, with a header flagging the game as supporting CGB enhancements.
+pub fn cgb_machine_running(code: Assembler) -> Box<Machine> {
+    let origin = code.origin();
+    let mut rom = code.rom();
+    rom[CGB_FLAG_ADDRESS] = 0x80;
+    machine_running_rom(rom, origin)
+}
+
+fn machine_running_rom(rom: Vec<u8>, origin: u16) -> Box<Machine> {
+    let mut machine = machine_with_rom(rom);
+    while machine.registers().pc.0 != origin {
+        machine.step();
+    }
+    machine
+}
+
+/// Steps `machine` until PC reaches `address`, panicking after `max_steps` steps.
+pub fn step_until_pc(machine: &mut Machine, address: u16, max_steps: usize) {
+    for _ in 0..max_steps {
+        if machine.registers().pc.0 == address {
+            return;
+        }
+        machine.step();
+    }
+    panic!("PC did not reach 0x{:04X} in {} steps", address, max_steps);
+}
+
+/// FNV-1a hash of a frame buffer, which unlike `DefaultHasher` is stable across Rust releases, so
+/// that tests can compare frames against hard-coded hashes.
+pub fn frame_hash(frame_buffer: &[u8]) -> u64 {
+    frame_buffer.iter().fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
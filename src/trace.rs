@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+
+use crate::{instructions::decode::DecodedInstruction, machine::Machine, registers::Registers};
+
+/// An executed instruction, along with the state it started from, as in the logs of other
+/// emulators.
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub pc: u16,
+    /// The instruction bytes, opcode first.
+    pub bytes: Vec<u8>,
+    /// Flags are in F.
+    pub registers: Registers,
+    pub ime: bool,
+    pub dot_count: u64,
+}
+
+/// The last executed instructions, up to `capacity`.  Nothing gets recorded while the capacity is
+/// 0, which is the default.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    capacity: usize,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Trace::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            capacity: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    fn record(&mut self, entry: TraceEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+impl Machine {
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    pub fn trace_mut(&mut self) -> &mut Trace {
+        &mut self.trace
+    }
+
+    /// Starts recording executed instructions, keeping the last `capacity` ones.
+    pub fn start_tracing(&mut self, capacity: usize) {
+        *self.trace_mut() = Trace {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        };
+    }
+
+    pub fn stop_tracing(&mut self) -> Trace {
+        std::mem::take(self.trace_mut())
+    }
+
+    // The state the next instruction starts from, only captured while tracing as this runs for
+    // every instruction
+    pub fn begin_trace_entry(&self) -> Option<TraceEntry> {
+        self.trace().is_enabled().then(|| TraceEntry {
+            pc: self.registers().pc.0,
+            bytes: Vec::new(),
+            registers: self.registers().clone(),
+            ime: self.interrupts().interrupt_master_enable,
+            dot_count: self.dot_count,
+        })
+    }
+
+    pub fn end_trace_entry(&mut self, entry: TraceEntry, instruction: &DecodedInstruction) {
+        self.trace_mut().record(TraceEntry {
+            pc: instruction.address.0,
+            bytes: instruction.raw.iter().map(|byte| byte.0).collect(),
+            ..entry
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instructions::assembler::Assembler,
+        registers::R8,
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    #[test]
+    fn trace_keeps_the_last_executed_instructions() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .ld_a_u8(0x12)
+                .ld_r8_r8(R8::B, R8::A)
+                .ei()
+                .nop()
+                .inc_r8(R8::B);
+            let mut machine = machine_running(code);
+            machine.step();
+            assert!(machine.trace().entries().next().is_none());
+
+            machine.start_tracing(3);
+            for _ in 0..4 {
+                machine.step();
+            }
+            let entries: Vec<_> = machine.trace().entries().collect();
+            let summary: Vec<_> = entries
+                .iter()
+                .map(|entry| (entry.pc, entry.bytes.clone(), entry.ime))
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    (0x0153, vec![0xFB], false),
+                    (0x0154, vec![0x00], false),
+                    (0x0155, vec![0x04], true),
+                ]
+            );
+            assert_eq!(entries[2].registers.read_r8(&R8::B).0, 0x12);
+            assert_eq!(entries[2].registers.read_a().0, 0x12);
+            assert_eq!(entries[1].dot_count - entries[0].dot_count, 4);
+            assert_eq!(entries[2].dot_count - entries[1].dot_count, 4);
+
+            let trace = machine.stop_tracing();
+            assert_eq!(trace.entries().count(), 3);
+            machine.step();
+            assert!(!machine.trace().is_enabled());
+            assert!(machine.trace().entries().next().is_none());
+        });
+    }
+}
@@ -17,7 +17,7 @@ impl ApplicationState {
         let debugger_view = debugger::view(app);
 
         // let cycle_row =
-        //     widget::Row::new().push(widget::text(format!("Cycles: {}", machine.t_cycle_count)));
+        //     widget::Row::new().push(widget::text(format!("Cycles: {}", machine.dot_count)));
 
         let mut grid = Grid::new().vertical_alignment(alignment::Vertical::Bottom);
 
@@ -36,7 +36,7 @@ impl ApplicationState {
             widget::Image::new(image::Handle::from_rgba(
                 160,
                 144,
-                image::Bytes::copy_from_slice(&machine.ppu().lcd_pixels),
+                image::Bytes::from(machine.ppu().to_rgba()),
             ))
             .content_fit(iced::ContentFit::Fill)
             .filter_method(FilterMethod::Nearest)
@@ -0,0 +1,159 @@
+use std::{cell::Cell, num::Wrapping};
+
+use crate::machine::Machine;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchpointAccess {
+    Read,
+    Write,
+    ReadOrWrite,
+}
+
+impl WatchpointAccess {
+    fn matches(&self, access: WatchpointAccess) -> bool {
+        *self == WatchpointAccess::ReadOrWrite || *self == access
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub access: WatchpointAccess,
+    /// When set, only accesses reading or writing this value trigger the watchpoint.
+    pub value: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchpointHit {
+    pub address: u16,
+    /// Either `Read` or `Write`, the access that triggered the watchpoint.
+    pub access: WatchpointAccess,
+    /// The value read or written.
+    pub value: u8,
+}
+
+/// Memory watchpoints, checked by `Machine::read_u8` and `Machine::write_u8`.
+#[derive(Clone, Debug)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    // Reads go through `&Machine`, hence the interior mutability
+    hit: Cell<Option<WatchpointHit>>,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Watchpoints::new()
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints {
+            watchpoints: Vec::new(),
+            hit: Cell::new(None),
+        }
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+        self.hit.set(None);
+    }
+
+    // Only the first hit is kept until `take_hit()` is called
+    pub fn check(&self, address: Wrapping<u16>, access: WatchpointAccess, value: Wrapping<u8>) {
+        if self.hit.get().is_some() {
+            return;
+        }
+        let is_hit = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.address == address.0
+                && watchpoint.access.matches(access)
+                && watchpoint.value.is_none_or(|expected| expected == value.0)
+        });
+        if is_hit {
+            self.hit.set(Some(WatchpointHit {
+                address: address.0,
+                access,
+                value: value.0,
+            }));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<WatchpointHit> {
+        self.hit.take()
+    }
+}
+
+impl Machine {
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints_mut().add(watchpoint);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use crate::{
+        instructions::assembler::Assembler,
+        test_utils::{machine_running, with_large_stack},
+    };
+
+    use super::{Watchpoint, WatchpointAccess};
+
+    #[test]
+    fn writes_to_watched_addresses_report_the_value() {
+        with_large_stack(|| {
+            let code = Assembler::new(0x0150)
+                .ld_a_u8(0x42)
+                .ld_mu16_a(0xC123)
+                .ld_a_u8(0x24)
+                .ld_mu16_a(0xC123)
+                .label("idle")
+                .jr_label("idle");
+            let mut machine = machine_running(code);
+            machine.add_watchpoint(Watchpoint {
+                address: 0xC123,
+                access: WatchpointAccess::Write,
+                value: Some(0x24),
+            });
+            machine.add_watchpoint(Watchpoint {
+                address: 0xC124,
+                access: WatchpointAccess::ReadOrWrite,
+                value: None,
+            });
+            let hits: Vec<_> = (0..6)
+                .filter_map(|_| machine.step().watchpoint_hit)
+                .collect();
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].address, 0xC123);
+            assert_eq!(hits[0].access, WatchpointAccess::Write);
+            assert_eq!(hits[0].value, 0x24);
+
+            machine.clear_watchpoints();
+            machine.add_watchpoint(Watchpoint {
+                address: 0xC123,
+                access: WatchpointAccess::Read,
+                value: None,
+            });
+            machine.read_u8(Wrapping(0xC123));
+            let hit = machine.watchpoints().take_hit().unwrap();
+            assert_eq!((hit.access, hit.value), (WatchpointAccess::Read, 0x24));
+        });
+    }
+}